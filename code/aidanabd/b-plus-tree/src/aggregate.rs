@@ -0,0 +1,359 @@
+// User-defined aggregates, generalizing the per-child counts of order.rs. A registered
+// monoid measures every entry and combines measurements associatively; each internal
+// node caches the combined summary of every child next to its count. A range query
+// then combines the cached summaries of the children lying entirely inside the range
+// and only descends into the (at most two) children the range boundaries cut through,
+// so it visits O(log n) nodes no matter how many entries the range covers.
+//
+// Summaries are stored type-erased, since the tree itself only knows strings, and are
+// combined strictly from left to right, so the monoid does not have to be commutative.
+
+use std::any::Any;
+use std::cmp::Ordering;
+use std::fmt;
+use std::marker::PhantomData;
+use std::ops::{Bound, RangeBounds};
+use std::sync::Arc;
+
+use crate::compare::KeyOrder;
+use crate::keys::Key;
+use crate::{str_bounds, BPlusTree, NodeValue};
+
+pub trait Monoid: Send + Sync + 'static {
+    type Summary: Send + Sync + 'static;
+
+    fn identity(&self) -> Self::Summary;
+    fn measure(&self, key: &str, value: &str) -> Self::Summary;
+    // has to be associative, with `identity` as its neutral element
+    fn combine(&self, left: &Self::Summary, right: &Self::Summary) -> Self::Summary;
+}
+
+pub(crate) type Summary = Box<dyn Any + Send + Sync>;
+
+trait ErasedMonoid: Send + Sync {
+    fn identity(&self) -> Summary;
+    fn measure(&self, key: &str, value: &str) -> Summary;
+    fn combine(&self, left: &Summary, right: &Summary) -> Summary;
+}
+
+impl<M: Monoid> ErasedMonoid for M {
+    fn identity(&self) -> Summary {
+        Box::new(Monoid::identity(self))
+    }
+
+    fn measure(&self, key: &str, value: &str) -> Summary {
+        Box::new(Monoid::measure(self, key, value))
+    }
+
+    fn combine(&self, left: &Summary, right: &Summary) -> Summary {
+        let left = left.downcast_ref().expect("summary of another aggregate");
+        let right = right.downcast_ref().expect("summary of another aggregate");
+        Box::new(Monoid::combine(self, left, right))
+    }
+}
+
+// shared by the trees split off from one another, so aggregate ids work on all of them
+#[derive(Default, Clone)]
+pub(crate) struct Aggregates {
+    monoids: Vec<Arc<dyn ErasedMonoid>>,
+}
+
+impl Aggregates {
+    // whether summaries cached by one tree are valid in the other
+    pub(crate) fn shared_with(&self, other: &Aggregates) -> bool {
+        self.monoids.len() == other.monoids.len()
+            && self
+                .monoids
+                .iter()
+                .zip(other.monoids.iter())
+                .all(|(a, b)| Arc::ptr_eq(a, b))
+    }
+}
+
+impl fmt::Debug for Aggregates {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Aggregates({})", self.monoids.len())
+    }
+}
+
+// identifies a registered aggregate and the summary type it produces
+pub struct AggregateId<M: Monoid> {
+    index: usize,
+    monoid: PhantomData<fn() -> M>,
+}
+
+impl<M: Monoid> Clone for AggregateId<M> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<M: Monoid> Copy for AggregateId<M> {}
+
+impl<M: Monoid> fmt::Debug for AggregateId<M> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "AggregateId({})", self.index)
+    }
+}
+
+// whether every key in [lower, upper) lies inside the range
+pub(crate) fn covers(
+    lower: Option<Key>,
+    upper: Option<Key>,
+    start: Bound<&str>,
+    end: Bound<&str>,
+    order: &KeyOrder,
+) -> bool {
+    let from_start = match start {
+        Bound::Unbounded => true,
+        _ => lower.is_some_and(|lower| order.after_start(lower, start)),
+    };
+    let to_end = match end {
+        Bound::Included(end) | Bound::Excluded(end) => {
+            upper.is_some_and(|upper| order.cmp_key(upper, end) != Ordering::Greater)
+        }
+        Bound::Unbounded => true,
+    };
+    from_start && to_end
+}
+
+impl BPlusTree {
+    pub fn register_aggregate<M: Monoid>(&mut self, monoid: M) -> AggregateId<M> {
+        let index = self.aggregates.monoids.len();
+        self.aggregates.monoids.push(Arc::new(monoid));
+        // existing entries are summarized bottom-up once
+        let root = self.root_index;
+        self.rebuild_summaries(root, index);
+        AggregateId {
+            index,
+            monoid: PhantomData,
+        }
+    }
+
+    fn rebuild_summaries(&mut self, index: usize, aggregate: usize) -> Summary {
+        let children = match self.nodes[index].values {
+            NodeValue::Internal(ref pointers) => pointers.clone(),
+            NodeValue::Leaf(_) => return self.summarize(index, aggregate),
+        };
+        for (position, child) in children.into_iter().enumerate() {
+            let summary = self.rebuild_summaries(child, aggregate);
+            self.nodes[index].summaries[position].push(summary);
+        }
+        self.summarize(index, aggregate)
+    }
+
+    // recomputes every cached summary below `index`, for a subtree summarized by the
+    // aggregates of another tree; returns the summaries of `index` itself
+    pub(crate) fn resummarize(&mut self, index: usize) -> Vec<Summary> {
+        if let NodeValue::Internal(ref pointers) = self.nodes[index].values {
+            for (position, child) in pointers.clone().into_iter().enumerate() {
+                self.nodes[index].summaries[position] = self.resummarize(child);
+            }
+        }
+        self.subtree_summaries(index)
+    }
+
+    // the summary of everything below `index` for one aggregate
+    fn summarize(&self, index: usize, aggregate: usize) -> Summary {
+        let monoid = &self.aggregates.monoids[aggregate];
+        let node = &self.nodes[index];
+        let mut summary = monoid.identity();
+        match node.values {
+            NodeValue::Internal(_) => {
+                for child in node.summaries.iter() {
+                    summary = monoid.combine(&summary, &child[aggregate]);
+                }
+            }
+            NodeValue::Leaf(_) => {
+                for (key, value) in node.leaf_entries(&self.key_order) {
+                    let value = self.overflow.read(value);
+                    summary = monoid.combine(&summary, &monoid.measure(&key.to_string(), &value));
+                }
+            }
+        }
+        summary
+    }
+
+    // the summaries of every registered aggregate for a node, as its parent caches them
+    pub(crate) fn subtree_summaries(&self, index: usize) -> Vec<Summary> {
+        (0..self.aggregates.monoids.len())
+            .map(|aggregate| self.summarize(index, aggregate))
+            .collect()
+    }
+
+    pub fn aggregate<M: Monoid, R: RangeBounds<String>>(
+        &self,
+        id: AggregateId<M>,
+        range: R,
+    ) -> M::Summary {
+        let (start, end) = str_bounds(&range);
+        let summary = self.aggregate_node(self.root_index, id.index, None, None, start, end);
+        *summary
+            .downcast()
+            .expect("aggregate id belongs to another tree")
+    }
+
+    // combines the parts of the subtree at `index` (holding keys in [lower, upper))
+    // that fall inside the range
+    fn aggregate_node(
+        &self,
+        index: usize,
+        aggregate: usize,
+        lower: Option<Key>,
+        upper: Option<Key>,
+        start: Bound<&str>,
+        end: Bound<&str>,
+    ) -> Summary {
+        let monoid = &self.aggregates.monoids[aggregate];
+        let node = &self.nodes[index];
+        let mut summary = monoid.identity();
+        match node.values {
+            NodeValue::Internal(ref pointers) => {
+                for (position, child) in pointers.iter().enumerate() {
+                    if !node.child_overlaps(position, start, end, &self.key_order) {
+                        continue;
+                    }
+                    let child_lower = match position {
+                        0 => lower,
+                        _ => Some(node.keys.key(position - 1)),
+                    };
+                    let child_upper = node.keys.get(position).or(upper);
+                    summary = if covers(child_lower, child_upper, start, end, &self.key_order) {
+                        monoid.combine(&summary, &node.summaries[position][aggregate])
+                    } else {
+                        let part = self.aggregate_node(
+                            *child,
+                            aggregate,
+                            child_lower,
+                            child_upper,
+                            start,
+                            end,
+                        );
+                        monoid.combine(&summary, &part)
+                    };
+                }
+            }
+            NodeValue::Leaf(_) => {
+                let order = &self.key_order;
+                for (key, value) in node.leaf_entries(order) {
+                    if order.after_start(key, start) && order.before_end(key, end) {
+                        let value = self.overflow.read(value);
+                        summary =
+                            monoid.combine(&summary, &monoid.measure(&key.to_string(), &value));
+                    }
+                }
+            }
+        }
+        summary
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::batch::WriteBatch;
+
+    struct Sum;
+
+    impl Monoid for Sum {
+        type Summary = u64;
+
+        fn identity(&self) -> u64 {
+            0
+        }
+
+        fn measure(&self, _: &str, value: &str) -> u64 {
+            value.parse().unwrap()
+        }
+
+        fn combine(&self, left: &u64, right: &u64) -> u64 {
+            left + right
+        }
+    }
+
+    // not commutative, so summaries combined out of order show up
+    struct Concat;
+
+    impl Monoid for Concat {
+        type Summary = String;
+
+        fn identity(&self) -> String {
+            String::new()
+        }
+
+        fn measure(&self, key: &str, _: &str) -> String {
+            format!("{},", key)
+        }
+
+        fn combine(&self, left: &String, right: &String) -> String {
+            format!("{}{}", left, right)
+        }
+    }
+
+    fn key(i: usize) -> String {
+        format!("{:04}", i)
+    }
+
+    fn brute_force(tree: &BPlusTree, range: (Bound<String>, Bound<String>)) -> (u64, String) {
+        let entries = tree.scan(range);
+        (
+            entries
+                .iter()
+                .map(|(_, value)| value.parse::<u64>().unwrap())
+                .sum(),
+            entries.iter().map(|(key, _)| format!("{},", key)).collect(),
+        )
+    }
+
+    #[test]
+    fn range_queries_match_scans() {
+        let mut tree = BPlusTree::new();
+        for i in 0..300 {
+            tree.insert(key(i), i.to_string());
+        }
+        // registered on a populated tree, then kept up to date by every kind of write
+        let sum = tree.register_aggregate(Sum);
+        let concat = tree.register_aggregate(Concat);
+        for i in (0..300).step_by(3) {
+            tree.delete(key(i));
+        }
+        for i in 300..600 {
+            tree.insert(key(i), "1".to_string());
+        }
+        let mut batch = WriteBatch::new();
+        batch.delete_range(key(400)..key(450));
+        for i in (7..600).step_by(10) {
+            batch.delete(key(i));
+        }
+        tree.apply(batch);
+        tree.remove_range(key(500)..key(520));
+        tree.retain(|key, _| !key.ends_with('3'));
+
+        let ranges = [
+            (Bound::Unbounded, Bound::Unbounded),
+            (Bound::Included(key(10)), Bound::Excluded(key(20))),
+            (Bound::Excluded(key(10)), Bound::Included(key(20))),
+            (Bound::Included(key(250)), Bound::Unbounded),
+            (Bound::Unbounded, Bound::Excluded(key(5))),
+            (Bound::Included(key(17)), Bound::Included(key(17))),
+            (Bound::Included(key(401)), Bound::Excluded(key(449))),
+        ];
+        for range in ranges {
+            let (expected_sum, expected_keys) = brute_force(&tree, range.clone());
+            assert_eq!(tree.aggregate(sum, range.clone()), expected_sum);
+            assert_eq!(tree.aggregate(concat, range), expected_keys);
+        }
+    }
+
+    #[test]
+    fn split_trees_share_aggregates() {
+        let mut tree = BPlusTree::new();
+        let sum = tree.register_aggregate(Sum);
+        for i in 0..100 {
+            tree.insert(key(i), i.to_string());
+        }
+        let upper = tree.split_off(&key(50));
+        assert_eq!(tree.aggregate(sum, ..), (0..50).sum());
+        assert_eq!(upper.aggregate(sum, ..), (50..100).sum());
+    }
+}
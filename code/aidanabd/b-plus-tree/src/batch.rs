@@ -0,0 +1,348 @@
+// A WriteBatch collects puts, deletes and range deletes that should become visible
+// together. `BPlusTree::apply` resolves the batch to one final operation per key, then
+// visits every affected leaf once in key order: each leaf is rebuilt in a single
+// merge of its entries with the batch, split as often as needed, and merges are only
+// checked after all leaves have been written.
+//
+// `apply` takes the tree mutably, so readers never observe half of a batch. Batches
+// are applied in memory only. A snapshot is written while nothing modifies the tree and
+// replaces the previous one with a rename, so it holds either none or all of a batch,
+// and a crash before the next snapshot loses whole batches. The WiscKey store logs
+// every write as it happens and takes no batches.
+
+use std::cmp::Ordering;
+use std::ops::{Bound, RangeBounds};
+
+use crate::compare::KeyOrder;
+use crate::keys::Key;
+use crate::{BPlusTree, NodeValue};
+
+type KeyRange = (Bound<String>, Bound<String>);
+
+#[derive(Debug, Clone)]
+enum Operation {
+    Put(String, String),
+    Delete(String),
+    DeleteRange(Bound<String>, Bound<String>),
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct WriteBatch {
+    operations: Vec<Operation>,
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        WriteBatch {
+            operations: Vec::new(),
+        }
+    }
+
+    pub fn put(&mut self, key: String, value: String) -> &mut Self {
+        self.operations.push(Operation::Put(key, value));
+        self
+    }
+
+    pub fn delete(&mut self, key: String) -> &mut Self {
+        self.operations.push(Operation::Delete(key));
+        self
+    }
+
+    pub fn delete_range<R: RangeBounds<String>>(&mut self, range: R) -> &mut Self {
+        self.operations.push(Operation::DeleteRange(
+            range.start_bound().cloned(),
+            range.end_bound().cloned(),
+        ));
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.operations.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.operations.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.operations.clear();
+    }
+}
+
+fn in_ranges(key: &str, ranges: &[KeyRange], order: &KeyOrder) -> bool {
+    ranges.iter().any(|range| in_range(key, range, order))
+}
+
+fn in_range(key: &str, (start, end): &KeyRange, order: &KeyOrder) -> bool {
+    let key = Key::full(key);
+    order.after_start(key, start.as_ref().map(|s| s.as_str()))
+        && order.before_end(key, end.as_ref().map(|s| s.as_str()))
+}
+
+// the first key at or after `from` that a range could still delete
+fn next_in_range(from: &str, ranges: &[KeyRange], order: &KeyOrder) -> Option<String> {
+    ranges
+        .iter()
+        .filter_map(|(start, end)| {
+            let candidate = match start {
+                Bound::Included(start) | Bound::Excluded(start)
+                    if order.cmp(start, from) == Ordering::Greater =>
+                {
+                    start.clone()
+                }
+                _ => from.to_string(),
+            };
+            if order.before_end(Key::full(&candidate), end.as_ref().map(|s| s.as_str())) {
+                Some(candidate)
+            } else {
+                None
+            }
+        })
+        .min_by(|a, b| order.cmp(a, b))
+}
+
+// collapses the batch into the last point operation per key and the range deletes
+// that were not overridden by a later put
+fn resolve(
+    operations: Vec<Operation>,
+    order: &KeyOrder,
+) -> (Vec<(String, Option<String>)>, Vec<KeyRange>) {
+    // (key, value, position in the batch)
+    let mut points = Vec::new();
+    let mut ranges = Vec::new();
+
+    for (position, operation) in operations.into_iter().enumerate() {
+        match operation {
+            Operation::Put(key, value) => points.push((key, Some(value), position)),
+            Operation::Delete(key) => points.push((key, None, position)),
+            Operation::DeleteRange(start, end) => ranges.push(((start, end), position)),
+        }
+    }
+
+    // keys the comparator considers equal are one key, written last by the last write
+    points.sort_by(|(a, _, a_position), (b, _, b_position)| {
+        order.cmp(a, b).then(a_position.cmp(b_position))
+    });
+    let mut resolved: Vec<(String, Option<String>)> = Vec::with_capacity(points.len());
+    for (key, value, position) in points {
+        // only earlier puts are deleted, later ones have to survive the range
+        let deleted = ranges.iter().any(|(range, range_position)| {
+            *range_position > position && in_range(&key, range, order)
+        });
+        let value = if deleted { None } else { value };
+        match resolved.last_mut() {
+            Some(last) if order.cmp(&last.0, &key) == Ordering::Equal => *last = (key, value),
+            _ => resolved.push((key, value)),
+        }
+    }
+
+    (
+        resolved,
+        ranges.into_iter().map(|(range, _)| range).collect(),
+    )
+}
+
+impl BPlusTree {
+    // the leaf responsible for `key` and the smallest separator above it on the path
+    fn leaf_and_upper_fence(&self, key: &str) -> (usize, Option<String>) {
+        let mut node_index = self.root_index;
+        let mut upper = None;
+        loop {
+            let node = &self.nodes[node_index];
+            match node.values {
+                NodeValue::Internal(ref children) => {
+                    let index = node.child_position(key, &self.key_order);
+                    if let Some(separator) = node.keys.get(index) {
+                        upper = Some(separator.to_string());
+                    }
+                    node_index = children[index];
+                }
+                NodeValue::Leaf(_) => return (node_index, upper),
+            }
+        }
+    }
+
+    pub fn apply(&mut self, batch: WriteBatch) {
+        let (points, ranges) = resolve(batch.operations, &self.key_order);
+        // every key the batch writes loses its deadline
+        for (key, _) in points.iter() {
+            self.forget_deadline(key);
+        }
+        for range in ranges.iter() {
+            self.forget_deadlines(range.clone());
+        }
+
+        let mut next_point = 0;
+        let order = self.key_order.clone();
+        let earlier = |a: String, b: String| match order.cmp(&a, &b) {
+            Ordering::Greater => b,
+            _ => a,
+        };
+        let mut cursor = match (points.first(), next_in_range("", &ranges, &order)) {
+            (Some((key, _)), Some(range_key)) => Some(earlier(key.clone(), range_key)),
+            (Some((key, _)), None) => Some(key.clone()),
+            (None, range_key) => range_key,
+        };
+        let mut touched = Vec::new();
+
+        while let Some(key) = cursor {
+            let (leaf_index, upper) = self.leaf_and_upper_fence(&key);
+            let leaf_end = points[next_point..]
+                .iter()
+                .position(|(point, _)| {
+                    upper
+                        .as_ref()
+                        .is_some_and(|upper| order.cmp(point, upper) != Ordering::Less)
+                })
+                .map_or(points.len(), |offset| next_point + offset);
+            let leaf_points = &points[next_point..leaf_end];
+            next_point = leaf_end;
+
+            // pending lazy writes are older than anything in the batch
+            let pending = self.take_leaf_buffer(leaf_index);
+            self.merge_into_leaf(leaf_index, &pending);
+
+            if !ranges.is_empty() {
+                self.retain_in_leaf(leaf_index, |key, _, _| !in_ranges(key, &ranges, &order));
+            }
+            self.merge_into_leaf(leaf_index, leaf_points);
+            self.refresh_ancestors(leaf_index);
+            self.split_until_fits(leaf_index);
+            touched.push(key);
+
+            cursor = match upper {
+                Some(upper) => {
+                    let point = points.get(next_point).map(|(key, _)| key.clone());
+                    match (point, next_in_range(&upper, &ranges, &order)) {
+                        (Some(point), Some(range_key)) => Some(earlier(point, range_key)),
+                        (point, range_key) => point.or(range_key),
+                    }
+                }
+                None => None,
+            };
+        }
+
+        for key in touched {
+            self.check_merge_at(&key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(i: usize) -> String {
+        format!("{:04}", i)
+    }
+
+    fn tree_with(count: usize) -> BPlusTree {
+        let mut tree = BPlusTree::new();
+        for i in 0..count {
+            tree.insert(key(i), i.to_string());
+        }
+        tree
+    }
+
+    #[test]
+    fn a_batch_matches_its_writes_applied_one_by_one() {
+        let mut batched = tree_with(200);
+        let mut single = tree_with(200);
+        let mut batch = WriteBatch::new();
+        for i in (0..300).step_by(7) {
+            batch.put(key(i), format!("new {}", i));
+            single.insert(key(i), format!("new {}", i));
+        }
+        for i in (0..300).step_by(5) {
+            batch.delete(key(i));
+            single.delete(key(i));
+        }
+        batch.delete_range(key(40)..key(120));
+        for (key, _) in single.scan(key(40)..key(120)) {
+            single.delete(key);
+        }
+        batch.put(key(50), "after the range".to_string());
+        single.insert(key(50), "after the range".to_string());
+        assert_eq!(batch.len(), 105);
+
+        batched.apply(batch);
+        assert_eq!(batched.scan(..), single.scan(..));
+        batched.validate().unwrap();
+        for i in 0..300 {
+            assert_eq!(batched.get(&key(i)), single.get(&key(i)));
+        }
+    }
+
+    #[test]
+    fn later_writes_to_a_key_win() {
+        let mut tree = tree_with(10);
+        let mut batch = WriteBatch::new();
+        batch
+            .put(key(1), "put".to_string())
+            .delete(key(1))
+            .delete(key(2))
+            .put(key(2), "put".to_string())
+            .put(key(3), "first".to_string())
+            .put(key(3), "second".to_string())
+            .put(key(5), "deleted by the range".to_string())
+            .delete_range(key(4)..=key(6))
+            .put(key(6), "after the range".to_string());
+        tree.apply(batch);
+
+        assert_eq!(tree.get(&key(0)), Some("0".to_string()));
+        assert_eq!(tree.get(&key(1)), None);
+        assert_eq!(tree.get(&key(2)), Some("put".to_string()));
+        assert_eq!(tree.get(&key(3)), Some("second".to_string()));
+        assert_eq!(tree.get(&key(4)), None);
+        assert_eq!(tree.get(&key(5)), None);
+        assert_eq!(tree.get(&key(6)), Some("after the range".to_string()));
+        assert_eq!(tree.get(&key(7)), Some("7".to_string()));
+        assert_eq!(tree.scan(..).len(), 7);
+    }
+
+    #[test]
+    fn batches_fill_and_empty_trees() {
+        let mut tree = BPlusTree::new();
+        let mut batch = WriteBatch::new();
+        for i in 0..100 {
+            batch.put(key(i), i.to_string());
+        }
+        batch.delete_range(key(90)..);
+        tree.apply(batch);
+        assert_eq!(tree.scan(..).len(), 90);
+        assert_eq!(tree.get(&key(10)), Some("10".to_string()));
+        tree.validate().unwrap();
+
+        let mut batch = WriteBatch::new();
+        batch.delete(key(1)).delete_range(..);
+        tree.apply(batch);
+        assert!(tree.scan(..).is_empty());
+        tree.insert(key(1), "again".to_string());
+        assert_eq!(tree.get(&key(1)), Some("again".to_string()));
+    }
+
+    #[test]
+    fn pending_lazy_writes_are_older_than_the_batch() {
+        let mut tree = BPlusTree::with_update_buffer(4);
+        for i in (0..100).step_by(2) {
+            tree.insert(key(i), "buffered".to_string());
+        }
+        tree.delete(key(20));
+        let mut batch = WriteBatch::new();
+        for i in 0..100 {
+            if i != 20 {
+                batch.put(key(i), i.to_string());
+            }
+        }
+        batch.delete_range(key(90)..);
+        tree.apply(batch);
+        let expected: Vec<_> = (0..90)
+            .filter(|i| *i != 20)
+            .map(|i| (key(i), i.to_string()))
+            .collect();
+        assert_eq!(tree.scan(..), expected);
+        tree.flush();
+        assert_eq!(tree.scan(..), expected);
+        tree.validate().unwrap();
+    }
+}
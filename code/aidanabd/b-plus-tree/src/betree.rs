@@ -0,0 +1,307 @@
+// A B-epsilon tree built from the same ArrayNode/NodeValue arena as BPlusTree.
+//
+// Writes are not applied to leaves right away. They become messages (put, delete or
+// upsert) in the buffer of the root. When an internal node's buffer holds more than
+// `buffer_capacity` messages, the messages bound for the child with the most pending
+// messages are flushed one level down in a single write, so every node rewrite moves
+// a whole batch of messages instead of a single key. Leaves apply the messages that
+// reach them and split as the B+ tree does.
+//
+// Point queries apply the messages found along the search path on top of the leaf
+// value, and range scans do the same for every node overlapping the range. Deletes are
+// messages as well, so leaves are never merged and may stay underfull.
+
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::ops::RangeBounds;
+
+use crate::{after_start, before_end, str_bounds, BPlusTree, Message, NodeValue};
+
+// combines the current value, if any, with the operand of an upsert message
+pub type UpsertFn = fn(Option<&str>, &str) -> String;
+
+fn apply_message(current: Option<String>, message: &Message, upsert: UpsertFn) -> Option<String> {
+    match message {
+        Message::Put(value) => Some(value.clone()),
+        Message::Delete => None,
+        Message::Upsert(operand) => Some(upsert(current.as_deref(), operand)),
+    }
+}
+
+#[derive(Debug)]
+pub struct BEpsilonTree {
+    tree: BPlusTree,
+    buffer_capacity: usize,
+    upsert: UpsertFn,
+    // a B+ tree rewrites one leaf per write, this tree once per flush reaching a leaf
+    leaf_writes: usize,
+}
+
+impl BEpsilonTree {
+    pub fn new(buffer_capacity: usize, upsert: UpsertFn) -> Self {
+        assert!(
+            buffer_capacity > 0,
+            "message buffers need room for one message"
+        );
+        BEpsilonTree {
+            tree: BPlusTree::new(),
+            buffer_capacity,
+            upsert,
+            leaf_writes: 0,
+        }
+    }
+
+    pub fn leaf_writes(&self) -> usize {
+        self.leaf_writes
+    }
+
+    pub fn display(&self) {
+        self.tree.display();
+    }
+
+    pub fn insert(&mut self, key: String, value: String) {
+        self.send(key, Message::Put(value));
+    }
+
+    pub fn delete(&mut self, key: String) {
+        self.send(key, Message::Delete);
+    }
+
+    pub fn upsert(&mut self, key: String, operand: String) {
+        self.send(key, Message::Upsert(operand));
+    }
+
+    fn send(&mut self, key: String, message: Message) {
+        let root = self.tree.root_index;
+        self.push_messages(root, vec![(key, message)]);
+    }
+
+    // hands messages sorted by key (older first for the same key) to a node
+    fn push_messages(&mut self, node_index: usize, messages: Vec<(String, Message)>) {
+        if let NodeValue::Leaf(_) = self.tree.nodes[node_index].values {
+            self.apply_to_leaf(node_index, messages);
+            return;
+        }
+
+        // messages already buffered are older, so they stay in front of equal keys
+        let buffer = std::mem::take(&mut self.tree.nodes[node_index].buffer);
+        let mut merged = Vec::with_capacity(buffer.len() + messages.len());
+        let mut old = buffer.into_iter().peekable();
+        let mut new = messages.into_iter().peekable();
+        loop {
+            let take_old = match (old.peek(), new.peek()) {
+                (Some((old_key, _)), Some((new_key, _))) => old_key <= new_key,
+                (Some(_), None) => true,
+                (None, Some(_)) => false,
+                (None, None) => break,
+            };
+            merged.push(if take_old { old.next() } else { new.next() }.unwrap());
+        }
+        self.tree.nodes[node_index].buffer = merged;
+
+        // flushing a child may split it and, through it, this node; the left half keeps
+        // the index and its share of the buffer
+        while self.tree.nodes[node_index].buffer.len() > self.buffer_capacity {
+            self.flush(node_index);
+        }
+    }
+
+    // moves every message bound for the child with the most pending messages
+    fn flush(&mut self, node_index: usize) {
+        let node = &mut self.tree.nodes[node_index];
+        let NodeValue::Internal(ref children) = node.values else {
+            panic!("Flushed a leaf node");
+        };
+
+        let mut pending = vec![0; children.len()];
+        for (key, _) in node.buffer.iter() {
+            pending[node.child_position(key, &self.tree.key_order)] += 1;
+        }
+        let position = (0..pending.len())
+            .max_by_key(|position| pending[*position])
+            .unwrap();
+        let child = children[position];
+
+        let buffer = std::mem::take(&mut node.buffer);
+        let (moving, staying): (Vec<_>, Vec<_>) = buffer
+            .into_iter()
+            .partition(|(key, _)| node.child_position(key, &self.tree.key_order) == position);
+        node.buffer = staying;
+
+        self.push_messages(child, moving);
+    }
+
+    fn apply_to_leaf(&mut self, leaf_index: usize, messages: Vec<(String, Message)>) {
+        let leaf = &self.tree.nodes[leaf_index];
+        let NodeValue::Leaf(ref values) = leaf.values else {
+            panic!("Search yielded internal node");
+        };
+
+        let mut updates = Vec::new();
+        for group in messages.chunk_by(|a, b| a.0 == b.0) {
+            let key = &group[0].0;
+            let current = leaf
+                .keys
+                .binary_search(key, &self.tree.key_order)
+                .ok()
+                .map(|index| self.tree.overflow.read(values[index].as_ref()));
+            let value = group.iter().fold(current, |value, (_, message)| {
+                apply_message(value, message, self.upsert)
+            });
+            updates.push((key.clone(), value));
+        }
+
+        self.leaf_writes += 1;
+        self.tree.merge_into_leaf(leaf_index, &updates);
+        self.tree.refresh_ancestors(leaf_index);
+        self.tree.split_until_fits(leaf_index);
+    }
+
+    pub fn get(&self, key: &str) -> Option<String> {
+        let nodes = &self.tree.nodes;
+        let mut node_index = self.tree.root_index;
+        // buffers from the root down, so newest first
+        let mut levels: Vec<Vec<&Message>> = Vec::new();
+
+        loop {
+            let node = &nodes[node_index];
+            match node.values {
+                NodeValue::Internal(ref children) => {
+                    let start = node
+                        .buffer
+                        .partition_point(|(pending, _)| pending.as_str() < key);
+                    levels.push(
+                        node.buffer[start..]
+                            .iter()
+                            .take_while(|(pending, _)| pending == key)
+                            .map(|(_, message)| message)
+                            .collect(),
+                    );
+                    node_index = children[node.child_position(key, &self.tree.key_order)];
+                }
+                NodeValue::Leaf(ref values) => {
+                    let base = node
+                        .keys
+                        .binary_search(key, &self.tree.key_order)
+                        .ok()
+                        .map(|index| self.tree.overflow.read(values[index].as_ref()));
+                    return levels.iter().rev().flatten().fold(base, |value, message| {
+                        apply_message(value, message, self.upsert)
+                    });
+                }
+            }
+        }
+    }
+
+    pub fn scan<R: RangeBounds<String>>(&self, range: R) -> Vec<(String, String)> {
+        let (start, end) = str_bounds(&range);
+        let in_range = |key: &str| after_start(key, start) && before_end(key, end);
+
+        let mut entries: BTreeMap<String, Option<String>> = BTreeMap::new();
+        // (key, depth, position in buffer, message)
+        let mut messages = Vec::new();
+        let mut stack = vec![(self.tree.root_index, 0)];
+
+        while let Some((node_index, depth)) = stack.pop() {
+            let node = &self.tree.nodes[node_index];
+            match node.values {
+                NodeValue::Internal(ref pointers) => {
+                    for (position, (key, message)) in node.buffer.iter().enumerate() {
+                        if in_range(key) {
+                            messages.push((key, depth, position, message));
+                        }
+                    }
+                    for (position, pointer) in pointers.iter().enumerate() {
+                        if node.child_overlaps(position, start, end, &self.tree.key_order) {
+                            stack.push((*pointer, depth + 1));
+                        }
+                    }
+                }
+                NodeValue::Leaf(ref values) => {
+                    for (key, value) in node.keys.iter().zip(values.iter()) {
+                        let key = key.to_string();
+                        if in_range(&key) {
+                            entries.insert(key, Some(self.tree.overflow.read(value.as_ref())));
+                        }
+                    }
+                }
+            }
+        }
+
+        // deeper buffers hold older messages
+        messages.sort_by_key(|(key, depth, position, _)| (*key, Reverse(*depth), *position));
+        for (key, _, _, message) in messages {
+            let current = entries.remove(key).flatten();
+            entries.insert(key.clone(), apply_message(current, message, self.upsert));
+        }
+
+        entries
+            .into_iter()
+            .filter_map(|(key, value)| value.map(|value| (key, value)))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WRITES: usize = 2000;
+
+    fn append(current: Option<&str>, operand: &str) -> String {
+        format!("{}{}", current.unwrap_or(""), operand)
+    }
+
+    fn key(i: usize) -> String {
+        format!("{:04}", i * 7919 % 500)
+    }
+
+    #[test]
+    fn messages_match_writes_applied_directly() {
+        let mut betree = BEpsilonTree::new(16, append);
+        let mut reference = BTreeMap::new();
+        for i in 0..WRITES {
+            let key = key(i);
+            match i % 4 {
+                0 => {
+                    betree.delete(key.clone());
+                    reference.remove(&key);
+                }
+                1 => {
+                    betree.upsert(key.clone(), "+".to_string());
+                    let value = append(reference.get(&key).map(String::as_str), "+");
+                    reference.insert(key, value);
+                }
+                _ => {
+                    betree.insert(key.clone(), i.to_string());
+                    reference.insert(key, i.to_string());
+                }
+            }
+        }
+
+        for i in 0..500 {
+            let key = format!("{:04}", i);
+            assert_eq!(betree.get(&key), reference.get(&key).cloned());
+        }
+        let range: Vec<_> = reference
+            .range(key(3)..key(9))
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect();
+        assert_eq!(betree.scan(key(3)..key(9)), range);
+        assert_eq!(betree.scan(..), reference.into_iter().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn buffers_cut_leaf_writes() {
+        let mut unbuffered = BEpsilonTree::new(1, append);
+        let mut buffered = BEpsilonTree::new(64, append);
+        for i in 0..WRITES {
+            unbuffered.insert(key(i), String::new());
+            buffered.insert(key(i), String::new());
+        }
+        assert_eq!(buffered.scan(..), unbuffered.scan(..));
+        // with room for one message every write soon reaches a leaf on its own
+        assert!(unbuffered.leaf_writes() > WRITES / 2);
+        assert!(buffered.leaf_writes() * 4 < unbuffered.leaf_writes());
+    }
+}
@@ -0,0 +1,58 @@
+// Serves a BPlusTree over RESP, so that redis-cli can talk to it:
+//
+//     resp_server [--bind ADDRESS] [--snapshot FILE]
+//
+// The server listens on 127.0.0.1:6379 unless told otherwise. With a snapshot file the
+// tree is loaded from it on start, if it exists, and saved to it by SAVE and on
+// shutdown. SHUTDOWN, Ctrl-C and SIGTERM shut the server down gracefully.
+
+use std::env;
+use std::io;
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+use b_plus_tree::server::Server;
+use b_plus_tree::BPlusTree;
+
+const USAGE: &str = "usage: resp_server [--bind ADDRESS] [--snapshot FILE]";
+
+fn serve(address: &str, snapshot: Option<PathBuf>) -> io::Result<()> {
+    let mut tree = BPlusTree::new();
+    if let Some(ref path) = snapshot {
+        match tree.load(path) {
+            Ok(()) => eprintln!("loaded {} entries from {}", tree.len(), path.display()),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => (),
+            Err(error) => return Err(error),
+        }
+    }
+    let server = Server::bind(address, tree, snapshot)?;
+    let handle = server.shutdown_handle();
+    ctrlc::set_handler(move || handle.shutdown()).map_err(io::Error::other)?;
+    eprintln!("listening on {}", server.local_addr());
+    let tree = server.run()?;
+    eprintln!("shut down with {} entries", tree.len());
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    let mut address = "127.0.0.1:6379".to_string();
+    let mut snapshot = None;
+    let mut arguments = env::args().skip(1);
+    while let Some(argument) = arguments.next() {
+        match (argument.as_str(), arguments.next()) {
+            ("--bind", Some(value)) => address = value,
+            ("--snapshot", Some(value)) => snapshot = Some(PathBuf::from(value)),
+            _ => {
+                eprintln!("{}", USAGE);
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+    match serve(&address, snapshot) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => {
+            eprintln!("error: {}", error);
+            ExitCode::FAILURE
+        }
+    }
+}
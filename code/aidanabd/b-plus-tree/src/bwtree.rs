@@ -0,0 +1,921 @@
+// A latch-free Bw-tree. Nodes are addressed by logical page ids, like the arena
+// indices of BPlusTree, but the id is resolved through a mapping table of atomic
+// pointers. A page is never modified in place: every update prepends an immutable
+// delta record to the page's chain and installs it with a compare-and-swap on the
+// mapping table entry. Once a chain grows past CONSOLIDATE_AFTER deltas it is replaced
+// by a freshly built base node, and the old chain is freed through epoch-based
+// reclamation once no reader can still hold it.
+//
+// Structure modifications are delta records as well. A split first installs a new
+// right sibling, then a split delta on the old node, then an index entry delta on the
+// parent; between the steps the right sibling is reachable through the side link, as in
+// a B-link tree. A leaf merge freezes the right node with a remove node delta, appends
+// its contents to the left sibling with a merge delta and finally posts an index delete
+// delta on the parent. Any writer that runs into a frozen node helps to finish the
+// merge before retrying, so no step depends on the thread that started it.
+//
+// Only leaves merge. Removed pages keep their slot in the mapping table as forwarding
+// stubs, since another thread may still hold their id.
+//
+// Page ids are never reused, so the mapping table grows instead: it is a list of
+// segments, each twice the size of the one before, and a segment is allocated by the
+// first page id that falls into it. Slots never move once their segment exists.
+
+use std::ops::RangeBounds;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::OnceLock;
+
+use crossbeam_epoch::{self as epoch, Atomic, Guard, Owned, Shared};
+
+use crate::{after_start, before_end, str_bounds, MERGE, SPLIT_AFTER};
+
+pub type PageId = usize;
+
+const CONSOLIDATE_AFTER: usize = 8;
+// the size of the first mapping table segment; segment n holds FIRST_SEGMENT << n ids
+const FIRST_SEGMENT: usize = 1 << 10;
+// enough segments for more page ids than fit in memory
+const SEGMENTS: usize = 40;
+
+#[derive(Debug, Clone)]
+struct LeafNode {
+    entries: Vec<(String, String)>,
+    low: Option<String>,
+    high: Option<String>,
+    right: Option<PageId>,
+}
+
+#[derive(Debug, Clone)]
+struct InnerNode {
+    keys: Vec<String>,
+    children: Vec<PageId>,
+    low: Option<String>,
+    high: Option<String>,
+    right: Option<PageId>,
+}
+
+#[derive(Debug)]
+enum Delta {
+    Leaf(LeafNode),
+    Inner(InnerNode),
+    Insert {
+        key: String,
+        value: String,
+    },
+    Delete {
+        key: String,
+    },
+    // keys at or above the separator moved to the right sibling
+    Split {
+        separator: String,
+        right: PageId,
+    },
+    // the contents, fence and side link of the absorbed right sibling
+    Merge {
+        separator: String,
+        node: LeafNode,
+    },
+    // keys in [separator, high) are routed to the child
+    IndexEntry {
+        separator: String,
+        high: Option<String>,
+        child: PageId,
+    },
+    // the removed child's range is routed to the node that absorbed it
+    IndexDelete {
+        separator: String,
+        high: Option<String>,
+        removed: PageId,
+        child: PageId,
+    },
+    // the page is frozen until its left sibling has absorbed it
+    RemoveNode {
+        left: PageId,
+    },
+}
+
+#[derive(Debug)]
+struct Page {
+    delta: Delta,
+    next: Atomic<Page>,
+    level: u32,
+    length: usize,
+}
+
+impl Page {
+    fn base(delta: Delta, level: u32) -> Owned<Page> {
+        Owned::new(Page {
+            delta,
+            next: Atomic::null(),
+            level,
+            length: 0,
+        })
+    }
+
+    fn prepend<'g>(delta: Delta, head: Shared<'g, Page>, page: &Page) -> Owned<Page> {
+        Owned::new(Page {
+            delta,
+            next: Atomic::from(head),
+            level: page.level,
+            length: page.length + 1,
+        })
+    }
+
+    fn is_frozen(&self) -> Option<PageId> {
+        match self.delta {
+            Delta::RemoveNode { left } => Some(left),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Route {
+    Found(Option<String>),
+    Child(PageId),
+    Right(PageId),
+}
+
+#[derive(Debug)]
+enum View {
+    Leaf(LeafNode),
+    Inner(InnerNode),
+}
+
+impl View {
+    fn low(&self) -> &Option<String> {
+        match self {
+            View::Leaf(node) => &node.low,
+            View::Inner(node) => &node.low,
+        }
+    }
+
+    fn high(&self) -> &Option<String> {
+        match self {
+            View::Leaf(node) => &node.high,
+            View::Inner(node) => &node.high,
+        }
+    }
+
+    fn right(&self) -> Option<PageId> {
+        match self {
+            View::Leaf(node) => node.right,
+            View::Inner(node) => node.right,
+        }
+    }
+
+    fn covers(&self, key: &str) -> bool {
+        self.high().as_ref().is_none_or(|high| key < high.as_str())
+    }
+}
+
+fn in_range(key: &str, low: &str, high: &Option<String>) -> bool {
+    key >= low && high.as_ref().is_none_or(|high| key < high.as_str())
+}
+
+fn lookup(entries: &[(String, String)], key: &str) -> Option<String> {
+    entries
+        .binary_search_by(|(k, _)| k.as_str().cmp(key))
+        .ok()
+        .map(|index| entries[index].1.clone())
+}
+
+fn next_page<'g>(page: &Page, guard: &'g Guard) -> &'g Page {
+    // SAFETY: every chain ends in a base page, and pages reachable from a pinned head
+    // are only destroyed after the guard is dropped
+    unsafe { page.next.load(Ordering::Acquire, guard).deref() }
+}
+
+// walks the chain from the newest delta and stops at the first record deciding `key`
+fn route(page: &Page, key: &str, guard: &Guard) -> Route {
+    let mut current = page;
+    loop {
+        match &current.delta {
+            Delta::Insert { key: k, value } if k == key => {
+                return Route::Found(Some(value.clone()))
+            }
+            Delta::Delete { key: k } if k == key => return Route::Found(None),
+            Delta::Split { separator, right } if key >= separator.as_str() => {
+                return Route::Right(*right)
+            }
+            Delta::Merge { separator, node } if key >= separator.as_str() => {
+                return match (&node.high, node.right) {
+                    (Some(high), Some(right)) if key >= high.as_str() => Route::Right(right),
+                    _ => Route::Found(lookup(&node.entries, key)),
+                };
+            }
+            Delta::IndexEntry {
+                separator,
+                high,
+                child,
+            }
+            | Delta::IndexDelete {
+                separator,
+                high,
+                child,
+                ..
+            } if in_range(key, separator, high) => return Route::Child(*child),
+            Delta::Leaf(node) => {
+                return match (&node.high, node.right) {
+                    (Some(high), Some(right)) if key >= high.as_str() => Route::Right(right),
+                    _ => Route::Found(lookup(&node.entries, key)),
+                };
+            }
+            Delta::Inner(node) => {
+                return match (&node.high, node.right) {
+                    (Some(high), Some(right)) if key >= high.as_str() => Route::Right(right),
+                    _ => {
+                        let position = node
+                            .keys
+                            .partition_point(|separator| separator.as_str() <= key);
+                        Route::Child(node.children[position])
+                    }
+                };
+            }
+            _ => {}
+        }
+        current = next_page(current, guard);
+    }
+}
+
+// the logical node a chain describes, replayed from the base up
+fn view(page: &Page, guard: &Guard) -> View {
+    let mut deltas = Vec::new();
+    let mut current = page;
+    let mut view = loop {
+        match &current.delta {
+            Delta::Leaf(node) => break View::Leaf(node.clone()),
+            Delta::Inner(node) => break View::Inner(node.clone()),
+            delta => deltas.push(delta),
+        }
+        current = next_page(current, guard);
+    };
+
+    for delta in deltas.into_iter().rev() {
+        match (&mut view, delta) {
+            (View::Leaf(node), Delta::Insert { key, value }) => {
+                match node.entries.binary_search_by(|(k, _)| k.cmp(key)) {
+                    Ok(index) => node.entries[index].1 = value.clone(),
+                    Err(index) => node.entries.insert(index, (key.clone(), value.clone())),
+                }
+            }
+            (View::Leaf(node), Delta::Delete { key }) => {
+                if let Ok(index) = node.entries.binary_search_by(|(k, _)| k.cmp(key)) {
+                    node.entries.remove(index);
+                }
+            }
+            (View::Leaf(node), Delta::Split { separator, right }) => {
+                let position = node.entries.partition_point(|(k, _)| k < separator);
+                node.entries.truncate(position);
+                node.high = Some(separator.clone());
+                node.right = Some(*right);
+            }
+            (View::Leaf(node), Delta::Merge { node: absorbed, .. }) => {
+                node.entries.extend(absorbed.entries.iter().cloned());
+                node.high = absorbed.high.clone();
+                node.right = absorbed.right;
+            }
+            (View::Inner(node), Delta::Split { separator, right }) => {
+                let position = node.keys.partition_point(|k| k < separator);
+                node.keys.truncate(position);
+                node.children.truncate(position + 1);
+                node.high = Some(separator.clone());
+                node.right = Some(*right);
+            }
+            (
+                View::Inner(node),
+                Delta::IndexEntry {
+                    separator, child, ..
+                },
+            ) => {
+                let position = node.keys.partition_point(|k| k < separator);
+                node.keys.insert(position, separator.clone());
+                node.children.insert(position + 1, *child);
+            }
+            (
+                View::Inner(node),
+                Delta::IndexDelete {
+                    separator,
+                    removed,
+                    child,
+                    ..
+                },
+            ) => {
+                let position = node.keys.iter().position(|k| k == separator);
+                match position {
+                    Some(position) if node.children[position + 1] == *removed => {
+                        node.keys.remove(position);
+                        node.children.remove(position + 1);
+                    }
+                    _ => {
+                        // the parent split between the two siblings, keep routing
+                        // through the node that absorbed the removed one
+                        for c in node.children.iter_mut().filter(|c| **c == *removed) {
+                            *c = *child;
+                        }
+                    }
+                }
+            }
+            (_, Delta::RemoveNode { .. }) => {}
+            (_, delta) => unreachable!("{:?} does not apply to this page", delta),
+        }
+    }
+    view
+}
+
+#[derive(Debug)]
+struct MappingTable {
+    segments: [OnceLock<Box<[Atomic<Page>]>>; SEGMENTS],
+}
+
+impl MappingTable {
+    fn new() -> Self {
+        MappingTable {
+            segments: std::array::from_fn(|_| OnceLock::new()),
+        }
+    }
+
+    fn slot(&self, id: PageId) -> &Atomic<Page> {
+        let segment = (id / FIRST_SEGMENT + 1).ilog2() as usize;
+        let offset = id - FIRST_SEGMENT * ((1 << segment) - 1);
+        let slots = self.segments[segment].get_or_init(|| {
+            (0..FIRST_SEGMENT << segment)
+                .map(|_| Atomic::null())
+                .collect()
+        });
+        &slots[offset]
+    }
+
+    fn slots(&self) -> impl Iterator<Item = &Atomic<Page>> {
+        self.segments
+            .iter()
+            .filter_map(OnceLock::get)
+            .flat_map(|slots| slots.iter())
+    }
+}
+
+#[derive(Debug)]
+pub struct BwTree {
+    mapping: MappingTable,
+    next_page: AtomicUsize,
+    root: AtomicUsize,
+}
+
+impl Default for BwTree {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BwTree {
+    pub fn new() -> Self {
+        let tree = BwTree {
+            mapping: MappingTable::new(),
+            next_page: AtomicUsize::new(0),
+            root: AtomicUsize::new(0),
+        };
+        let root = tree.allocate(Page::base(
+            Delta::Leaf(LeafNode {
+                entries: Vec::new(),
+                low: None,
+                high: None,
+                right: None,
+            }),
+            0,
+        ));
+        tree.root.store(root, Ordering::Release);
+        tree
+    }
+
+    pub fn pages(&self) -> usize {
+        self.next_page.load(Ordering::Acquire)
+    }
+
+    fn allocate(&self, page: Owned<Page>) -> PageId {
+        let id = self.next_page.fetch_add(1, Ordering::AcqRel);
+        self.mapping.slot(id).store(page, Ordering::Release);
+        id
+    }
+
+    // takes back a page that was allocated but never published
+    fn abandon(&self, id: PageId, guard: &Guard) {
+        let page = self
+            .mapping
+            .slot(id)
+            .swap(Shared::null(), Ordering::AcqRel, guard);
+        // SAFETY: no other page refers to `id`, so nobody else can have loaded it
+        unsafe { drop(page.into_owned()) };
+    }
+
+    fn head<'g>(&self, id: PageId, guard: &'g Guard) -> Shared<'g, Page> {
+        self.mapping.slot(id).load(Ordering::Acquire, guard)
+    }
+
+    fn page<'g>(&self, id: PageId, guard: &'g Guard) -> &'g Page {
+        // SAFETY: published ids always point at a chain, and replaced chains are only
+        // destroyed once every guard that could have loaded them is dropped
+        unsafe { self.head(id, guard).deref() }
+    }
+
+    fn install<'g>(
+        &self,
+        id: PageId,
+        head: Shared<'g, Page>,
+        page: Owned<Page>,
+        guard: &'g Guard,
+    ) -> bool {
+        self.mapping
+            .slot(id)
+            .compare_exchange(head, page, Ordering::AcqRel, Ordering::Acquire, guard)
+            .is_ok()
+    }
+
+    // the node that took over a frozen page's range, if the merge got that far
+    fn absorbed_by(&self, id: PageId, guard: &Guard) -> Option<PageId> {
+        let page = self.page(id, guard);
+        let mut current = page.is_frozen()?;
+        let low = view(page, guard).low().clone()?;
+        loop {
+            let page = self.page(current, guard);
+            if page.is_frozen().is_some() && current != id {
+                if let Some(absorber) = self.absorbed_by(current, guard) {
+                    current = absorber;
+                    continue;
+                }
+            }
+            let view = view(page, guard);
+            if view.covers(&low) {
+                return Some(current);
+            }
+            match view.right() {
+                Some(right) if right != id => current = right,
+                _ => return None,
+            }
+        }
+    }
+
+    // the leaf covering `key`, its head and the value it holds for `key`
+    fn find<'g>(&self, key: &str, guard: &'g Guard) -> (PageId, Shared<'g, Page>, Option<String>) {
+        let mut id = self.root.load(Ordering::Acquire);
+        loop {
+            let head = self.head(id, guard);
+            // SAFETY: see `page`
+            let page = unsafe { head.deref() };
+            if page.is_frozen().is_some() {
+                if let Some(absorber) = self.absorbed_by(id, guard) {
+                    id = absorber;
+                    continue;
+                }
+            }
+            match route(page, key, guard) {
+                Route::Found(value) => return (id, head, value),
+                Route::Child(child) => id = child,
+                Route::Right(right) => id = right,
+            }
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<String> {
+        let guard = &epoch::pin();
+        self.find(key, guard).2
+    }
+
+    pub fn insert(&self, key: String, value: String) -> Option<String> {
+        self.write(Delta::Insert { key, value })
+    }
+
+    pub fn delete(&self, key: &str) -> Option<String> {
+        self.write(Delta::Delete {
+            key: key.to_string(),
+        })
+    }
+
+    fn write(&self, delta: Delta) -> Option<String> {
+        let guard = &epoch::pin();
+        let key = match &delta {
+            Delta::Insert { key, .. } | Delta::Delete { key } => key.clone(),
+            _ => unreachable!(),
+        };
+        let mut delta = Some(delta);
+        loop {
+            let (id, head, previous) = self.find(&key, guard);
+            // SAFETY: see `page`
+            let page = unsafe { head.deref() };
+            if page.is_frozen().is_some() {
+                self.help_merge(id, guard);
+                continue;
+            }
+            if matches!(delta, Some(Delta::Delete { .. })) && previous.is_none() {
+                return None;
+            }
+            let record = Page::prepend(delta.take().unwrap(), head, page);
+            match self.mapping.slot(id).compare_exchange(
+                head,
+                record,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+                guard,
+            ) {
+                Ok(_) => {
+                    self.maybe_consolidate(id, guard);
+                    return previous;
+                }
+                Err(error) => {
+                    delta = Some(error.new.into_box().delta);
+                }
+            }
+        }
+    }
+
+    pub fn scan<R: RangeBounds<String>>(&self, range: R) -> Vec<(String, String)> {
+        let guard = &epoch::pin();
+        let (start, end) = str_bounds(&range);
+        let first = match start {
+            std::ops::Bound::Included(key) | std::ops::Bound::Excluded(key) => key,
+            std::ops::Bound::Unbounded => "",
+        };
+
+        let mut results = Vec::new();
+        let (mut id, _, _) = self.find(first, guard);
+        // everything below the cursor was already read from a node to the left
+        let mut cursor: Option<String> = None;
+        loop {
+            let page = self.page(id, guard);
+            if page.is_frozen().is_some() {
+                if let Some(absorber) = self.absorbed_by(id, guard) {
+                    id = absorber;
+                    continue;
+                }
+            }
+            let View::Leaf(node) = view(page, guard) else {
+                unreachable!("the leaf level only links leaves");
+            };
+            results.extend(node.entries.into_iter().filter(|(key, _)| {
+                cursor.as_ref().is_none_or(|cursor| key >= cursor)
+                    && after_start(key, start)
+                    && before_end(key, end)
+            }));
+            match (node.high, node.right) {
+                (Some(high), Some(right)) if before_end(&high, end) => {
+                    cursor = Some(high);
+                    id = right;
+                }
+                _ => return results,
+            }
+        }
+    }
+
+    fn maybe_consolidate(&self, id: PageId, guard: &Guard) {
+        if self.page(id, guard).length > CONSOLIDATE_AFTER {
+            self.consolidate(id, guard);
+        }
+    }
+
+    fn consolidate(&self, id: PageId, guard: &Guard) {
+        let head = self.head(id, guard);
+        // SAFETY: see `page`
+        let page = unsafe { head.deref() };
+        if page.is_frozen().is_some() || page.length == 0 {
+            return;
+        }
+        let consolidated = view(page, guard);
+        let base = match &consolidated {
+            View::Leaf(node) => Delta::Leaf(node.clone()),
+            View::Inner(node) => Delta::Inner(node.clone()),
+        };
+        if !self.install(id, head, Page::base(base, page.level), guard) {
+            return;
+        }
+
+        let mut retired = head;
+        while !retired.is_null() {
+            // SAFETY: the chain is unreachable from the mapping table now
+            unsafe {
+                let next = retired.deref().next.load(Ordering::Acquire, guard);
+                guard.defer_destroy(retired);
+                retired = next;
+            }
+        }
+
+        match consolidated {
+            View::Leaf(ref node) if node.entries.len() > SPLIT_AFTER => self.split(id, guard),
+            View::Inner(ref node) if node.keys.len() > SPLIT_AFTER => self.split(id, guard),
+            View::Leaf(ref node) if node.entries.len() < MERGE => self.start_merge(id, guard),
+            _ => {}
+        }
+    }
+
+    fn split(&self, id: PageId, guard: &Guard) {
+        let head = self.head(id, guard);
+        // SAFETY: see `page`
+        let page = unsafe { head.deref() };
+        if page.is_frozen().is_some() {
+            return;
+        }
+        let (separator, high, sibling) = match view(page, guard) {
+            View::Leaf(mut node) => {
+                let upper = node.entries.split_off(node.entries.len() / 2);
+                let separator = upper[0].0.clone();
+                let sibling = Delta::Leaf(LeafNode {
+                    entries: upper,
+                    low: Some(separator.clone()),
+                    high: node.high.clone(),
+                    right: node.right,
+                });
+                (separator, node.high, sibling)
+            }
+            View::Inner(mut node) => {
+                let middle = node.keys.len() / 2;
+                let keys = node.keys.split_off(middle + 1);
+                let separator = node.keys.pop().unwrap();
+                let children = node.children.split_off(middle + 1);
+                let sibling = Delta::Inner(InnerNode {
+                    keys,
+                    children,
+                    low: Some(separator.clone()),
+                    high: node.high.clone(),
+                    right: node.right,
+                });
+                (separator, node.high, sibling)
+            }
+        };
+
+        let right = self.allocate(Page::base(sibling, page.level));
+        let split = Delta::Split {
+            separator: separator.clone(),
+            right,
+        };
+        if !self.install(id, head, Page::prepend(split, head, page), guard) {
+            self.abandon(right, guard);
+            return;
+        }
+        self.post_index_entry(id, right, separator, high, page.level, guard);
+    }
+
+    fn post_index_entry(
+        &self,
+        left: PageId,
+        right: PageId,
+        separator: String,
+        high: Option<String>,
+        level: u32,
+        guard: &Guard,
+    ) {
+        'retry: loop {
+            let root = self.root.load(Ordering::Acquire);
+            if root == left {
+                let new_root = self.allocate(Page::base(
+                    Delta::Inner(InnerNode {
+                        keys: vec![separator.clone()],
+                        children: vec![left, right],
+                        low: None,
+                        high: None,
+                        right: None,
+                    }),
+                    level + 1,
+                ));
+                if self
+                    .root
+                    .compare_exchange(root, new_root, Ordering::AcqRel, Ordering::Acquire)
+                    .is_ok()
+                {
+                    return;
+                }
+                self.abandon(new_root, guard);
+                continue;
+            }
+
+            let mut id = root;
+            loop {
+                let head = self.head(id, guard);
+                // SAFETY: see `page`
+                let page = unsafe { head.deref() };
+                if page.level <= level {
+                    return;
+                }
+                match route(page, &separator, guard) {
+                    Route::Child(child) if child == left => {
+                        let entry = Delta::IndexEntry {
+                            separator: separator.clone(),
+                            high: high.clone(),
+                            child: right,
+                        };
+                        if self.install(id, head, Page::prepend(entry, head, page), guard) {
+                            self.maybe_consolidate(id, guard);
+                            return;
+                        }
+                        continue 'retry;
+                    }
+                    Route::Child(child) if child == right => return,
+                    // the split node was merged away, the side link still reaches its sibling
+                    Route::Child(_) if page.level == level + 1 => return,
+                    Route::Child(child) => id = child,
+                    Route::Right(sibling) => id = sibling,
+                    Route::Found(_) => unreachable!("only leaves hold values"),
+                }
+            }
+        }
+    }
+
+    // freezes an underfull leaf so that its left sibling can absorb it
+    fn start_merge(&self, id: PageId, guard: &Guard) {
+        let head = self.head(id, guard);
+        // SAFETY: see `page`
+        let page = unsafe { head.deref() };
+        if page.is_frozen().is_some() {
+            return;
+        }
+        let Some(low) = view(page, guard).low().clone() else {
+            return;
+        };
+
+        let mut parent = self.root.load(Ordering::Acquire);
+        let left = loop {
+            let parent_page = self.page(parent, guard);
+            if parent_page.level == 0 {
+                return;
+            }
+            match route(parent_page, &low, guard) {
+                Route::Child(child) if child == id => {
+                    let View::Inner(node) = view(parent_page, guard) else {
+                        return;
+                    };
+                    match node.children.iter().position(|c| *c == id) {
+                        Some(position) if position > 0 && node.keys[position - 1] == low => {
+                            break node.children[position - 1]
+                        }
+                        _ => return,
+                    }
+                }
+                Route::Child(_) if parent_page.level == 1 => return,
+                Route::Child(child) => parent = child,
+                Route::Right(sibling) => parent = sibling,
+                Route::Found(_) => unreachable!("only leaves hold values"),
+            }
+        };
+
+        if self.install(
+            id,
+            head,
+            Page::prepend(Delta::RemoveNode { left }, head, page),
+            guard,
+        ) {
+            self.help_merge(id, guard);
+        }
+    }
+
+    // finishes the merge of a frozen leaf, whoever started it
+    fn help_merge(&self, id: PageId, guard: &Guard) {
+        let page = self.page(id, guard);
+        let Some(left) = page.is_frozen() else {
+            return;
+        };
+        let View::Leaf(removed) = view(page, guard) else {
+            unreachable!("only leaves merge");
+        };
+        let low = removed
+            .low
+            .clone()
+            .expect("the leftmost leaf is never removed");
+
+        let mut current = left;
+        let absorber = loop {
+            let head = self.head(current, guard);
+            // SAFETY: see `page`
+            let page = unsafe { head.deref() };
+            if page.is_frozen().is_some() && current != id {
+                match self.absorbed_by(current, guard) {
+                    Some(absorber) => current = absorber,
+                    None => self.help_merge(current, guard),
+                }
+                continue;
+            }
+            let view = view(page, guard);
+            if view.covers(&low) {
+                break current;
+            }
+            match view.right() {
+                Some(right) if right == id => {
+                    let merge = Delta::Merge {
+                        separator: low.clone(),
+                        node: removed.clone(),
+                    };
+                    if self.install(current, head, Page::prepend(merge, head, page), guard) {
+                        self.maybe_consolidate(current, guard);
+                        break current;
+                    }
+                }
+                Some(right) => current = right,
+                None => unreachable!("a frozen leaf always has a left neighbour"),
+            }
+        };
+
+        self.post_index_delete(id, low, removed.high, absorber, guard);
+    }
+
+    fn post_index_delete(
+        &self,
+        removed: PageId,
+        separator: String,
+        high: Option<String>,
+        absorber: PageId,
+        guard: &Guard,
+    ) {
+        'retry: loop {
+            let mut id = self.root.load(Ordering::Acquire);
+            loop {
+                let head = self.head(id, guard);
+                // SAFETY: see `page`
+                let page = unsafe { head.deref() };
+                if page.level == 0 {
+                    return;
+                }
+                match route(page, &separator, guard) {
+                    Route::Child(child) if child == removed => {
+                        let entry = Delta::IndexDelete {
+                            separator: separator.clone(),
+                            high: high.clone(),
+                            removed,
+                            child: absorber,
+                        };
+                        if self.install(id, head, Page::prepend(entry, head, page), guard) {
+                            self.maybe_consolidate(id, guard);
+                            return;
+                        }
+                        continue 'retry;
+                    }
+                    Route::Child(_) if page.level == 1 => return,
+                    Route::Child(child) => id = child,
+                    Route::Right(sibling) => id = sibling,
+                    Route::Found(_) => unreachable!("only leaves hold values"),
+                }
+            }
+        }
+    }
+}
+
+impl Drop for BwTree {
+    fn drop(&mut self) {
+        // SAFETY: `&mut self` means no other thread is reading the tree
+        let guard = unsafe { epoch::unprotected() };
+        for slot in self.mapping.slots() {
+            let mut page = slot.swap(Shared::null(), Ordering::Relaxed, guard);
+            while !page.is_null() {
+                unsafe {
+                    let next = page.deref().next.load(Ordering::Relaxed, guard);
+                    drop(page.into_owned());
+                    page = next;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    const THREADS: usize = 4;
+
+    #[test]
+    fn concurrent_inserts_and_gets() {
+        let tree = BwTree::new();
+        thread::scope(|scope| {
+            for thread in 0..THREADS {
+                let tree = &tree;
+                scope.spawn(move || {
+                    for i in 0..2000 {
+                        let key = format!("{:05}-{}", i, thread);
+                        assert_eq!(tree.insert(key.clone(), i.to_string()), None);
+                        assert_eq!(tree.get(&key), Some(i.to_string()));
+                    }
+                    for i in (0..2000).step_by(2) {
+                        let key = format!("{:05}-{}", i, thread);
+                        assert_eq!(tree.delete(&key), Some(i.to_string()));
+                    }
+                });
+            }
+        });
+        let entries = tree.scan(..);
+        assert_eq!(entries.len(), THREADS * 1000);
+        assert!(entries.windows(2).all(|pair| pair[0].0 < pair[1].0));
+        for (key, value) in entries {
+            let i: usize = key[..5].parse().unwrap();
+            assert_eq!(i % 2, 1);
+            assert_eq!(value, i.to_string());
+        }
+    }
+
+    #[test]
+    fn mapping_table_grows() {
+        let tree = BwTree::new();
+        let mut i = 0;
+        while tree.pages() <= 1 << 16 {
+            tree.insert(format!("{:08}", i), String::new());
+            i += 1;
+        }
+        assert_eq!(tree.scan(..).len(), i);
+        assert_eq!(tree.get("00000000"), Some(String::new()));
+    }
+}
@@ -0,0 +1,437 @@
+// An order-preserving encoding for composite keys. A tuple of typed values encodes to
+// bytes whose lexicographic order is the order of the tuples, column by column, so a
+// bytewise tree can index composite keys without knowing about their types.
+//
+// Every value starts with a type tag, which also orders values of different types:
+// null < false < true < integers < floats < strings < bytes. Integers are stored
+// big-endian with the sign bit flipped, floats by the bits of their total order
+// (f64::total_cmp), and strings and byte strings are terminated by 0x00 0x01 with
+// every 0x00 inside them escaped as 0x00 0xFF, so a string sorts before its
+// extensions. Descending columns store the complement of every byte of their
+// encoding, which reverses their order because no encoding is a prefix of another.
+//
+// The encoding of the first columns of a tuple is a byte prefix of the encoding of the
+// whole tuple, so all keys starting with some columns form one range (`prefix_range`).
+//
+// Tree keys are strings: `encode_key` maps every byte to the char with the same code
+// point (U+0000 to U+00FF), whose UTF-8 encodings sort like the bytes themselves.
+
+use std::fmt;
+use std::ops::Bound;
+
+const NULL: u8 = 0x01;
+const FALSE: u8 = 0x02;
+const TRUE: u8 = 0x03;
+const INT: u8 = 0x04;
+const FLOAT: u8 = 0x05;
+const STRING: u8 = 0x06;
+const BYTES: u8 = 0x07;
+
+// 0x00 starts either an escaped 0x00 or the end of a string
+const MARKER: u8 = 0x00;
+const ESCAPED: u8 = 0xFF;
+const END: u8 = 0x01;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    String(String),
+    Bytes(Vec<u8>),
+}
+
+impl From<bool> for Value {
+    fn from(value: bool) -> Self {
+        Value::Bool(value)
+    }
+}
+
+impl From<i64> for Value {
+    fn from(value: i64) -> Self {
+        Value::Int(value)
+    }
+}
+
+impl From<f64> for Value {
+    fn from(value: f64) -> Self {
+        Value::Float(value)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(value: &str) -> Self {
+        Value::String(value.to_string())
+    }
+}
+
+impl From<String> for Value {
+    fn from(value: String) -> Self {
+        Value::String(value)
+    }
+}
+
+impl From<Vec<u8>> for Value {
+    fn from(value: Vec<u8>) -> Self {
+        Value::Bytes(value)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Ascending,
+    Descending,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeError {
+    // the key ends in the middle of a value
+    Truncated,
+    UnknownTag(u8),
+    // a string column does not hold UTF-8
+    InvalidString,
+    // a tree key holds a char above U+00FF, so it was not made by encode_key
+    InvalidKey,
+    // a 0x00 inside a string column is followed by neither an escape nor the end
+    InvalidEscape,
+    // the key holds more columns than the codec describes
+    TooManyColumns,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::Truncated => write!(f, "key ends in the middle of a value"),
+            DecodeError::UnknownTag(tag) => write!(f, "unknown type tag {:#04x}", tag),
+            DecodeError::InvalidString => write!(f, "string column is not UTF-8"),
+            DecodeError::InvalidKey => write!(f, "key was not produced by encode_key"),
+            DecodeError::InvalidEscape => write!(f, "invalid escape in string column"),
+            DecodeError::TooManyColumns => write!(f, "key has more columns than the codec"),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+fn encode_terminated(bytes: &[u8], out: &mut Vec<u8>) {
+    for byte in bytes {
+        out.push(*byte);
+        if *byte == MARKER {
+            out.push(ESCAPED);
+        }
+    }
+    out.extend_from_slice(&[MARKER, END]);
+}
+
+fn encode_value(value: &Value, out: &mut Vec<u8>) {
+    match value {
+        Value::Null => out.push(NULL),
+        Value::Bool(false) => out.push(FALSE),
+        Value::Bool(true) => out.push(TRUE),
+        Value::Int(value) => {
+            out.push(INT);
+            out.extend_from_slice(&((*value as u64) ^ (1 << 63)).to_be_bytes());
+        }
+        Value::Float(value) => {
+            let bits = value.to_bits();
+            // negative floats order backwards, so all of their bits flip
+            let ordered = if bits >> 63 == 1 {
+                !bits
+            } else {
+                bits | (1 << 63)
+            };
+            out.push(FLOAT);
+            out.extend_from_slice(&ordered.to_be_bytes());
+        }
+        Value::String(value) => {
+            out.push(STRING);
+            encode_terminated(value.as_bytes(), out);
+        }
+        Value::Bytes(value) => {
+            out.push(BYTES);
+            encode_terminated(value, out);
+        }
+    }
+}
+
+// reads one value from the front of `bytes`, whose column was complemented when
+// `descending`, and returns it with the number of bytes it took
+fn decode_value(bytes: &[u8], descending: bool) -> Result<(Value, usize), DecodeError> {
+    let byte = |index: usize| {
+        bytes
+            .get(index)
+            .map(|byte| if descending { !byte } else { *byte })
+            .ok_or(DecodeError::Truncated)
+    };
+    let fixed = || -> Result<u64, DecodeError> {
+        let mut word = [0u8; 8];
+        for (index, slot) in word.iter_mut().enumerate() {
+            *slot = byte(1 + index)?;
+        }
+        Ok(u64::from_be_bytes(word))
+    };
+    let terminated = || -> Result<(Vec<u8>, usize), DecodeError> {
+        let mut content = Vec::new();
+        let mut index = 1;
+        loop {
+            match byte(index)? {
+                MARKER => match byte(index + 1)? {
+                    ESCAPED => {
+                        content.push(MARKER);
+                        index += 2;
+                    }
+                    END => return Ok((content, index + 2)),
+                    _ => return Err(DecodeError::InvalidEscape),
+                },
+                other => {
+                    content.push(other);
+                    index += 1;
+                }
+            }
+        }
+    };
+
+    match byte(0)? {
+        NULL => Ok((Value::Null, 1)),
+        FALSE => Ok((Value::Bool(false), 1)),
+        TRUE => Ok((Value::Bool(true), 1)),
+        INT => Ok((Value::Int((fixed()? ^ (1 << 63)) as i64), 9)),
+        FLOAT => {
+            let ordered = fixed()?;
+            let bits = if ordered >> 63 == 1 {
+                ordered & !(1 << 63)
+            } else {
+                !ordered
+            };
+            Ok((Value::Float(f64::from_bits(bits)), 9))
+        }
+        STRING => {
+            let (content, length) = terminated()?;
+            let value = String::from_utf8(content).map_err(|_| DecodeError::InvalidString)?;
+            Ok((Value::String(value), length))
+        }
+        BYTES => {
+            let (content, length) = terminated()?;
+            Ok((Value::Bytes(content), length))
+        }
+        tag => Err(DecodeError::UnknownTag(tag)),
+    }
+}
+
+// the directions of the columns of a composite key
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyCodec {
+    directions: Vec<Direction>,
+}
+
+impl KeyCodec {
+    pub fn new(directions: Vec<Direction>) -> Self {
+        KeyCodec { directions }
+    }
+
+    pub fn ascending(columns: usize) -> Self {
+        KeyCodec::new(vec![Direction::Ascending; columns])
+    }
+
+    // encodes the leading `values.len()` columns
+    pub fn encode(&self, values: &[Value]) -> Vec<u8> {
+        assert!(
+            values.len() <= self.directions.len(),
+            "{} values for {} columns",
+            values.len(),
+            self.directions.len()
+        );
+        let mut out = Vec::new();
+        for (value, direction) in values.iter().zip(self.directions.iter()) {
+            let start = out.len();
+            encode_value(value, &mut out);
+            if *direction == Direction::Descending {
+                for byte in out[start..].iter_mut() {
+                    *byte = !*byte;
+                }
+            }
+        }
+        out
+    }
+
+    pub fn decode(&self, mut bytes: &[u8]) -> Result<Vec<Value>, DecodeError> {
+        let mut values = Vec::new();
+        while !bytes.is_empty() {
+            let direction = self
+                .directions
+                .get(values.len())
+                .ok_or(DecodeError::TooManyColumns)?;
+            let (value, length) = decode_value(bytes, *direction == Direction::Descending)?;
+            values.push(value);
+            bytes = &bytes[length..];
+        }
+        Ok(values)
+    }
+
+    pub fn encode_key(&self, values: &[Value]) -> String {
+        self.encode(values).into_iter().map(char::from).collect()
+    }
+
+    pub fn decode_key(&self, key: &str) -> Result<Vec<Value>, DecodeError> {
+        let bytes = key
+            .chars()
+            .map(|c| u8::try_from(c).map_err(|_| DecodeError::InvalidKey))
+            .collect::<Result<Vec<u8>, _>>()?;
+        self.decode(&bytes)
+    }
+
+    // the tree keys whose leading columns are `prefix`; whatever follows the prefix
+    // starts with a type tag, and every tag is below 0xFF
+    pub fn prefix_range(&self, prefix: &[Value]) -> (Bound<String>, Bound<String>) {
+        let start = self.encode_key(prefix);
+        let end = format!("{}\u{ff}", start);
+        (Bound::Included(start), Bound::Excluded(end))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::BPlusTree;
+    use std::cmp::Ordering;
+
+    fn rank(value: &Value) -> u8 {
+        match value {
+            Value::Null => 0,
+            Value::Bool(_) => 1,
+            Value::Int(_) => 2,
+            Value::Float(_) => 3,
+            Value::String(_) => 4,
+            Value::Bytes(_) => 5,
+        }
+    }
+
+    // the order the encoding has to reproduce
+    fn cmp_value(a: &Value, b: &Value) -> Ordering {
+        match (a, b) {
+            (Value::Bool(a), Value::Bool(b)) => a.cmp(b),
+            (Value::Int(a), Value::Int(b)) => a.cmp(b),
+            (Value::Float(a), Value::Float(b)) => a.total_cmp(b),
+            (Value::String(a), Value::String(b)) => a.cmp(b),
+            (Value::Bytes(a), Value::Bytes(b)) => a.cmp(b),
+            _ => rank(a).cmp(&rank(b)),
+        }
+    }
+
+    fn values() -> Vec<Value> {
+        vec![
+            Value::Null,
+            false.into(),
+            true.into(),
+            i64::MIN.into(),
+            (-1i64).into(),
+            0i64.into(),
+            1i64.into(),
+            256i64.into(),
+            i64::MAX.into(),
+            f64::NEG_INFINITY.into(),
+            (-0.5f64).into(),
+            (-0.0f64).into(),
+            0.0f64.into(),
+            1e300f64.into(),
+            f64::NAN.into(),
+            "".into(),
+            "\0".into(),
+            "\0\0".into(),
+            "a".into(),
+            "a\0".into(),
+            "a\0b".into(),
+            "ab".into(),
+            "é".into(),
+            Vec::new().into(),
+            vec![0u8].into(),
+            vec![0u8, 0xff].into(),
+            vec![0xffu8].into(),
+        ]
+    }
+
+    #[test]
+    fn byte_order_matches_tuple_order() {
+        let directions = [
+            vec![Direction::Ascending, Direction::Ascending],
+            vec![Direction::Ascending, Direction::Descending],
+            vec![Direction::Descending, Direction::Ascending],
+        ];
+        let values = values();
+        for directions in directions {
+            let codec = KeyCodec::new(directions.clone());
+            let mut tuples = Vec::new();
+            for a in values.iter() {
+                for b in values.iter().step_by(2) {
+                    tuples.push(vec![a.clone(), b.clone()]);
+                }
+            }
+            let encoded: Vec<String> = tuples.iter().map(|t| codec.encode_key(t)).collect();
+            for (i, a) in tuples.iter().enumerate() {
+                for (j, b) in tuples.iter().enumerate() {
+                    let expected = a
+                        .iter()
+                        .zip(b.iter())
+                        .zip(directions.iter())
+                        .map(|((a, b), direction)| match direction {
+                            Direction::Ascending => cmp_value(a, b),
+                            Direction::Descending => cmp_value(b, a),
+                        })
+                        .find(|order| *order != Ordering::Equal)
+                        .unwrap_or(Ordering::Equal);
+                    assert_eq!(encoded[i].cmp(&encoded[j]), expected, "{:?} vs {:?}", a, b);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn keys_decode_to_their_values() {
+        let codec = KeyCodec::new(vec![Direction::Descending, Direction::Ascending]);
+        for a in values() {
+            for b in values() {
+                let tuple = vec![a.clone(), b.clone()];
+                let decoded = codec.decode_key(&codec.encode_key(&tuple)).unwrap();
+                // NaN never equals itself, so compare the encodings
+                assert_eq!(codec.encode_key(&decoded), codec.encode_key(&tuple));
+            }
+        }
+
+        let codec = KeyCodec::ascending(1);
+        assert_eq!(codec.decode(&[STRING, b'a']), Err(DecodeError::Truncated));
+        assert_eq!(codec.decode(&[0x7f]), Err(DecodeError::UnknownTag(0x7f)));
+        assert_eq!(
+            codec.decode(&[STRING, MARKER, 0x42]),
+            Err(DecodeError::InvalidEscape)
+        );
+        assert_eq!(
+            codec.decode(&[STRING, 0xc3, MARKER, END]),
+            Err(DecodeError::InvalidString)
+        );
+        assert_eq!(
+            codec.decode(&[NULL, NULL]),
+            Err(DecodeError::TooManyColumns)
+        );
+        assert_eq!(codec.decode_key("\u{100}"), Err(DecodeError::InvalidKey));
+    }
+
+    #[test]
+    fn prefixes_select_ranges() {
+        let codec = KeyCodec::ascending(2);
+        let mut tree = BPlusTree::new();
+        for user in ["ann", "ann\0", "annie", "bob"] {
+            for order in [-1i64, 0, 7] {
+                let key = codec.encode_key(&[user.into(), order.into()]);
+                tree.insert(key, format!("{}/{}", user, order));
+            }
+        }
+        let orders: Vec<String> = tree
+            .scan(codec.prefix_range(&["ann".into()]))
+            .into_iter()
+            .map(|(_, value)| value)
+            .collect();
+        assert_eq!(orders, ["ann/-1", "ann/0", "ann/7"]);
+        assert_eq!(tree.scan(codec.prefix_range(&[])).len(), 12);
+    }
+}
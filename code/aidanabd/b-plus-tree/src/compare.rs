@@ -0,0 +1,223 @@
+// Key ordering. A BPlusTree orders its keys with a Comparator, bytewise unless it is
+// created with another one, and every descent, search, split and range check goes
+// through it. Keys that compare equal are the same key: a case-insensitive tree holds
+// one entry for "Key" and "KEY", spelled the way it was last written.
+//
+// A comparator is identified by its name. Anything that persists keys in tree order
+// records the name and refuses to be opened with a comparator of another name, since
+// searching keys sorted one way with another ordering silently misses them. Two
+// comparators may only share a name if they order every pair of keys the same way.
+//
+// Full Unicode collation needs the collation tables of the Unicode Collation
+// Algorithm, which this crate does not carry; a collator from a dedicated library can
+// be plugged in by implementing Comparator for it.
+
+use std::cmp::Ordering;
+use std::fmt;
+use std::ops::Bound;
+use std::sync::Arc;
+
+use crate::keys::{shortest_separator, Key};
+
+pub trait Comparator: Send + Sync + 'static {
+    fn name(&self) -> &str;
+    // has to be a total order in which the empty key comes first
+    fn compare(&self, left: &str, right: &str) -> Ordering;
+
+    // a key above `left` and at or below `right`, promoted when a leaf splits between
+    // them; shorter separators make for smaller internal nodes
+    fn shortest_separator(&self, left: &str, right: &str) -> String {
+        let _ = left;
+        right.to_string()
+    }
+}
+
+// plain byte order, the order of `str` itself
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Bytewise;
+
+impl Comparator for Bytewise {
+    fn name(&self) -> &str {
+        "bytewise"
+    }
+
+    fn compare(&self, left: &str, right: &str) -> Ordering {
+        left.cmp(right)
+    }
+
+    fn shortest_separator(&self, left: &str, right: &str) -> String {
+        shortest_separator(left, right)
+    }
+}
+
+// compares keys by their lowercase form
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CaseInsensitive;
+
+impl Comparator for CaseInsensitive {
+    fn name(&self) -> &str {
+        "case-insensitive"
+    }
+
+    fn compare(&self, left: &str, right: &str) -> Ordering {
+        let left = left.chars().flat_map(char::to_lowercase);
+        left.cmp(right.chars().flat_map(char::to_lowercase))
+    }
+}
+
+// orders runs of ASCII digits by their numeric value, so "file9" sorts before
+// "file10"; keys that only differ in leading zeros fall back to byte order
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Natural;
+
+fn digit_run(key: &str) -> &str {
+    let end = key.find(|c: char| !c.is_ascii_digit()).unwrap_or(key.len());
+    &key[..end]
+}
+
+impl Comparator for Natural {
+    fn name(&self) -> &str {
+        "natural"
+    }
+
+    fn compare(&self, left: &str, right: &str) -> Ordering {
+        let (mut a, mut b) = (left, right);
+        loop {
+            let (Some(a_char), Some(b_char)) = (a.chars().next(), b.chars().next()) else {
+                return a.len().cmp(&b.len()).then_with(|| left.cmp(right));
+            };
+            if a_char.is_ascii_digit() && b_char.is_ascii_digit() {
+                let (a_run, b_run) = (digit_run(a), digit_run(b));
+                let (a_value, b_value) =
+                    (a_run.trim_start_matches('0'), b_run.trim_start_matches('0'));
+                let order = a_value
+                    .len()
+                    .cmp(&b_value.len())
+                    .then_with(|| a_value.cmp(b_value));
+                if order != Ordering::Equal {
+                    return order;
+                }
+                (a, b) = (&a[a_run.len()..], &b[b_run.len()..]);
+            } else {
+                if a_char != b_char {
+                    return a_char.cmp(&b_char);
+                }
+                (a, b) = (&a[a_char.len_utf8()..], &b[b_char.len_utf8()..]);
+            }
+        }
+    }
+}
+
+// the comparator a tree was created with, shared with whatever is derived from it
+#[derive(Clone)]
+pub(crate) struct KeyOrder {
+    comparator: Arc<dyn Comparator>,
+    // compressed keys can be compared without reassembling them
+    bytewise: bool,
+}
+
+impl Default for KeyOrder {
+    fn default() -> Self {
+        KeyOrder::new(Bytewise)
+    }
+}
+
+impl fmt::Debug for KeyOrder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "KeyOrder({:?})", self.name())
+    }
+}
+
+impl KeyOrder {
+    pub(crate) fn new<C: Comparator>(comparator: C) -> Self {
+        let bytewise = comparator.name() == Bytewise.name();
+        KeyOrder {
+            comparator: Arc::new(comparator),
+            bytewise,
+        }
+    }
+
+    pub(crate) fn name(&self) -> &str {
+        self.comparator.name()
+    }
+
+    pub(crate) fn is_bytewise(&self) -> bool {
+        self.bytewise
+    }
+
+    pub(crate) fn cmp(&self, left: &str, right: &str) -> Ordering {
+        if self.bytewise {
+            left.cmp(right)
+        } else {
+            self.comparator.compare(left, right)
+        }
+    }
+
+    pub(crate) fn cmp_key(&self, key: Key<'_>, other: &str) -> Ordering {
+        if self.bytewise {
+            key.cmp_str(other)
+        } else {
+            self.comparator.compare(&key.to_cow(), other)
+        }
+    }
+
+    pub(crate) fn after_start(&self, key: Key<'_>, start: Bound<&str>) -> bool {
+        match start {
+            Bound::Included(start) => self.cmp_key(key, start) != Ordering::Less,
+            Bound::Excluded(start) => self.cmp_key(key, start) == Ordering::Greater,
+            Bound::Unbounded => true,
+        }
+    }
+
+    pub(crate) fn before_end(&self, key: Key<'_>, end: Bound<&str>) -> bool {
+        match end {
+            Bound::Included(end) => self.cmp_key(key, end) != Ordering::Greater,
+            Bound::Excluded(end) => self.cmp_key(key, end) == Ordering::Less,
+            Bound::Unbounded => true,
+        }
+    }
+
+    pub(crate) fn separator(&self, left: &str, right: &str) -> String {
+        self.comparator.shortest_separator(left, right)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wisckey::WiscKey;
+    use crate::BPlusTree;
+    use std::io;
+
+    #[test]
+    fn comparators_order_keys() {
+        assert_eq!(Natural.compare("file9", "file10"), Ordering::Less);
+        assert_eq!(Natural.compare("file09", "file9"), Ordering::Less);
+        assert_eq!(Natural.compare("a2b10", "a2b9"), Ordering::Greater);
+        assert_eq!(Natural.compare("", "0"), Ordering::Less);
+        assert_eq!(CaseInsensitive.compare("Key", "KEY"), Ordering::Equal);
+        assert_eq!(CaseInsensitive.compare("apple", "Banana"), Ordering::Less);
+
+        let mut tree = BPlusTree::with_comparator(CaseInsensitive);
+        tree.insert("Key".to_string(), "1".to_string());
+        tree.insert("KEY".to_string(), "2".to_string());
+        assert_eq!(tree.len(), 1);
+        assert_eq!(tree.scan(..), vec![("KEY".to_string(), "2".to_string())]);
+        assert_eq!(tree.get("key"), Some("2".to_string()));
+    }
+
+    #[test]
+    fn value_logs_refuse_another_comparator() {
+        let dir = std::env::temp_dir().join(format!("compare_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let mut store = WiscKey::open_with_comparator(&dir, 1 << 20, Natural).unwrap();
+        store.insert("file10".to_string(), "v".to_string()).unwrap();
+        drop(store);
+        let error = WiscKey::open(&dir, 1 << 20).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        assert!(error.to_string().contains("\"natural\""));
+        let store = WiscKey::open_with_comparator(&dir, 1 << 20, Natural).unwrap();
+        assert_eq!(store.get("file10").unwrap(), Some("v".to_string()));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
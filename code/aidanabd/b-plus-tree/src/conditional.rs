@@ -0,0 +1,362 @@
+// Conditional writes. `compare_and_swap`, `insert_if_absent`, `remove_if_equals` and
+// `update_with` read the current value of a key and decide from it whether and what to
+// write, and every one of them reports the value it saw, whether it wrote or not, so
+// that a caller that lost a race learns what it lost to without a second read.
+//
+// All four are built on one read-decide-write step, which each way of sharing a tree
+// makes atomic its own way: on a BPlusTree the `&mut self` borrow keeps anyone from
+// writing in between, a two-phase locking transaction takes the exclusive lock on the
+// key before reading it, and a serializable snapshot transaction reads and writes
+// the key, so a concurrent transaction doing the same conflicts with it and one of the
+// two aborts. The RESP server holds the tree's write lock for the whole command.
+//
+// Expired entries count as absent. Like `insert` and `delete`, a conditional write
+// that changes a key clears its deadline.
+
+use crate::lock::{LockError, LockMode, Resource, Transaction};
+use crate::ssi::{SsiError, SsiTransaction};
+use crate::BPlusTree;
+
+// what a conditional write found, and whether it wrote
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WriteOutcome {
+    // the key was written or removed; `previous` is the value it had before
+    Written { previous: Option<String> },
+    // the condition did not hold and nothing changed; `current` is the value it has
+    Unchanged { current: Option<String> },
+}
+
+impl WriteOutcome {
+    pub fn is_written(&self) -> bool {
+        matches!(self, WriteOutcome::Written { .. })
+    }
+
+    // the value the write observed, whether or not it went ahead
+    pub fn observed(&self) -> Option<&str> {
+        match self {
+            WriteOutcome::Written { previous } => previous.as_deref(),
+            WriteOutcome::Unchanged { current } => current.as_deref(),
+        }
+    }
+
+    pub fn into_observed(self) -> Option<String> {
+        match self {
+            WriteOutcome::Written { previous } => previous,
+            WriteOutcome::Unchanged { current } => current,
+        }
+    }
+}
+
+// what to do with a key, given its value
+enum Change {
+    Keep,
+    Put(String),
+    Remove,
+}
+
+fn swap(expected: Option<&str>, new: String) -> impl FnOnce(Option<&str>) -> Change + '_ {
+    move |current| {
+        if current == expected {
+            Change::Put(new)
+        } else {
+            Change::Keep
+        }
+    }
+}
+
+fn remove_equal(expected: &str) -> impl FnOnce(Option<&str>) -> Change + '_ {
+    move |current| {
+        if current == Some(expected) {
+            Change::Remove
+        } else {
+            Change::Keep
+        }
+    }
+}
+
+fn update<F: FnOnce(Option<&str>) -> Option<String>>(f: F) -> impl FnOnce(Option<&str>) -> Change {
+    move |current| match (f(current), current) {
+        (Some(value), _) => Change::Put(value),
+        (None, Some(_)) => Change::Remove,
+        (None, None) => Change::Keep,
+    }
+}
+
+// decides on a change and splits it into the write to make, if any, and the outcome
+fn decide<D: FnOnce(Option<&str>) -> Change>(
+    current: Option<String>,
+    decide: D,
+) -> (Option<Option<String>>, WriteOutcome) {
+    match decide(current.as_deref()) {
+        Change::Keep => (None, WriteOutcome::Unchanged { current }),
+        Change::Put(value) => (
+            Some(Some(value)),
+            WriteOutcome::Written { previous: current },
+        ),
+        Change::Remove => (Some(None), WriteOutcome::Written { previous: current }),
+    }
+}
+
+impl BPlusTree {
+    fn write_if<D: FnOnce(Option<&str>) -> Change>(
+        &mut self,
+        key: String,
+        decide_change: D,
+    ) -> WriteOutcome {
+        let (write, outcome) = decide(self.get(&key), decide_change);
+        match write {
+            Some(Some(value)) => self.insert(key, value),
+            Some(None) => self.delete(key),
+            None => (),
+        }
+        outcome
+    }
+
+    // sets `key` to `new` if its value is `expected`, None meaning absent
+    pub fn compare_and_swap(
+        &mut self,
+        key: String,
+        expected: Option<&str>,
+        new: String,
+    ) -> WriteOutcome {
+        self.write_if(key, swap(expected, new))
+    }
+
+    pub fn insert_if_absent(&mut self, key: String, value: String) -> WriteOutcome {
+        self.write_if(key, swap(None, value))
+    }
+
+    pub fn remove_if_equals(&mut self, key: String, expected: &str) -> WriteOutcome {
+        self.write_if(key, remove_equal(expected))
+    }
+
+    // sets `key` to what `f` makes of its value, removing it if `f` returns None
+    pub fn update_with<F: FnOnce(Option<&str>) -> Option<String>>(
+        &mut self,
+        key: String,
+        f: F,
+    ) -> WriteOutcome {
+        self.write_if(key, update(f))
+    }
+}
+
+impl Transaction<'_> {
+    fn write_if<D: FnOnce(Option<&str>) -> Change>(
+        &mut self,
+        key: String,
+        decide_change: D,
+    ) -> Result<WriteOutcome, LockError> {
+        // locking exclusively before reading rules out two transactions both reading
+        // under shared locks and deadlocking on the upgrade
+        self.lock(Resource::Key(key.clone()), LockMode::Exclusive)?;
+        let (write, outcome) = decide(self.get(&key)?, decide_change);
+        if let Some(write) = write {
+            self.writes.insert(key, write);
+        }
+        Ok(outcome)
+    }
+
+    pub fn compare_and_swap(
+        &mut self,
+        key: String,
+        expected: Option<&str>,
+        new: String,
+    ) -> Result<WriteOutcome, LockError> {
+        self.write_if(key, swap(expected, new))
+    }
+
+    pub fn insert_if_absent(
+        &mut self,
+        key: String,
+        value: String,
+    ) -> Result<WriteOutcome, LockError> {
+        self.write_if(key, swap(None, value))
+    }
+
+    pub fn remove_if_equals(
+        &mut self,
+        key: String,
+        expected: &str,
+    ) -> Result<WriteOutcome, LockError> {
+        self.write_if(key, remove_equal(expected))
+    }
+
+    pub fn update_with<F: FnOnce(Option<&str>) -> Option<String>>(
+        &mut self,
+        key: String,
+        f: F,
+    ) -> Result<WriteOutcome, LockError> {
+        self.write_if(key, update(f))
+    }
+}
+
+impl SsiTransaction<'_> {
+    fn write_if<D: FnOnce(Option<&str>) -> Change>(
+        &mut self,
+        key: String,
+        decide_change: D,
+    ) -> Result<WriteOutcome, SsiError> {
+        let (write, outcome) = decide(self.get(&key)?, decide_change);
+        if let Some(write) = write {
+            self.write(key, write)?;
+        }
+        Ok(outcome)
+    }
+
+    pub fn compare_and_swap(
+        &mut self,
+        key: String,
+        expected: Option<&str>,
+        new: String,
+    ) -> Result<WriteOutcome, SsiError> {
+        self.write_if(key, swap(expected, new))
+    }
+
+    pub fn insert_if_absent(
+        &mut self,
+        key: String,
+        value: String,
+    ) -> Result<WriteOutcome, SsiError> {
+        self.write_if(key, swap(None, value))
+    }
+
+    pub fn remove_if_equals(
+        &mut self,
+        key: String,
+        expected: &str,
+    ) -> Result<WriteOutcome, SsiError> {
+        self.write_if(key, remove_equal(expected))
+    }
+
+    pub fn update_with<F: FnOnce(Option<&str>) -> Option<String>>(
+        &mut self,
+        key: String,
+        f: F,
+    ) -> Result<WriteOutcome, SsiError> {
+        self.write_if(key, update(f))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lock::LockingTree;
+    use crate::ssi::SsiTree;
+    use std::thread;
+    use std::time::Duration;
+
+    const THREADS: usize = 4;
+    const INCREMENTS: usize = 50;
+
+    fn increment(current: Option<&str>) -> Option<String> {
+        let count: usize = current.map_or(0, |value| value.parse().unwrap());
+        Some((count + 1).to_string())
+    }
+
+    #[test]
+    fn outcomes_report_the_observed_value() {
+        let mut tree = BPlusTree::new();
+        let written = |previous: Option<&str>| WriteOutcome::Written {
+            previous: previous.map(str::to_string),
+        };
+        let unchanged = |current: Option<&str>| WriteOutcome::Unchanged {
+            current: current.map(str::to_string),
+        };
+
+        assert_eq!(tree.insert_if_absent("a".into(), "1".into()), written(None));
+        assert_eq!(
+            tree.insert_if_absent("a".into(), "2".into()),
+            unchanged(Some("1"))
+        );
+        assert_eq!(
+            tree.compare_and_swap("a".into(), Some("0"), "2".into()),
+            unchanged(Some("1"))
+        );
+        assert_eq!(
+            tree.compare_and_swap("a".into(), Some("1"), "2".into()),
+            written(Some("1"))
+        );
+        assert_eq!(tree.remove_if_equals("a".into(), "1"), unchanged(Some("2")));
+        assert_eq!(tree.remove_if_equals("a".into(), "2"), written(Some("2")));
+        assert_eq!(tree.get("a"), None);
+
+        assert_eq!(tree.update_with("b".into(), |_| None), unchanged(None));
+        assert_eq!(tree.update_with("b".into(), increment), written(None));
+        assert_eq!(tree.update_with("b".into(), increment), written(Some("1")));
+        assert_eq!(tree.update_with("b".into(), |_| None), written(Some("2")));
+        assert_eq!(tree.len(), 0);
+    }
+
+    #[test]
+    fn locking_transactions_do_not_lose_updates() {
+        let db = LockingTree::new(BPlusTree::new(), Duration::from_secs(5));
+        thread::scope(|scope| {
+            for _ in 0..THREADS {
+                scope.spawn(|| {
+                    for _ in 0..INCREMENTS {
+                        loop {
+                            let mut transaction = db.begin();
+                            let done = transaction
+                                .update_with("counter".into(), increment)
+                                .and_then(|_| transaction.commit());
+                            if done.is_ok() {
+                                break;
+                            }
+                        }
+                    }
+                });
+            }
+        });
+        let expected = (THREADS * INCREMENTS).to_string();
+        assert_eq!(db.into_inner().get("counter"), Some(expected));
+    }
+
+    #[test]
+    fn snapshot_transactions_do_not_lose_updates() {
+        let db = SsiTree::new();
+        thread::scope(|scope| {
+            for _ in 0..THREADS {
+                scope.spawn(|| {
+                    for _ in 0..INCREMENTS {
+                        loop {
+                            let mut transaction = db.begin();
+                            let done = transaction
+                                .update_with("counter".into(), increment)
+                                .and_then(|_| transaction.commit());
+                            if done.is_ok() {
+                                break;
+                            }
+                        }
+                    }
+                });
+            }
+        });
+        let expected = (THREADS * INCREMENTS).to_string();
+        assert_eq!(db.begin().get("counter"), Ok(Some(expected)));
+    }
+
+    #[test]
+    fn snapshot_compare_and_swap_loses_to_an_earlier_commit() {
+        let db = SsiTree::new();
+        let mut first = db.begin();
+        let mut second = db.begin();
+        assert!(first
+            .insert_if_absent("leader".into(), "a".into())
+            .unwrap()
+            .is_written());
+        assert!(second
+            .insert_if_absent("leader".into(), "b".into())
+            .unwrap()
+            .is_written());
+        first.commit().unwrap();
+        assert_eq!(second.commit(), Err(SsiError::SerializationFailure));
+
+        let mut third = db.begin();
+        assert_eq!(
+            third.insert_if_absent("leader".into(), "c".into()),
+            Ok(WriteOutcome::Unchanged {
+                current: Some("a".into())
+            })
+        );
+    }
+}
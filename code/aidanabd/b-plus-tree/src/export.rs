@@ -0,0 +1,302 @@
+// Structure exports for debugging and design reviews. `to_dot` renders the tree for
+// Graphviz: every node is a record of its keys, internal nodes have a port between
+// each pair of separators that their child edge starts from, children point back to
+// their parent with a dashed edge, and the leaves are chained left to right with
+// dotted sibling edges the way a linked-leaf B+ tree would be.
+//
+// `to_json` writes the same structure for other tools. Nodes are numbered in level
+// order from the root rather than by their arena index, so the export of a tree does
+// not depend on the order its nodes happened to be allocated in.
+
+use std::fmt::Write;
+
+use crate::overflow::LeafValue;
+use crate::{BPlusTree, Message, NodeValue};
+
+const INTERNAL_COLOR: &str = "lightblue";
+const LEAF_COLOR: &str = "palegreen";
+
+// escapes the characters that structure a record label
+fn dot_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\\' | '{' | '}' | '|' | '<' | '>' | '"' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            '\n' => escaped.push_str("\\n"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+pub(crate) fn json_string(text: &str, out: &mut String) {
+    out.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+fn json_list<T>(items: &[T], out: &mut String, mut item: impl FnMut(&T, &mut String)) {
+    out.push('[');
+    for (position, value) in items.iter().enumerate() {
+        if position > 0 {
+            out.push(',');
+        }
+        item(value, out);
+    }
+    out.push(']');
+}
+
+impl BPlusTree {
+    // arena indices in level order, and the number of every node in that order
+    fn level_order(&self) -> (Vec<usize>, Vec<usize>) {
+        let mut order = vec![self.root_index];
+        let mut next = 0;
+        while next < order.len() {
+            if let NodeValue::Internal(ref children) = self.nodes[order[next]].values {
+                order.extend(children.iter().copied());
+            }
+            next += 1;
+        }
+        let mut numbers = vec![0; self.nodes.len()];
+        for (number, index) in order.iter().enumerate() {
+            numbers[*index] = number;
+        }
+        (order, numbers)
+    }
+
+    pub fn to_dot(&self) -> String {
+        let (order, numbers) = self.level_order();
+        let mut dot = String::new();
+        dot.push_str("digraph BPlusTree {\n");
+        dot.push_str("    node [shape=record, style=filled, fontname=\"monospace\"];\n");
+
+        let mut leaves = Vec::new();
+        for index in order.iter() {
+            let node = &self.nodes[*index];
+            let number = numbers[*index];
+            let keys: Vec<String> = node
+                .keys
+                .iter()
+                .map(|key| dot_escape(&key.to_cow()))
+                .collect();
+            let (mut label, color) = match node.values {
+                NodeValue::Internal(ref children) => {
+                    let mut label = String::from("<c0>");
+                    for (position, key) in keys.iter().enumerate() {
+                        write!(label, "|{}|<c{}>", key, position + 1).unwrap();
+                    }
+                    for (position, child) in children.iter().enumerate() {
+                        writeln!(
+                            dot,
+                            "    n{}:c{} -> n{};",
+                            number, position, numbers[*child]
+                        )
+                        .unwrap();
+                    }
+                    (label, INTERNAL_COLOR)
+                }
+                NodeValue::Leaf(_) => {
+                    leaves.push(number);
+                    (keys.join("|"), LEAF_COLOR)
+                }
+            };
+            if !node.buffer.is_empty() {
+                write!(label, "|+{} pending", node.buffer.len()).unwrap();
+            }
+            writeln!(
+                dot,
+                "    n{} [label=\"{}\", fillcolor=\"{}\"];",
+                number, label, color
+            )
+            .unwrap();
+            if let Some(parent) = node.parent {
+                writeln!(
+                    dot,
+                    "    n{} -> n{} [style=dashed, color=gray, constraint=false];",
+                    number, numbers[parent]
+                )
+                .unwrap();
+            }
+        }
+
+        // level order visits the leaves from left to right
+        for pair in leaves.windows(2) {
+            writeln!(
+                dot,
+                "    n{} -> n{} [style=dotted, color=darkgreen, constraint=false];",
+                pair[0], pair[1]
+            )
+            .unwrap();
+        }
+        if !leaves.is_empty() {
+            let names: Vec<String> = leaves.iter().map(|leaf| format!("n{}", leaf)).collect();
+            writeln!(dot, "    {{ rank=same; {}; }}", names.join("; ")).unwrap();
+        }
+        dot.push_str("}\n");
+        dot
+    }
+
+    // {"comparator", "len", "root", "nodes": [{"id", "kind", "parent", "keys", ...}]},
+    // where internal nodes list their "children" and the "counts" below them and
+    // leaves their "values", each a string or {"first_page", "length"} when stored in
+    // overflow pages, and both list their "pending" writes
+    pub fn to_json(&self) -> String {
+        let (order, numbers) = self.level_order();
+        let mut json = String::from("{\"comparator\":");
+        json_string(self.comparator_name(), &mut json);
+        write!(json, ",\"len\":{},\"root\":0,\"nodes\":", self.len()).unwrap();
+
+        json_list(&order, &mut json, |index, json| {
+            let node = &self.nodes[*index];
+            let kind = match node.values {
+                NodeValue::Internal(_) => "internal",
+                NodeValue::Leaf(_) => "leaf",
+            };
+            write!(
+                json,
+                "{{\"id\":{},\"kind\":\"{}\",\"parent\":",
+                numbers[*index], kind
+            )
+            .unwrap();
+            match node.parent {
+                Some(parent) => write!(json, "{}", numbers[parent]).unwrap(),
+                None => json.push_str("null"),
+            }
+            json.push_str(",\"keys\":");
+            let keys: Vec<_> = node.keys.iter().collect();
+            json_list(&keys, json, |key, json| json_string(&key.to_cow(), json));
+
+            match node.values {
+                NodeValue::Internal(ref children) => {
+                    json.push_str(",\"children\":");
+                    json_list(children, json, |child, json| {
+                        write!(json, "{}", numbers[*child]).unwrap()
+                    });
+                    json.push_str(",\"counts\":");
+                    json_list(&node.counts, json, |count, json| {
+                        write!(json, "{}", count).unwrap()
+                    });
+                }
+                NodeValue::Leaf(ref values) => {
+                    json.push_str(",\"values\":");
+                    json_list(values, json, |value, json| match value {
+                        LeafValue::Inline(value) => json_string(value, json),
+                        LeafValue::Overflow { first_page, length } => write!(
+                            json,
+                            "{{\"first_page\":{},\"length\":{}}}",
+                            first_page, length
+                        )
+                        .unwrap(),
+                    });
+                }
+            }
+
+            json.push_str(",\"pending\":");
+            json_list(&node.buffer, json, |(key, message), json| {
+                json.push_str("{\"key\":");
+                json_string(key, json);
+                match message {
+                    Message::Put(value) => {
+                        json.push_str(",\"put\":");
+                        json_string(value, json);
+                    }
+                    Message::Delete => json.push_str(",\"delete\":true"),
+                    Message::Upsert(operand) => {
+                        json.push_str(",\"upsert\":");
+                        json_string(operand, json);
+                    }
+                }
+                json.push('}');
+            });
+            json.push('}');
+        });
+        json.push('}');
+        json
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn two_leaves() -> BPlusTree {
+        let mut tree = BPlusTree::new();
+        for key in ["a", "b", "c", "d", "e", "f"] {
+            tree.insert(key.to_string(), key.to_uppercase());
+        }
+        tree
+    }
+
+    #[test]
+    fn dot_shows_nodes_and_edges() {
+        let expected = "\
+digraph BPlusTree {
+    node [shape=record, style=filled, fontname=\"monospace\"];
+    n0:c0 -> n1;
+    n0:c1 -> n2;
+    n0 [label=\"<c0>|d|<c1>\", fillcolor=\"lightblue\"];
+    n1 [label=\"a|b|c\", fillcolor=\"palegreen\"];
+    n1 -> n0 [style=dashed, color=gray, constraint=false];
+    n2 [label=\"d|e|f\", fillcolor=\"palegreen\"];
+    n2 -> n0 [style=dashed, color=gray, constraint=false];
+    n1 -> n2 [style=dotted, color=darkgreen, constraint=false];
+    { rank=same; n1; n2; }
+}
+";
+        assert_eq!(two_leaves().to_dot(), expected);
+
+        let mut tree = BPlusTree::with_update_buffer(8);
+        tree.insert("a|b".to_string(), String::new());
+        tree.insert("{\"x\"}\n".to_string(), String::new());
+        let dot = tree.to_dot();
+        assert!(dot.contains("n0 [label=\"|+2 pending\", fillcolor=\"palegreen\"];"));
+        tree.flush();
+        assert!(tree
+            .to_dot()
+            .contains("n0 [label=\"a\\|b|\\{\\\"x\\\"\\}\\n\", fillcolor=\"palegreen\"];"));
+    }
+
+    #[test]
+    fn json_lists_nodes_in_level_order() {
+        let expected = concat!(
+            "{\"comparator\":\"bytewise\",\"len\":6,\"root\":0,\"nodes\":[",
+            "{\"id\":0,\"kind\":\"internal\",\"parent\":null,\"keys\":[\"d\"],",
+            "\"children\":[1,2],\"counts\":[3,3],\"pending\":[]},",
+            "{\"id\":1,\"kind\":\"leaf\",\"parent\":0,\"keys\":[\"a\",\"b\",\"c\"],",
+            "\"values\":[\"A\",\"B\",\"C\"],\"pending\":[]},",
+            "{\"id\":2,\"kind\":\"leaf\",\"parent\":0,\"keys\":[\"d\",\"e\",\"f\"],",
+            "\"values\":[\"D\",\"E\",\"F\"],\"pending\":[]}]}",
+        );
+        assert_eq!(two_leaves().to_json(), expected);
+
+        let mut tree = BPlusTree::with_update_buffer(8);
+        tree.overflow.threshold = Some(4);
+        tree.insert("long".to_string(), "overflowing".to_string());
+        tree.flush();
+        tree.insert("quote\"".to_string(), "tab\t\u{1}".to_string());
+        tree.delete("long".to_string());
+        assert_eq!(
+            tree.to_json(),
+            concat!(
+                "{\"comparator\":\"bytewise\",\"len\":1,\"root\":0,\"nodes\":[",
+                "{\"id\":0,\"kind\":\"leaf\",\"parent\":null,\"keys\":[\"long\"],",
+                "\"values\":[{\"first_page\":0,\"length\":11}],\"pending\":[",
+                "{\"key\":\"long\",\"delete\":true},",
+                "{\"key\":\"quote\\\"\",\"put\":\"tab\\t\\u0001\"}]}]}",
+            )
+        );
+    }
+}
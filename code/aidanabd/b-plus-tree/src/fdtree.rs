@@ -0,0 +1,373 @@
+// An FD-tree (Li et al.) for flash-style storage, where random writes are expensive and
+// sequential ones are cheap.
+//
+// Fresh writes go into a small head BPlusTree. Below it is a cascade of sorted runs,
+// level k holding up to `head_capacity * ratio^(k + 1)` records, each split into pages
+// of PAGE_SIZE entries. When the head overflows it is merged into the first level that
+// can take everything above it; that level is rewritten in one sequential pass and the
+// levels above it are left with nothing but fences. Records are never updated in place.
+//
+// Levels are linked by fences (fractional cascading): every page of level k + 1 has a
+// fence in level k holding its first key and page number, and every page of level k
+// starts with a fence, so a lookup reads exactly one page per level and moves down
+// through the last fence at or below the key. Deletes insert a filter entry that hides
+// older records below it until both meet in a merge; filters that reach the last level
+// are dropped.
+
+use std::collections::BTreeMap;
+use std::ops::RangeBounds;
+
+use crate::{after_start, before_end, str_bounds, BPlusTree};
+
+const PAGE_SIZE: usize = 8;
+
+#[derive(Debug, Clone)]
+enum Entry {
+    Put { key: String, value: String },
+    Filter { key: String },
+    // keys from here on continue in `page` of the next level
+    Fence { key: String, page: usize },
+}
+
+impl Entry {
+    fn key(&self) -> &str {
+        match self {
+            Entry::Put { key, .. } | Entry::Filter { key } | Entry::Fence { key, .. } => key,
+        }
+    }
+
+    fn record(key: String, value: Option<String>) -> Entry {
+        match value {
+            Some(value) => Entry::Put { key, value },
+            None => Entry::Filter { key },
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Level {
+    entries: Vec<Entry>,
+    records: usize,
+}
+
+impl Level {
+    fn page(&self, page: usize) -> &[Entry] {
+        let start = (page * PAGE_SIZE).min(self.entries.len());
+        &self.entries[start..(start + PAGE_SIZE).min(self.entries.len())]
+    }
+
+    // one fence per page; the first page is reached from the smallest key
+    fn fences(&self) -> Vec<Entry> {
+        self.entries
+            .chunks(PAGE_SIZE)
+            .enumerate()
+            .map(|(page, entries)| Entry::Fence {
+                key: if page == 0 {
+                    String::new()
+                } else {
+                    entries[0].key().to_string()
+                },
+                page,
+            })
+            .collect()
+    }
+
+    fn records(&self) -> impl Iterator<Item = (&String, Option<&String>)> {
+        self.entries.iter().filter_map(|entry| match entry {
+            Entry::Put { key, value } => Some((key, Some(value))),
+            Entry::Filter { key } => Some((key, None)),
+            Entry::Fence { .. } => None,
+        })
+    }
+
+    // lays out records and the fences into `below` page by page, starting every page
+    // with a fence so that a lookup never has to look at the previous page
+    fn build(records: Vec<(String, Option<String>)>, below: Option<&Level>) -> Level {
+        let fences = below.map(Level::fences).unwrap_or_default();
+        let record_count = records.len();
+        let mut merged = Vec::with_capacity(record_count + fences.len());
+        let mut records = records.into_iter().peekable();
+        let mut fences = fences.into_iter().peekable();
+        loop {
+            let take_fence = match (fences.peek(), records.peek()) {
+                (Some(fence), Some((key, _))) => fence.key() <= key.as_str(),
+                (Some(_), None) => true,
+                (None, Some(_)) => false,
+                (None, None) => break,
+            };
+            if take_fence {
+                merged.push(fences.next().unwrap());
+            } else {
+                let (key, value) = records.next().unwrap();
+                merged.push(Entry::record(key, value));
+            }
+        }
+
+        let mut entries: Vec<Entry> = Vec::with_capacity(merged.len());
+        let mut current_fence = None;
+        for entry in merged {
+            if let Entry::Fence { page, .. } = entry {
+                current_fence = Some(page);
+            } else if entries.len().is_multiple_of(PAGE_SIZE) {
+                if let Some(page) = current_fence {
+                    // an internal fence repeating the one in effect
+                    entries.push(Entry::Fence {
+                        key: entry.key().to_string(),
+                        page,
+                    });
+                }
+            }
+            entries.push(entry);
+        }
+
+        Level {
+            entries,
+            records: record_count,
+        }
+    }
+}
+
+fn encode(value: Option<&str>) -> String {
+    match value {
+        Some(value) => format!("+{}", value),
+        None => "-".to_string(),
+    }
+}
+
+fn decode(value: &str) -> Option<String> {
+    value.strip_prefix('+').map(|value| value.to_string())
+}
+
+// merges two sorted runs of records, `newer` winning on equal keys
+fn merge_runs(
+    newer: Vec<(String, Option<String>)>,
+    older: Vec<(String, Option<String>)>,
+) -> Vec<(String, Option<String>)> {
+    let mut merged = Vec::with_capacity(newer.len() + older.len());
+    let mut newer = newer.into_iter().peekable();
+    let mut older = older.into_iter().peekable();
+    loop {
+        match (newer.peek(), older.peek()) {
+            (Some((new_key, _)), Some((old_key, _))) => {
+                if new_key <= old_key {
+                    if new_key == old_key {
+                        older.next();
+                    }
+                    merged.push(newer.next().unwrap());
+                } else {
+                    merged.push(older.next().unwrap());
+                }
+            }
+            (Some(_), None) => merged.push(newer.next().unwrap()),
+            (None, Some(_)) => merged.push(older.next().unwrap()),
+            (None, None) => return merged,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct FdTree {
+    head: BPlusTree,
+    head_len: usize,
+    head_capacity: usize,
+    // fences into the pages of the first level
+    head_fences: Vec<Entry>,
+    levels: Vec<Level>,
+    ratio: usize,
+}
+
+impl FdTree {
+    pub fn new(head_capacity: usize, ratio: usize) -> Self {
+        assert!(head_capacity > 0, "the head has to hold at least one entry");
+        assert!(ratio > 1, "levels have to grow");
+        FdTree {
+            head: BPlusTree::new(),
+            head_len: 0,
+            head_capacity,
+            head_fences: Vec::new(),
+            levels: Vec::new(),
+            ratio,
+        }
+    }
+
+    // records (puts and filters) per level, head first
+    pub fn level_sizes(&self) -> Vec<usize> {
+        std::iter::once(self.head_len)
+            .chain(self.levels.iter().map(|level| level.records))
+            .collect()
+    }
+
+    fn capacity(&self, level: usize) -> usize {
+        self.head_capacity * self.ratio.pow(level as u32 + 1)
+    }
+
+    pub fn insert(&mut self, key: String, value: String) {
+        self.write(key, encode(Some(&value)));
+    }
+
+    pub fn delete(&mut self, key: String) {
+        self.write(key, encode(None));
+    }
+
+    fn write(&mut self, key: String, value: String) {
+        if self.head.get(&key).is_none() {
+            self.head_len += 1;
+        }
+        self.head.insert(key, value);
+        if self.head_len > self.head_capacity {
+            self.merge_head();
+        }
+    }
+
+    fn merge_head(&mut self) {
+        let mut incoming: Vec<(String, Option<String>)> = self
+            .head
+            .scan(..)
+            .into_iter()
+            .map(|(key, value)| {
+                let value = decode(&value);
+                (key, value)
+            })
+            .collect();
+        self.head = BPlusTree::new();
+        self.head_len = 0;
+
+        // find the first level that can take everything above it
+        let mut target = 0;
+        loop {
+            if target == self.levels.len() {
+                self.levels.push(Level::default());
+            }
+            let older = self.levels[target]
+                .records()
+                .map(|(key, value)| (key.clone(), value.cloned()))
+                .collect();
+            incoming = merge_runs(incoming, older);
+            if target + 1 == self.levels.len() {
+                // nothing below can be hidden by a filter anymore
+                incoming.retain(|(_, value)| value.is_some());
+            }
+            if incoming.len() <= self.capacity(target) {
+                break;
+            }
+            target += 1;
+        }
+
+        self.levels[target] = Level::build(incoming, self.levels.get(target + 1));
+        for level in (0..target).rev() {
+            self.levels[level] = Level::build(Vec::new(), Some(&self.levels[level + 1]));
+        }
+        self.head_fences = self.levels[0].fences();
+    }
+
+    pub fn get(&self, key: &str) -> Option<String> {
+        if let Some(value) = self.head.get(key) {
+            return decode(&value);
+        }
+
+        let mut fences: &[Entry] = &self.head_fences;
+        for level in &self.levels {
+            // every page starts with a fence at or below the keys it holds, so there is
+            // none only when all levels below are empty
+            let page = fences.iter().rev().find_map(|entry| match entry {
+                Entry::Fence { key: fence, page } if fence.as_str() <= key => Some(*page),
+                _ => None,
+            })?;
+            let entries = level.page(page);
+            for entry in entries {
+                match entry {
+                    Entry::Put { key: k, value } if k == key => return Some(value.clone()),
+                    Entry::Filter { key: k } if k == key => return None,
+                    _ => {}
+                }
+            }
+            fences = entries;
+        }
+        None
+    }
+
+    pub fn scan<R: RangeBounds<String>>(&self, range: R) -> Vec<(String, String)> {
+        let (start, end) = str_bounds(&range);
+        let mut result: BTreeMap<&str, Option<&str>> = BTreeMap::new();
+
+        // oldest first, so newer levels overwrite what they shadow
+        for level in self.levels.iter().rev() {
+            let first = level
+                .entries
+                .partition_point(|entry| !after_start(entry.key(), start));
+            for (key, value) in level.entries[first..]
+                .iter()
+                .take_while(|entry| before_end(entry.key(), end))
+                .filter_map(|entry| match entry {
+                    Entry::Put { key, value } => Some((key, Some(value))),
+                    Entry::Filter { key } => Some((key, None)),
+                    Entry::Fence { .. } => None,
+                })
+            {
+                result.insert(key, value.map(|value| value.as_str()));
+            }
+        }
+
+        let head = self.head.scan(range);
+        for (key, value) in &head {
+            result.insert(key, value.strip_prefix('+'));
+        }
+        result
+            .into_iter()
+            .filter_map(|(key, value)| value.map(|value| (key.to_string(), value.to_string())))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(i: usize) -> String {
+        format!("{:05}", i * 7919 % 3000)
+    }
+
+    #[test]
+    fn lookups_match_the_tree() {
+        let mut fdtree = FdTree::new(8, 3);
+        let mut tree = BPlusTree::new();
+        for i in 0..5000 {
+            if i % 5 == 0 {
+                fdtree.delete(key(i / 2));
+                tree.delete(key(i / 2));
+            } else {
+                fdtree.insert(key(i), i.to_string());
+                tree.insert(key(i), i.to_string());
+            }
+
+            if i % 997 == 0 {
+                assert_eq!(fdtree.scan(..), tree.scan(..));
+            }
+        }
+        // merges cascaded past the first level
+        assert!(fdtree.level_sizes().len() > 3);
+
+        for i in 0..3100 {
+            let key = format!("{:05}", i);
+            assert_eq!(fdtree.get(&key), tree.get(&key));
+        }
+        assert_eq!(fdtree.get(""), None);
+        assert_eq!(fdtree.scan(..), tree.scan(..));
+        let range = "00100".to_string().."02000".to_string();
+        assert_eq!(fdtree.scan(range.clone()), tree.scan(range));
+    }
+
+    #[test]
+    fn levels_stay_within_capacity() {
+        let mut fdtree = FdTree::new(4, 2);
+        for i in 0..1000 {
+            fdtree.insert(format!("{:04}", i), String::new());
+            let sizes = fdtree.level_sizes();
+            assert!(sizes[0] <= 4);
+            for (level, size) in sizes[1..].iter().enumerate() {
+                assert!(*size <= fdtree.capacity(level));
+            }
+        }
+        assert_eq!(fdtree.scan(..).len(), 1000);
+    }
+}
@@ -0,0 +1,314 @@
+// Prefix-compressed node keys. Keys in a node are sorted and, below the root, all fall
+// between the separators of the parent, so long keys such as URLs tend to share a
+// long prefix. NodeKeys stores that prefix once and only the suffixes per key. Keys
+// are read through `Key`, which in byte order compares against plain strings without
+// rebuilding the full key, so lookups work on compressed nodes as they did on plain
+// ones. Other comparators see the reassembled key.
+//
+// Inserting a key that does not share the whole prefix shortens the prefix; removing
+// keys never lengthens it until the node is split or merged, which recompute it.
+
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::fmt;
+use std::mem::size_of;
+
+use crate::compare::KeyOrder;
+
+// the byte length of the longest common prefix, on a char boundary
+fn common_prefix(a: &str, b: &str) -> usize {
+    a.char_indices()
+        .zip(b.chars())
+        .find(|((_, a), b)| a != b)
+        .map_or(a.len().min(b.len()), |((index, _), _)| index)
+}
+
+// the shortest key that sorts above `left` and at or below `right`, so that it can
+// separate two leaves as well as `right` itself
+pub(crate) fn shortest_separator(left: &str, right: &str) -> String {
+    let shared = common_prefix(left, right);
+    let next = right[shared..].chars().next().map_or(0, char::len_utf8);
+    right[..shared + next].to_string()
+}
+
+#[derive(Clone, Copy)]
+pub(crate) struct Key<'a> {
+    prefix: &'a str,
+    suffix: &'a str,
+}
+
+impl<'a> Key<'a> {
+    pub(crate) fn full(key: &'a str) -> Self {
+        Key {
+            prefix: "",
+            suffix: key,
+        }
+    }
+
+    // byte order, without reassembling the key
+    pub(crate) fn cmp_str(&self, other: &str) -> Ordering {
+        let (prefix, other) = (self.prefix.as_bytes(), other.as_bytes());
+        if other.len() < prefix.len() {
+            return prefix[..other.len()].cmp(other).then(Ordering::Greater);
+        }
+        prefix
+            .cmp(&other[..prefix.len()])
+            .then_with(|| self.suffix.as_bytes().cmp(&other[prefix.len()..]))
+    }
+
+    pub(crate) fn to_cow(self) -> Cow<'a, str> {
+        if self.prefix.is_empty() {
+            Cow::Borrowed(self.suffix)
+        } else {
+            Cow::Owned(self.to_string())
+        }
+    }
+}
+
+impl fmt::Display for Key<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", self.prefix, self.suffix)
+    }
+}
+
+impl fmt::Debug for Key<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self.to_string())
+    }
+}
+
+#[derive(Clone, Default)]
+pub(crate) struct NodeKeys {
+    prefix: String,
+    suffixes: Vec<String>,
+}
+
+impl fmt::Debug for NodeKeys {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?} + {:?}", self.prefix, self.suffixes)
+    }
+}
+
+impl From<Vec<String>> for NodeKeys {
+    fn from(keys: Vec<String>) -> Self {
+        // under other comparators the keys in between need not share the prefix of
+        // the first and last key
+        let length = match keys.first() {
+            Some(first) => keys.iter().fold(first.len(), |length, key| {
+                common_prefix(&first[..length], key)
+            }),
+            None => 0,
+        };
+        let prefix = keys
+            .first()
+            .map_or(String::new(), |first| first[..length].to_string());
+        NodeKeys {
+            prefix,
+            suffixes: keys
+                .into_iter()
+                .map(|key| key[length..].to_string())
+                .collect(),
+        }
+    }
+}
+
+impl NodeKeys {
+    pub(crate) fn with_capacity(capacity: usize) -> Self {
+        NodeKeys {
+            prefix: String::new(),
+            suffixes: Vec::with_capacity(capacity),
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.suffixes.len()
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.suffixes.is_empty()
+    }
+
+    pub(crate) fn get(&self, index: usize) -> Option<Key<'_>> {
+        self.suffixes.get(index).map(|suffix| Key {
+            prefix: &self.prefix,
+            suffix,
+        })
+    }
+
+    pub(crate) fn key(&self, index: usize) -> Key<'_> {
+        self.get(index).expect("key index out of bounds")
+    }
+
+    pub(crate) fn iter(&self) -> impl DoubleEndedIterator<Item = Key<'_>> + '_ {
+        self.suffixes.iter().map(|suffix| Key {
+            prefix: &self.prefix,
+            suffix,
+        })
+    }
+
+    pub(crate) fn partition_point(&self, mut predicate: impl FnMut(Key<'_>) -> bool) -> usize {
+        self.suffixes.partition_point(|suffix| {
+            predicate(Key {
+                prefix: &self.prefix,
+                suffix,
+            })
+        })
+    }
+
+    pub(crate) fn binary_search(&self, key: &str, order: &KeyOrder) -> Result<usize, usize> {
+        self.suffixes.binary_search_by(|suffix| {
+            let stored = Key {
+                prefix: &self.prefix,
+                suffix,
+            };
+            order.cmp_key(stored, key)
+        })
+    }
+
+    // shortens the prefix so that it is shared by `key` as well
+    fn make_room(&mut self, key: &str) {
+        if self.suffixes.is_empty() {
+            self.prefix = key.to_string();
+            return;
+        }
+        let length = common_prefix(&self.prefix, key);
+        if length < self.prefix.len() {
+            let moved = self.prefix.split_off(length);
+            for suffix in self.suffixes.iter_mut() {
+                suffix.insert_str(0, &moved);
+            }
+        }
+    }
+
+    pub(crate) fn insert(&mut self, index: usize, key: String) {
+        self.make_room(&key);
+        let suffix = key[self.prefix.len()..].to_string();
+        self.suffixes.insert(index, suffix);
+    }
+
+    pub(crate) fn push(&mut self, key: String) {
+        let index = self.len();
+        self.insert(index, key);
+    }
+
+    pub(crate) fn set(&mut self, index: usize, key: String) {
+        self.make_room(&key);
+        self.suffixes[index] = key[self.prefix.len()..].to_string();
+    }
+
+    pub(crate) fn remove(&mut self, index: usize) -> String {
+        let suffix = self.suffixes.remove(index);
+        let key = format!("{}{}", self.prefix, suffix);
+        if self.suffixes.is_empty() {
+            self.prefix.clear();
+        }
+        key
+    }
+
+    // bytes of the prefix and the suffixes, used and allocated
+    pub(crate) fn heap_bytes(&self) -> (usize, usize) {
+        let mut used = self.prefix.len() + self.suffixes.len() * size_of::<String>();
+        let mut allocated = self.prefix.capacity() + self.suffixes.capacity() * size_of::<String>();
+        for suffix in self.suffixes.iter() {
+            used += suffix.len();
+            allocated += suffix.capacity();
+        }
+        (used, allocated)
+    }
+
+    pub(crate) fn into_vec(self) -> Vec<String> {
+        let prefix = self.prefix;
+        self.suffixes
+            .into_iter()
+            .map(|suffix| format!("{}{}", prefix, suffix))
+            .collect()
+    }
+
+    pub(crate) fn split_off(&mut self, at: usize) -> NodeKeys {
+        let mut keys = std::mem::take(self).into_vec();
+        let right = keys.split_off(at);
+        *self = NodeKeys::from(keys);
+        NodeKeys::from(right)
+    }
+
+    // the keys of `self` followed by those of `right`
+    pub(crate) fn append(&mut self, right: NodeKeys) {
+        let mut keys = std::mem::take(self).into_vec();
+        keys.extend(right.into_vec());
+        *self = NodeKeys::from(keys);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::BPlusTree;
+
+    #[test]
+    fn separators_fall_between_their_keys() {
+        let pairs = [
+            ("apple", "banana"),
+            ("apple", "apricot"),
+            ("app", "apple"),
+            ("", "a"),
+            ("https://example.com/a/1", "https://example.com/a/2"),
+            ("caf", "café"),
+            ("café", "cafü"),
+            ("日本", "日本語"),
+        ];
+        for (left, right) in pairs {
+            let separator = shortest_separator(left, right);
+            assert!(left < separator.as_str(), "{:?} <= {:?}", separator, left);
+            assert!(separator.as_str() <= right, "{:?} > {:?}", separator, right);
+        }
+        assert_eq!(shortest_separator("apple", "banana"), "b");
+        assert_eq!(shortest_separator("café", "cafü"), "cafü");
+    }
+
+    #[test]
+    fn compressed_keys_read_back_whole() {
+        let urls: Vec<String> = (0..8)
+            .map(|i| format!("https://example.com/items/{}", i))
+            .collect();
+        let mut keys = NodeKeys::from(urls.clone());
+        assert_eq!(
+            keys.iter().map(|key| key.to_string()).collect::<Vec<_>>(),
+            urls
+        );
+
+        // a key sharing less of the prefix shortens it
+        keys.insert(0, "https://example.org".to_string());
+        assert_eq!(keys.key(0).to_string(), "https://example.org");
+        assert_eq!(keys.key(1).to_string(), urls[0]);
+        assert_eq!(keys.remove(0), "https://example.org");
+
+        let right = keys.split_off(4);
+        assert_eq!(right.key(0).to_string(), urls[4]);
+        keys.append(right);
+        assert_eq!(keys.into_vec(), urls);
+    }
+
+    #[test]
+    fn trees_of_long_shared_keys_stay_ordered() {
+        let mut tree = BPlusTree::new();
+        for i in (0..2000).rev() {
+            tree.insert(
+                format!("https://example.com/{:x}/page", i * 7),
+                i.to_string(),
+            );
+        }
+        for i in (0..2000).step_by(3) {
+            tree.delete(format!("https://example.com/{:x}/page", i * 7));
+        }
+        tree.validate().unwrap();
+        for i in 0..2000 {
+            let expected = (i % 3 != 0).then(|| i.to_string());
+            assert_eq!(
+                tree.get(&format!("https://example.com/{:x}/page", i * 7)),
+                expected
+            );
+        }
+        let keys: Vec<String> = tree.scan(..).into_iter().map(|(key, _)| key).collect();
+        assert!(keys.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+}
@@ -0,0 +1,147 @@
+// Lazy mode, loosely following WiredTiger: instead of shifting a leaf's vectors on
+// every write, each leaf absorbs writes into a small sorted buffer of pending updates.
+// Lookups and scans read the buffer on top of the leaf. Once a buffer fills up it is
+// reconciled: the leaf is rebuilt in one merge with its buffer and split as many times
+// as the new entries require, so a hot leaf is rewritten once per buffer instead of
+// once per write.
+
+use crate::{BPlusTree, Message};
+
+impl BPlusTree {
+    pub fn with_update_buffer(capacity: usize) -> Self {
+        assert!(
+            capacity > 0,
+            "update buffers need room for at least one write"
+        );
+        let mut tree = BPlusTree::new();
+        tree.buffer_capacity = Some(capacity);
+        tree
+    }
+
+    // switches lazy mode on with the given capacity or off with None; pending writes
+    // are reconciled first
+    pub fn set_update_buffer(&mut self, capacity: Option<usize>) {
+        assert!(
+            capacity != Some(0),
+            "update buffers need room for at least one write"
+        );
+        self.flush();
+        self.buffer_capacity = capacity;
+    }
+
+    pub(crate) fn buffer_write(&mut self, key: String, message: Message) {
+        let leaf_index = self.get_node_for_key(&key);
+        let buffer = &mut self.nodes[leaf_index].buffer;
+        match buffer.binary_search_by(|(pending, _)| self.key_order.cmp(pending, &key)) {
+            Ok(index) => buffer[index] = (key, message),
+            Err(index) => buffer.insert(index, (key, message)),
+        }
+        self.refresh_ancestors(leaf_index);
+
+        if self.nodes[leaf_index].buffer.len() >= self.buffer_capacity.unwrap() {
+            self.reconcile(leaf_index);
+        }
+    }
+
+    fn reconcile(&mut self, leaf_index: usize) {
+        let pending = self.take_leaf_buffer(leaf_index);
+        let Some(first_key) = pending.first().map(|(key, _)| key.clone()) else {
+            return;
+        };
+        let shrinks = pending.iter().any(|(_, value)| value.is_none());
+
+        self.merge_into_leaf(leaf_index, &pending);
+        self.split_until_fits(leaf_index);
+        if shrinks {
+            self.check_merge_at(&first_key);
+        }
+    }
+
+    pub fn pending_writes(&self) -> usize {
+        self.nodes.iter().map(|node| node.buffer.len()).sum()
+    }
+
+    // reconciles every leaf that still holds pending writes
+    pub fn flush(&mut self) {
+        let buffered_keys: Vec<String> = self
+            .nodes
+            .iter()
+            .filter_map(|node| node.buffer.first())
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in buffered_keys {
+            // earlier reconciliations may have moved or merged this leaf
+            let leaf_index = self.get_node_for_key(&key);
+            self.reconcile(leaf_index);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(i: usize) -> String {
+        format!("{:04}", i)
+    }
+
+    #[test]
+    fn buffered_writes_are_visible_before_reconciling() {
+        let mut tree = BPlusTree::with_update_buffer(8);
+        tree.insert(key(1), "1".to_string());
+        tree.insert(key(2), "2".to_string());
+        tree.delete(key(1));
+        assert_eq!(tree.pending_writes(), 2);
+        assert_eq!(tree.get(&key(1)), None);
+        assert_eq!(tree.get(&key(2)), Some("2".to_string()));
+        assert_eq!(tree.scan(..), vec![(key(2), "2".to_string())]);
+        assert_eq!(tree.len(), 1);
+    }
+
+    #[test]
+    fn reconciling_matches_eager_writes() {
+        let mut lazy = BPlusTree::with_update_buffer(3);
+        let mut eager = BPlusTree::new();
+        for i in 0..300 {
+            let k = key(i * 37 % 300);
+            lazy.insert(k.clone(), i.to_string());
+            eager.insert(k, i.to_string());
+            if i % 3 == 0 {
+                lazy.delete(key(i));
+                eager.delete(key(i));
+            }
+        }
+        assert_eq!(lazy.scan(..), eager.scan(..));
+        assert!(lazy.pending_writes() > 0);
+
+        lazy.flush();
+        assert_eq!(lazy.pending_writes(), 0);
+        assert_eq!(lazy.scan(..), eager.scan(..));
+        lazy.validate().unwrap();
+
+        // deleting everything reconciles into merges down to a single leaf
+        for i in 0..300 {
+            lazy.delete(key(i));
+        }
+        lazy.flush();
+        assert!(lazy.is_empty());
+        assert_eq!(lazy.stats().height, 1);
+        lazy.validate().unwrap();
+    }
+
+    #[test]
+    fn switching_modes_flushes() {
+        let mut tree = BPlusTree::new();
+        tree.set_update_buffer(Some(100));
+        for i in 0..50 {
+            tree.insert(key(i), String::new());
+        }
+        assert_eq!(tree.pending_writes(), 50);
+        tree.set_update_buffer(None);
+        assert_eq!(tree.pending_writes(), 0);
+        assert_eq!(tree.len(), 50);
+        tree.insert(key(50), String::new());
+        assert_eq!(tree.pending_writes(), 0);
+        tree.validate().unwrap();
+    }
+}
@@ -0,0 +1,980 @@
+// Instead of tree structure, we are going to use a vector of nodes which are fixed size.
+// Each holds an optional parent index
+// Each holds a vector of n keys
+// Each holds a vector of either n+1 child indicies or n values
+
+pub mod aggregate;
+pub mod batch;
+pub mod betree;
+pub mod bwtree;
+pub mod codec;
+pub mod compare;
+pub mod conditional;
+pub mod export;
+pub mod fdtree;
+mod keys;
+pub mod lazy;
+pub mod lock;
+pub mod multimap;
+pub mod observe;
+pub mod order;
+pub mod overflow;
+pub mod partition;
+pub mod remove;
+mod resp;
+pub mod server;
+pub mod snapshot;
+pub mod ssi;
+pub mod stats;
+pub mod ttl;
+pub mod validate;
+pub mod veb;
+pub mod wisckey;
+
+use std::cmp::Ordering;
+use std::ops::{Bound, RangeBounds};
+
+use aggregate::{Aggregates, Summary};
+use compare::{Comparator, KeyOrder};
+use keys::{Key, NodeKeys};
+use observe::{Event, ObserverSlot};
+use overflow::{LeafValue, OverflowStore, ValueRef};
+use stats::Counters;
+use ttl::Expiry;
+
+// the number of keys a node holds before it splits, unless a tree is given another
+pub const FANOUT: usize = 5;
+// the smallest fanout that still leaves both halves of a split internal node a key
+pub const MIN_FANOUT: usize = 3;
+const SPLIT_AFTER: usize = FANOUT;
+const MERGE: usize = FANOUT / 2;
+
+fn borrow_mut_nodes(
+    v: &mut [ArrayNode],
+    indicies: (usize, usize, usize),
+) -> (&mut ArrayNode, &mut ArrayNode, &mut ArrayNode) {
+    let mut result: (
+        Option<&mut ArrayNode>,
+        Option<&mut ArrayNode>,
+        Option<&mut ArrayNode>,
+    ) = (None, None, None);
+
+    let mut current: &mut [ArrayNode];
+    let mut rest = v;
+    let mut index = 0;
+    while !rest.is_empty() {
+        (current, rest) = rest.split_at_mut(1);
+        if index == indicies.0 {
+            result.0 = Some(&mut current[0]);
+        } else if index == indicies.1 {
+            result.1 = Some(&mut current[0]);
+        } else if index == indicies.2 {
+            result.2 = Some(&mut current[0]);
+        }
+        index += 1;
+    }
+    (result.0.unwrap(), result.1.unwrap(), result.2.unwrap())
+}
+
+fn after_start(key: &str, start: Bound<&str>) -> bool {
+    match start {
+        Bound::Included(start) => key >= start,
+        Bound::Excluded(start) => key > start,
+        Bound::Unbounded => true,
+    }
+}
+
+fn before_end(key: &str, end: Bound<&str>) -> bool {
+    match end {
+        Bound::Included(end) => key <= end,
+        Bound::Excluded(end) => key < end,
+        Bound::Unbounded => true,
+    }
+}
+
+fn str_bounds<R: RangeBounds<String>>(range: &R) -> (Bound<&str>, Bound<&str>) {
+    (
+        range.start_bound().map(|key| key.as_str()),
+        range.end_bound().map(|key| key.as_str()),
+    )
+}
+
+#[derive(Debug)]
+enum NodeValue {
+    Internal(Vec<usize>),
+    Leaf(Vec<LeafValue>),
+}
+
+// a write waiting in a node buffer until it is applied to a leaf
+#[derive(Debug, Clone, PartialEq)]
+enum Message {
+    Put(String),
+    Delete,
+    // combined with the current value by the B-epsilon tree's upsert function
+    Upsert(String),
+}
+
+impl Message {
+    // lazy B+ tree leaves only ever buffer puts and deletes
+    fn leaf_value(&self) -> Option<&String> {
+        match self {
+            Message::Put(value) => Some(value),
+            Message::Delete => None,
+            Message::Upsert(_) => unreachable!("B+ tree leaves do not buffer upserts"),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct ArrayNode {
+    parent: Option<usize>,
+    keys: NodeKeys,
+    values: NodeValue,
+    // pending writes sorted by key, absorbed by leaves in lazy mode and by internal
+    // nodes of the B-epsilon tree
+    buffer: Vec<(String, Message)>,
+    // entries below each child of an internal node
+    counts: Vec<usize>,
+    // the summary of every registered aggregate below each child
+    summaries: Vec<Vec<Summary>>,
+}
+
+impl ArrayNode {
+    // an empty leaf with room for the keys of a tree with the given fanout
+    fn new(fanout: usize) -> Self {
+        ArrayNode {
+            parent: None,
+            keys: NodeKeys::with_capacity(fanout),
+            values: NodeValue::Leaf(Vec::with_capacity(fanout)),
+            buffer: Vec::new(),
+            counts: Vec::new(),
+            summaries: Vec::new(),
+        }
+    }
+
+    // whether the child at `position` can hold keys inside the range, judging by the
+    // separators on either side of it
+    fn child_overlaps(
+        &self,
+        position: usize,
+        start: Bound<&str>,
+        end: Bound<&str>,
+        order: &KeyOrder,
+    ) -> bool {
+        let below_start = match (self.keys.get(position), start) {
+            (Some(upper), Bound::Included(start) | Bound::Excluded(start)) => {
+                order.cmp_key(upper, start) != Ordering::Greater
+            }
+            _ => false,
+        };
+        let above_end = match position.checked_sub(1) {
+            Some(lower) => !order.before_end(self.keys.key(lower), end),
+            None => false,
+        };
+        !below_start && !above_end
+    }
+
+    // the position of the child responsible for `key`
+    fn child_position(&self, key: &str, order: &KeyOrder) -> usize {
+        self.keys
+            .partition_point(|separator| order.cmp_key(separator, key) != Ordering::Greater)
+    }
+
+    // leaf entries with the pending writes of the buffer applied on top
+    fn leaf_entries(&self, order: &KeyOrder) -> Vec<(Key<'_>, ValueRef<'_>)> {
+        let NodeValue::Leaf(ref values) = self.values else {
+            panic!("Entries requested from internal node");
+        };
+        let mut entries: Vec<(Key, ValueRef)> = self
+            .keys
+            .iter()
+            .zip(values.iter().map(LeafValue::as_ref))
+            .collect();
+        for (key, message) in self.buffer.iter() {
+            match (
+                entries.binary_search_by(|(k, _)| order.cmp_key(*k, key)),
+                message.leaf_value().map(|value| ValueRef::Inline(value)),
+            ) {
+                (Ok(index), Some(value)) => entries[index].1 = value,
+                (Ok(index), None) => {
+                    entries.remove(index);
+                }
+                (Err(index), Some(value)) => entries.insert(index, (Key::full(key), value)),
+                (Err(_), None) => (),
+            }
+        }
+        entries
+    }
+
+    fn display(&self, indent: &str) {
+        let parent_string = match self.parent {
+            Some(index) => index.to_string(),
+            None => "Root".to_string(),
+        };
+
+        let type_string = match self.values {
+            NodeValue::Internal(_) => format!("{}Internal(parent: {})", indent, parent_string),
+            NodeValue::Leaf(_) => format!("{}Leaf(parent: {})", indent, parent_string),
+        };
+        println!("{}Node: {}", indent, type_string);
+        println!("{}Keys: {:?}", indent, self.keys);
+        match self.values {
+            NodeValue::Internal(ref children) => {
+                println!("{}Children: {:?}", indent, children);
+            }
+            NodeValue::Leaf(ref values) => println!("{}Values: {:?}", indent, values),
+        }
+        if !self.buffer.is_empty() {
+            println!("{}Pending: {:?}", indent, self.buffer);
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct BPlusTree {
+    root_index: usize,
+    nodes: Vec<ArrayNode>,
+    // when set, leaves buffer up to this many writes before reconciling them
+    buffer_capacity: Option<usize>,
+    aggregates: Aggregates,
+    overflow: OverflowStore,
+    key_order: KeyOrder,
+    observer: ObserverSlot,
+    counters: Counters,
+    expiry: Option<Box<Expiry>>,
+    fanout: usize,
+}
+
+impl Default for BPlusTree {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BPlusTree {
+    pub fn new() -> Self {
+        BPlusTree {
+            root_index: 0,
+            nodes: vec![ArrayNode::new(FANOUT)],
+            buffer_capacity: None,
+            aggregates: Aggregates::default(),
+            overflow: OverflowStore::default(),
+            key_order: KeyOrder::default(),
+            observer: ObserverSlot::default(),
+            counters: Counters::default(),
+            expiry: None,
+            fanout: FANOUT,
+        }
+    }
+
+    pub fn with_comparator<C: Comparator>(comparator: C) -> Self {
+        let mut tree = BPlusTree::new();
+        tree.key_order = KeyOrder::new(comparator);
+        tree
+    }
+
+    pub fn with_fanout(fanout: usize) -> Self {
+        let mut tree = BPlusTree::new();
+        tree.set_fanout(fanout);
+        tree
+    }
+
+    pub fn fanout(&self) -> usize {
+        self.fanout
+    }
+
+    // nodes split once they hold more than `fanout` keys from now on; a tree with
+    // entries is rebuilt with nodes of the new size
+    pub fn set_fanout(&mut self, fanout: usize) {
+        assert!(
+            fanout >= MIN_FANOUT,
+            "fanouts below {} cannot split internal nodes",
+            MIN_FANOUT
+        );
+        let mut rebuilt = self.empty_like();
+        rebuilt.fanout = fanout;
+        rebuilt.nodes = vec![ArrayNode::new(fanout)];
+        rebuilt.observer = self.observer.clone();
+        rebuilt.counters = self.counters;
+        let mut old = std::mem::replace(self, rebuilt);
+        self.append(&mut old);
+    }
+
+    // the name of the comparator ordering the keys
+    pub fn comparator_name(&self) -> &str {
+        self.key_order.name()
+    }
+
+    pub fn display(&self) {
+        let mut stack = vec![self.root_index];
+        let mut indent = "".to_string();
+
+        println!();
+        println!("---------------------------------------------");
+        println!("Displaying BTREE");
+        println!("---------------------------------------------");
+
+        loop {
+            let mut next_stack = Vec::new();
+            for node_index in stack.iter() {
+                println!();
+                self.nodes[*node_index].display(&indent);
+                if let NodeValue::Internal(ref pointers) = self.nodes[*node_index].values {
+                    for pointer in pointers.iter() {
+                        next_stack.push(*pointer);
+                    }
+                }
+            }
+
+            if next_stack.is_empty() {
+                break;
+            }
+
+            stack = next_stack;
+            indent += "  ";
+        }
+        println!();
+    }
+
+    fn check_split(&mut self, index: usize) {
+        if self.nodes[index].keys.len() <= self.fanout {
+            return;
+        }
+        self.split(index);
+    }
+
+    // splits a node that may have grown past several nodes worth of keys at once
+    fn split_until_fits(&mut self, index: usize) {
+        let mut split_index = index;
+        while self.nodes[split_index].keys.len() > self.fanout {
+            split_index = self.split(split_index);
+        }
+    }
+
+    // rebuilds a leaf from its own entries and sorted updates in a single pass instead
+    // of shifting the vectors once per update
+    fn merge_into_leaf(&mut self, leaf_index: usize, updates: &[(String, Option<String>)]) {
+        if updates.is_empty() {
+            return;
+        }
+        let leaf = &mut self.nodes[leaf_index];
+        let NodeValue::Leaf(ref mut values) = leaf.values else {
+            panic!("Search yielded internal node");
+        };
+        let old_keys = std::mem::take(&mut leaf.keys).into_vec();
+        let old_values = std::mem::take(values);
+        let mut keys = Vec::with_capacity(old_keys.len() + updates.len());
+        values.reserve(old_keys.len() + updates.len());
+
+        // the events describe the merge as single writes applied in key order, so
+        // every position is where the entry is when that write happens
+        let observed = self.observer.is_attached();
+        let mut events = Vec::new();
+        let mut old = old_keys.into_iter().zip(old_values).peekable();
+        let mut new = updates.iter().peekable();
+        loop {
+            let (take_old, replaced) = match (old.peek(), new.peek()) {
+                (Some((old_key, _)), Some((new_key, _))) => {
+                    match self.key_order.cmp(old_key, new_key) {
+                        Ordering::Equal => {
+                            let (replaced_key, replaced) = old.next().unwrap();
+                            self.overflow.release(replaced);
+                            (false, Some(replaced_key))
+                        }
+                        order => (order == Ordering::Less, None),
+                    }
+                }
+                (Some(_), None) => (true, None),
+                (None, Some(_)) => (false, None),
+                (None, None) => break,
+            };
+            let (key, value) = if take_old {
+                old.next().unwrap()
+            } else {
+                match new.next().unwrap() {
+                    (key, Some(value)) => {
+                        if observed {
+                            events.push(Event::LeafInsert {
+                                leaf: leaf_index,
+                                position: keys.len(),
+                                key: key.clone(),
+                                replaced: replaced.is_some(),
+                            });
+                        }
+                        (key.clone(), self.overflow.store(value.clone()))
+                    }
+                    (_, None) => {
+                        if let (true, Some(key)) = (observed, replaced) {
+                            events.push(Event::LeafDelete {
+                                leaf: leaf_index,
+                                position: keys.len(),
+                                key,
+                            });
+                        }
+                        continue;
+                    }
+                }
+            };
+            keys.push(key);
+            values.push(value);
+        }
+        leaf.keys = NodeKeys::from(keys);
+        for event in events {
+            self.emit(|| event);
+        }
+    }
+
+    // drops the entries of a leaf that `keep` turns down and releases their values;
+    // returns how many were dropped
+    fn retain_in_leaf<F>(&mut self, leaf_index: usize, mut keep: F) -> usize
+    where
+        F: FnMut(&str, &LeafValue, &OverflowStore) -> bool,
+    {
+        let observed = self.observer.is_attached();
+        let leaf = &mut self.nodes[leaf_index];
+        let NodeValue::Leaf(ref mut values) = leaf.values else {
+            panic!("Search yielded internal node");
+        };
+        let entries = std::mem::take(&mut leaf.keys)
+            .into_vec()
+            .into_iter()
+            .zip(std::mem::take(values));
+        let mut keys = Vec::new();
+        let mut dropped = Vec::new();
+        let mut events = Vec::new();
+        for (key, value) in entries {
+            if keep(&key, &value, &self.overflow) {
+                keys.push(key);
+                values.push(value);
+                continue;
+            }
+            if observed {
+                events.push(Event::LeafDelete {
+                    leaf: leaf_index,
+                    position: keys.len(),
+                    key,
+                });
+            }
+            dropped.push(value);
+        }
+        leaf.keys = NodeKeys::from(keys);
+        let count = dropped.len();
+        for value in dropped {
+            self.overflow.release(value);
+        }
+        for event in events {
+            self.emit(|| event);
+        }
+        count
+    }
+
+    // empties a lazy leaf's buffer into the updates merge_into_leaf expects
+    fn take_leaf_buffer(&mut self, leaf_index: usize) -> Vec<(String, Option<String>)> {
+        std::mem::take(&mut self.nodes[leaf_index].buffer)
+            .into_iter()
+            .map(|(key, message)| (key, message.leaf_value().cloned()))
+            .collect()
+    }
+
+    // returns the index of the newly created right sibling
+    fn split(&mut self, node_index: usize) -> usize {
+        self.counters.splits += 1;
+        let nodes_length = self.nodes.len();
+        let mut_nodes_ref = &mut self.nodes;
+        let parent = mut_nodes_ref[node_index].parent;
+        let next_parent_index = match parent {
+            Some(index) => index,
+            None => {
+                // create new root node
+                mut_nodes_ref.len() + 1
+            }
+        };
+
+        // determine promotion index
+        let promotion_index = match mut_nodes_ref[node_index].values {
+            NodeValue::Internal(_) => self.fanout / 2,
+            NodeValue::Leaf(_) => self.fanout.div_ceil(2),
+        };
+        let keys = &mut_nodes_ref[node_index].keys;
+        let promotion_key = match mut_nodes_ref[node_index].values {
+            NodeValue::Internal(_) => keys.key(promotion_index).to_string(),
+            // any key between the two halves separates leaves, so take the shortest
+            NodeValue::Leaf(_) => self.key_order.separator(
+                &keys.key(promotion_index - 1).to_cow(),
+                &keys.key(promotion_index).to_cow(),
+            ),
+        };
+
+        let mut right_keys = mut_nodes_ref[node_index].keys.split_off(promotion_index);
+        if let NodeValue::Internal(_) = mut_nodes_ref[node_index].values {
+            right_keys.remove(0);
+        }
+
+        // buffered messages follow their keys into the sibling
+        let buffer = &mut mut_nodes_ref[node_index].buffer;
+        let sibling_buffer =
+            buffer.split_off(buffer.partition_point(|(key, _)| {
+                self.key_order.cmp(key, &promotion_key) == Ordering::Less
+            }));
+
+        // create sibling node
+        let sibling_node = ArrayNode {
+            parent: Some(next_parent_index),
+            keys: right_keys,
+            values: match mut_nodes_ref[node_index].values {
+                NodeValue::Internal(ref mut pointers) => {
+                    let mut sibling_pointers = Vec::with_capacity(self.fanout + 1);
+                    sibling_pointers.extend(pointers.split_off(promotion_index + 1));
+
+                    NodeValue::Internal(sibling_pointers)
+                }
+                NodeValue::Leaf(ref mut values) => {
+                    let mut sibling_values = Vec::with_capacity(self.fanout);
+                    sibling_values.extend(values.split_off(promotion_index));
+
+                    NodeValue::Leaf(sibling_values)
+                }
+            },
+            buffer: sibling_buffer,
+            counts: match mut_nodes_ref[node_index].values {
+                NodeValue::Internal(_) => mut_nodes_ref[node_index]
+                    .counts
+                    .split_off(promotion_index + 1),
+                NodeValue::Leaf(_) => Vec::new(),
+            },
+            summaries: match mut_nodes_ref[node_index].values {
+                NodeValue::Internal(_) => mut_nodes_ref[node_index]
+                    .summaries
+                    .split_off(promotion_index + 1),
+                NodeValue::Leaf(_) => Vec::new(),
+            },
+        };
+        mut_nodes_ref.push(sibling_node);
+        let node_count = self.subtree_count(node_index);
+        let sibling_count = self.subtree_count(nodes_length);
+        let node_summaries = self.subtree_summaries(node_index);
+        let sibling_summaries = self.subtree_summaries(nodes_length);
+        let mut_nodes_ref = &mut self.nodes;
+
+        // children moved to the sibling need their parent updated
+        if let NodeValue::Internal(ref pointers) = mut_nodes_ref[nodes_length].values {
+            for pointer in pointers.clone() {
+                mut_nodes_ref[pointer].parent = Some(nodes_length);
+            }
+        }
+
+        // update parent of original node
+        mut_nodes_ref[node_index].parent = Some(next_parent_index);
+        self.emit(|| Event::Split {
+            node: node_index,
+            sibling: nodes_length,
+            promoted: promotion_key.clone(),
+        });
+        let mut_nodes_ref = &mut self.nodes;
+
+        //update parent node
+        match parent {
+            Some(parent_index) => {
+                let parent_node = &mut mut_nodes_ref[parent_index];
+                match parent_node.values {
+                    NodeValue::Internal(ref mut pointers) => {
+                        let key_position = pointers.iter().position(|p| *p == node_index).unwrap();
+                        parent_node.keys.insert(key_position, promotion_key);
+                        pointers.insert(key_position + 1, nodes_length);
+                        parent_node.counts[key_position] = node_count;
+                        parent_node.counts.insert(key_position + 1, sibling_count);
+                        parent_node.summaries[key_position] = node_summaries;
+                        parent_node
+                            .summaries
+                            .insert(key_position + 1, sibling_summaries);
+                    }
+                    NodeValue::Leaf(_) => panic!("Leaf node is parent"),
+                }
+                self.check_split(parent_index)
+            }
+            None => {
+                // create new root node
+                let mut new_root = ArrayNode {
+                    parent: None,
+                    keys: NodeKeys::with_capacity(self.fanout),
+                    values: NodeValue::Internal(Vec::with_capacity(self.fanout + 1)),
+                    buffer: Vec::new(),
+                    counts: vec![node_count, sibling_count],
+                    summaries: vec![node_summaries, sibling_summaries],
+                };
+                new_root.keys.push(promotion_key);
+
+                match new_root.values {
+                    NodeValue::Internal(ref mut pointers) => {
+                        pointers.push(node_index);
+                        pointers.push(nodes_length);
+                    }
+                    NodeValue::Leaf(_) => panic!("New root is a leaf"),
+                }
+
+                self.nodes.push(new_root);
+                self.root_index = self.nodes.len() - 1;
+                self.emit(|| Event::RootGrow {
+                    root: self.root_index,
+                });
+            }
+        };
+        nodes_length
+    }
+
+    fn remove_node(&mut self, index: usize) {
+        // uses swap_remove so that we do not need to reorder all elements
+        if self.root_index == index {
+            panic!("Removed root node");
+        }
+
+        let swap_origin = self.nodes.len() - 1;
+
+        self.nodes.swap_remove(index);
+        self.counters.removed_nodes += 1;
+        self.emit(|| Event::RemoveNode {
+            node: index,
+            moved: (swap_origin != index).then_some(swap_origin),
+        });
+
+        if self.root_index == swap_origin {
+            self.root_index = index;
+        }
+
+        // update parent indicies
+        for node in self.nodes.iter_mut() {
+            if let NodeValue::Internal(ref mut pointers) = node.values {
+                for pointer in pointers.iter_mut() {
+                    if *pointer == index {
+                        panic!("Removed referenced node");
+                    }
+
+                    if *pointer == swap_origin {
+                        *pointer = index;
+                    }
+                }
+            }
+            if let Some(parent_index) = node.parent {
+                if parent_index == index {
+                    panic!("Removed referenced node");
+                }
+                if parent_index == swap_origin {
+                    node.parent = Some(index);
+                }
+            }
+        }
+    }
+
+    fn check_merge(&mut self, index: usize) {
+        let check_node = &self.nodes[index];
+
+        match check_node.values {
+            NodeValue::Internal(ref pointers) => {
+                let mut first_pos = 0;
+                let mut second_pos = 1;
+                while second_pos < pointers.len() {
+                    if self.nodes[pointers[first_pos]].keys.len()
+                        + self.nodes[pointers[second_pos]].keys.len()
+                        <= self.fanout / 2
+                    {
+                        self.merge(pointers[first_pos], pointers[second_pos]);
+                        return;
+                    } else {
+                        first_pos += 1;
+                        second_pos += 1;
+                    }
+                }
+            }
+            NodeValue::Leaf(_) => {
+                println!("Check merge called on leaf node --- noop")
+            }
+        }
+    }
+
+    // runs check_merge on the parent of the leaf owning `key` until nothing merges
+    fn check_merge_at(&mut self, key: &str) {
+        loop {
+            let leaf = self.get_node_for_key(key);
+            let Some(parent) = self.nodes[leaf].parent else {
+                return;
+            };
+            let nodes_before = self.nodes.len();
+            self.check_merge(parent);
+            if self.nodes.len() == nodes_before {
+                return;
+            }
+        }
+    }
+
+    fn merge(&mut self, left_node_index: usize, right_node_index: usize) {
+        self.counters.merges += 1;
+        let mut parent_index = self.nodes[left_node_index].parent.unwrap();
+        self.emit(|| {
+            let parent = &self.nodes[parent_index];
+            let NodeValue::Internal(ref pointers) = parent.values else {
+                panic!("Leaf node is parent");
+            };
+            let position = pointers.iter().position(|p| *p == left_node_index).unwrap();
+            Event::Merge {
+                left: left_node_index,
+                right: right_node_index,
+                separator: parent.keys.key(position).to_string(),
+            }
+        });
+
+        let (left_node, right_node, parent_node) = borrow_mut_nodes(
+            &mut self.nodes,
+            (left_node_index, right_node_index, parent_index),
+        );
+
+        let key_position = match parent_node.values {
+            NodeValue::Internal(ref pointers) => pointers
+                .iter()
+                .position(|pointer| *pointer == left_node_index)
+                .unwrap(),
+            NodeValue::Leaf(_) => panic!("Leaf node is parent"),
+        };
+
+        let parent_separator = parent_node.keys.remove(key_position);
+        if let NodeValue::Internal(ref mut pointers) = parent_node.values {
+            pointers.remove(key_position);
+        }
+        let left_count = parent_node.counts.remove(key_position);
+        parent_node.counts[key_position] += left_count;
+        parent_node.summaries.remove(key_position);
+
+        // move keys and values from left node to the front of right node
+        let mut keys = std::mem::take(&mut left_node.keys);
+        let mut moved_children = Vec::new();
+        match right_node.values {
+            NodeValue::Internal(ref mut pointers) => match left_node.values {
+                NodeValue::Internal(ref mut left_pointers) => {
+                    // internal nodes pull the separator down between the two halves
+                    keys.push(parent_separator);
+                    moved_children.extend(left_pointers.iter().copied());
+                    left_pointers.append(pointers);
+                    std::mem::swap(pointers, left_pointers);
+                }
+                NodeValue::Leaf(_) => panic!("Sibling nodes have different types"),
+            },
+            NodeValue::Leaf(ref mut values) => match left_node.values {
+                NodeValue::Leaf(ref mut left_values) => {
+                    left_values.append(values);
+                    std::mem::swap(values, left_values);
+                }
+                NodeValue::Internal(_) => panic!("Sibling nodes have different types"),
+            },
+        }
+        keys.append(std::mem::take(&mut right_node.keys));
+        right_node.keys = keys;
+        left_node.buffer.append(&mut right_node.buffer);
+        std::mem::swap(&mut left_node.buffer, &mut right_node.buffer);
+        left_node.counts.append(&mut right_node.counts);
+        std::mem::swap(&mut left_node.counts, &mut right_node.counts);
+        left_node.summaries.append(&mut right_node.summaries);
+        std::mem::swap(&mut left_node.summaries, &mut right_node.summaries);
+
+        for child in moved_children {
+            self.nodes[child].parent = Some(right_node_index);
+        }
+        // the right node now holds the left one's entries too
+        self.nodes[parent_index].summaries[key_position] = self.subtree_summaries(right_node_index);
+
+        let swap_origin = self.nodes.len() - 1;
+        self.remove_node(left_node_index);
+        if parent_index == swap_origin {
+            parent_index = left_node_index;
+        }
+
+        // propagate merge upwards
+        if self.nodes[parent_index].keys.is_empty() && parent_index == self.root_index {
+            // the root lost its last separator, so its only child becomes the new root
+            let only_child = match self.nodes[parent_index].values {
+                NodeValue::Internal(ref pointers) => pointers[0],
+                NodeValue::Leaf(_) => panic!("Leaf node is parent"),
+            };
+            self.nodes[only_child].parent = None;
+            self.root_index = only_child;
+            self.emit(|| Event::RootShrink { root: only_child });
+            self.remove_node(parent_index);
+            return;
+        }
+
+        if let Some(index) = self.nodes[parent_index].parent {
+            self.check_merge(index)
+        }
+    }
+
+    fn get_node_for_key(&self, key: &str) -> usize {
+        let mut target_node = &self.nodes[self.root_index];
+        let mut target_node_index = self.root_index;
+
+        loop {
+            match target_node.values {
+                NodeValue::Internal(ref children) => {
+                    // Find the index of the child to descend into
+                    // separators are the first key of their right subtree
+                    let mut index = 0;
+                    for child in target_node.keys.iter() {
+                        if self.key_order.cmp_key(child, key) == Ordering::Greater {
+                            break;
+                        }
+                        index += 1;
+                    }
+                    let next_index = children[index];
+                    self.emit(|| Event::Descend {
+                        node: target_node_index,
+                        position: index,
+                        child: next_index,
+                    });
+
+                    target_node = &self.nodes[next_index];
+                    target_node_index = next_index;
+                }
+                NodeValue::Leaf(_) => {
+                    return target_node_index;
+                }
+            }
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<String> {
+        if self.is_expired(key) {
+            return None;
+        }
+        let target_node = &self.nodes[self.get_node_for_key(key)];
+
+        // buffered writes are newer than anything in the leaf itself
+        if let Ok(index) = target_node
+            .buffer
+            .binary_search_by(|(pending, _)| self.key_order.cmp(pending, key))
+        {
+            return target_node.buffer[index].1.leaf_value().cloned();
+        }
+
+        match target_node.values {
+            NodeValue::Internal(_) => panic!("Search yielded internal node"),
+            NodeValue::Leaf(ref children) => target_node
+                .keys
+                .binary_search(key, &self.key_order)
+                .ok()
+                .map(|index| self.overflow.read(children[index].as_ref())),
+        }
+    }
+
+    // in-order walk that skips subtrees lying entirely outside of the range
+    pub fn scan<R: RangeBounds<String>>(&self, range: R) -> Vec<(String, String)> {
+        let (start, end) = str_bounds(&range);
+        let mut result = Vec::new();
+        let mut stack = vec![self.root_index];
+
+        while let Some(node_index) = stack.pop() {
+            let node = &self.nodes[node_index];
+            match node.values {
+                NodeValue::Internal(ref pointers) => {
+                    // push in reverse so the leftmost child is visited first
+                    for (position, pointer) in pointers.iter().enumerate().rev() {
+                        if node.child_overlaps(position, start, end, &self.key_order) {
+                            stack.push(*pointer);
+                        }
+                    }
+                }
+                NodeValue::Leaf(_) => {
+                    for (key, value) in node.leaf_entries(&self.key_order) {
+                        let order = &self.key_order;
+                        if order.after_start(key, start) && order.before_end(key, end) {
+                            result.push((key.to_string(), self.overflow.read(value)));
+                        }
+                    }
+                }
+            }
+        }
+        self.hide_expired(&mut result);
+        result
+    }
+
+    pub fn insert(&mut self, key: String, value: String) {
+        self.forget_deadline(&key);
+        if self.buffer_capacity.is_some() {
+            self.buffer_write(key, Message::Put(value));
+            return;
+        }
+
+        let value = self.overflow.store(value);
+        self.insert_value(key, value);
+    }
+
+    // writes a stored value straight into its leaf, past any update buffer
+    fn insert_value(&mut self, key: String, value: LeafValue) {
+        let target_node_index = self.get_node_for_key(&key);
+        let target_node = &mut self.nodes[target_node_index];
+        let order = &self.key_order;
+        target_node
+            .buffer
+            .retain(|(pending, _)| order.cmp(pending, &key) != Ordering::Equal);
+
+        match target_node.values {
+            NodeValue::Internal(_) => panic!("Search yielded internal node"),
+            NodeValue::Leaf(ref mut children) => {
+                // Insert into the leaf node
+                let (index, found) = match target_node.keys.binary_search(&key, order) {
+                    Ok(index) => (index, true),
+                    Err(index) => (index, false),
+                };
+
+                let event_key = self.observer.is_attached().then(|| key.clone());
+                if found {
+                    target_node.keys.set(index, key);
+                    let replaced = std::mem::replace(&mut children[index], value);
+                    self.overflow.release(replaced);
+                } else {
+                    target_node.keys.insert(index, key);
+                    children.insert(index, value);
+                }
+                self.emit(|| Event::LeafInsert {
+                    leaf: target_node_index,
+                    position: index,
+                    key: event_key.unwrap(),
+                    replaced: found,
+                });
+                self.refresh_ancestors(target_node_index);
+                if !found && self.nodes[target_node_index].keys.len() > self.fanout {
+                    self.check_split(target_node_index)
+                }
+            }
+        }
+    }
+
+    pub fn delete(&mut self, key: String) {
+        self.forget_deadline(&key);
+        if self.buffer_capacity.is_some() {
+            self.buffer_write(key, Message::Delete);
+            return;
+        }
+
+        let target_node_index = self.get_node_for_key(&key);
+
+        let nodes = &mut self.nodes;
+        let target_node = &mut nodes[target_node_index];
+
+        match target_node.values {
+            NodeValue::Internal(_) => panic!("Search yielded internal node"),
+            NodeValue::Leaf(ref mut children) => {
+                if let Ok(index) = target_node.keys.binary_search(&key, &self.key_order) {
+                    let removed = target_node.keys.remove(index);
+                    self.overflow.release(children.remove(index));
+                    self.emit(|| Event::LeafDelete {
+                        leaf: target_node_index,
+                        position: index,
+                        key: removed,
+                    });
+                }
+            }
+        }
+        self.refresh_ancestors(target_node_index);
+
+        if let Some(index) = self.nodes[target_node_index].parent {
+            self.check_merge(index)
+        }
+    }
+}
@@ -0,0 +1,601 @@
+// Strict two-phase locking over a shared BPlusTree.
+//
+// Every transaction takes an intention lock on the whole tree and then shared or
+// exclusive locks on the keys and key ranges it touches. Range locks cover every key
+// that could ever fall into the range, so a scan blocks concurrent inserts into it and
+// phantoms cannot appear. Locks are only released on commit or abort.
+//
+// Blocked requests record waits-for edges. When an edge closes a cycle the youngest
+// transaction in the cycle is picked as the deadlock victim and its pending (or next)
+// lock request fails.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ops::{Bound, RangeBounds};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::{after_start, before_end, str_bounds, BPlusTree};
+
+pub type TransactionId = u64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockMode {
+    IntentionShared,
+    IntentionExclusive,
+    Shared,
+    SharedIntentionExclusive,
+    Exclusive,
+}
+
+impl LockMode {
+    fn compatible(self, other: LockMode) -> bool {
+        use LockMode::*;
+        match (self, other) {
+            (Exclusive, _) | (_, Exclusive) => false,
+            (IntentionShared, _) | (_, IntentionShared) => true,
+            (IntentionExclusive, IntentionExclusive) => true,
+            (Shared, Shared) => true,
+            _ => false,
+        }
+    }
+
+    // the weakest mode that grants everything both modes grant
+    fn combine(self, other: LockMode) -> LockMode {
+        use LockMode::*;
+        match (self, other) {
+            (a, b) if a == b => a,
+            (Exclusive, _) | (_, Exclusive) => Exclusive,
+            (IntentionShared, mode) | (mode, IntentionShared) => mode,
+            (SharedIntentionExclusive, _) | (_, SharedIntentionExclusive) => {
+                SharedIntentionExclusive
+            }
+            // only (Shared, IntentionExclusive) is left
+            _ => SharedIntentionExclusive,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Resource {
+    Tree,
+    Key(String),
+    Range(Bound<String>, Bound<String>),
+}
+
+impl Resource {
+    pub fn range<R: RangeBounds<String>>(range: R) -> Self {
+        Resource::Range(range.start_bound().cloned(), range.end_bound().cloned())
+    }
+
+    fn contains(start: &Bound<String>, end: &Bound<String>, key: &str) -> bool {
+        after_start(key, start.as_ref().map(|s| s.as_str()))
+            && before_end(key, end.as_ref().map(|s| s.as_str()))
+    }
+
+    // the tree lock sits one level above keys and ranges, which only ever conflict
+    // with each other
+    fn overlaps(&self, other: &Resource) -> bool {
+        match (self, other) {
+            (Resource::Tree, Resource::Tree) => true,
+            (Resource::Tree, _) | (_, Resource::Tree) => false,
+            (Resource::Key(a), Resource::Key(b)) => a == b,
+            (Resource::Key(key), Resource::Range(start, end))
+            | (Resource::Range(start, end), Resource::Key(key)) => {
+                Resource::contains(start, end, key)
+            }
+            (Resource::Range(a_start, a_end), Resource::Range(b_start, b_end)) => {
+                let a = (a_start.as_ref(), a_end.as_ref());
+                let b = (b_start.as_ref(), b_end.as_ref());
+                !ends_before(a.1, b.0) && !ends_before(b.1, a.0)
+            }
+        }
+    }
+}
+
+// true when no key can be both below `end` and above `start`
+fn ends_before(end: Bound<&String>, start: Bound<&String>) -> bool {
+    match (end, start) {
+        (Bound::Unbounded, _) | (_, Bound::Unbounded) => false,
+        (Bound::Included(end), Bound::Included(start)) => end < start,
+        (Bound::Included(end), Bound::Excluded(start))
+        | (Bound::Excluded(end), Bound::Included(start))
+        | (Bound::Excluded(end), Bound::Excluded(start)) => end <= start,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockError {
+    // chosen as the victim to break a waits-for cycle
+    Deadlock,
+    Timeout,
+    // the transaction already failed and can only be dropped
+    Aborted,
+}
+
+#[derive(Debug)]
+struct Grant {
+    transaction: TransactionId,
+    resource: Resource,
+    mode: LockMode,
+}
+
+#[derive(Debug, Default)]
+struct LockTable {
+    granted: Vec<Grant>,
+    waits_for: HashMap<TransactionId, HashSet<TransactionId>>,
+    victims: HashSet<TransactionId>,
+}
+
+impl LockTable {
+    fn blockers(
+        &self,
+        transaction: TransactionId,
+        resource: &Resource,
+        mode: LockMode,
+    ) -> HashSet<TransactionId> {
+        self.granted
+            .iter()
+            .filter(|grant| grant.transaction != transaction)
+            .filter(|grant| grant.resource.overlaps(resource))
+            .filter(|grant| !grant.mode.compatible(mode))
+            .map(|grant| grant.transaction)
+            .collect()
+    }
+
+    // depth first search for a path from the blockers of `start` back to `start`
+    fn find_cycle(&self, start: TransactionId) -> Option<Vec<TransactionId>> {
+        let mut path = vec![start];
+        let mut visited = HashSet::new();
+        if self.visit(start, start, &mut path, &mut visited) {
+            Some(path)
+        } else {
+            None
+        }
+    }
+
+    fn visit(
+        &self,
+        current: TransactionId,
+        start: TransactionId,
+        path: &mut Vec<TransactionId>,
+        visited: &mut HashSet<TransactionId>,
+    ) -> bool {
+        let Some(edges) = self.waits_for.get(&current) else {
+            return false;
+        };
+        for next in edges.iter() {
+            if *next == start {
+                return true;
+            }
+            if visited.insert(*next) {
+                path.push(*next);
+                if self.visit(*next, start, path, visited) {
+                    return true;
+                }
+                path.pop();
+            }
+        }
+        false
+    }
+}
+
+#[derive(Debug)]
+pub struct LockManager {
+    table: Mutex<LockTable>,
+    released: Condvar,
+    timeout: Duration,
+}
+
+impl LockManager {
+    pub fn new(timeout: Duration) -> Self {
+        LockManager {
+            table: Mutex::new(LockTable::default()),
+            released: Condvar::new(),
+            timeout,
+        }
+    }
+
+    pub fn lock(
+        &self,
+        transaction: TransactionId,
+        resource: Resource,
+        mode: LockMode,
+    ) -> Result<(), LockError> {
+        let deadline = Instant::now() + self.timeout;
+        let mut table = self.table.lock().unwrap();
+
+        // upgrades ask for the combination of the held and the requested mode
+        let held = table
+            .granted
+            .iter()
+            .position(|grant| grant.transaction == transaction && grant.resource == resource);
+        let mode = match held {
+            Some(index) => table.granted[index].mode.combine(mode),
+            None => mode,
+        };
+
+        loop {
+            if table.victims.remove(&transaction) {
+                table.waits_for.remove(&transaction);
+                return Err(LockError::Deadlock);
+            }
+
+            let blockers = table.blockers(transaction, &resource, mode);
+            if blockers.is_empty() {
+                table.waits_for.remove(&transaction);
+                // other grants may have been released while waiting, so look again
+                let held = table.granted.iter().position(|grant| {
+                    grant.transaction == transaction && grant.resource == resource
+                });
+                match held {
+                    Some(index) => table.granted[index].mode = mode,
+                    None => table.granted.push(Grant {
+                        transaction,
+                        resource,
+                        mode,
+                    }),
+                }
+                return Ok(());
+            }
+            table.waits_for.insert(transaction, blockers);
+
+            if let Some(cycle) = table.find_cycle(transaction) {
+                let victim = *cycle.iter().max().unwrap();
+                if victim == transaction {
+                    table.waits_for.remove(&transaction);
+                    return Err(LockError::Deadlock);
+                }
+                table.victims.insert(victim);
+                self.released.notify_all();
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                table.waits_for.remove(&transaction);
+                return Err(LockError::Timeout);
+            }
+            table = self.released.wait_timeout(table, deadline - now).unwrap().0;
+        }
+    }
+
+    pub fn release_all(&self, transaction: TransactionId) {
+        let mut table = self.table.lock().unwrap();
+        table
+            .granted
+            .retain(|grant| grant.transaction != transaction);
+        table.waits_for.remove(&transaction);
+        table.victims.remove(&transaction);
+        self.released.notify_all();
+    }
+}
+
+#[derive(Debug)]
+pub struct LockingTree {
+    tree: Mutex<BPlusTree>,
+    locks: LockManager,
+    next_transaction: AtomicU64,
+}
+
+impl LockingTree {
+    pub fn new(tree: BPlusTree, lock_timeout: Duration) -> Self {
+        // range locks and pending writes are kept in byte order
+        assert!(
+            tree.key_order.is_bytewise(),
+            "locking trees need bytewise keys, not {:?}",
+            tree.comparator_name()
+        );
+        LockingTree {
+            tree: Mutex::new(tree),
+            locks: LockManager::new(lock_timeout),
+            next_transaction: AtomicU64::new(1),
+        }
+    }
+
+    pub fn begin(&self) -> Transaction<'_> {
+        Transaction {
+            owner: self,
+            id: self.next_transaction.fetch_add(1, Ordering::SeqCst),
+            writes: BTreeMap::new(),
+            aborted: false,
+        }
+    }
+
+    pub fn into_inner(self) -> BPlusTree {
+        self.tree.into_inner().unwrap()
+    }
+}
+
+// Writes are buffered until commit. Exclusive locks keep anyone else from observing
+// the keys in the meantime, so aborting only has to drop the buffer.
+#[derive(Debug)]
+pub struct Transaction<'a> {
+    owner: &'a LockingTree,
+    id: TransactionId,
+    pub(crate) writes: BTreeMap<String, Option<String>>,
+    aborted: bool,
+}
+
+impl Transaction<'_> {
+    pub fn id(&self) -> TransactionId {
+        self.id
+    }
+
+    pub(crate) fn lock(&mut self, resource: Resource, mode: LockMode) -> Result<(), LockError> {
+        if self.aborted {
+            return Err(LockError::Aborted);
+        }
+        let intention = match mode {
+            LockMode::Shared | LockMode::IntentionShared => LockMode::IntentionShared,
+            _ => LockMode::IntentionExclusive,
+        };
+        let result = self
+            .owner
+            .locks
+            .lock(self.id, Resource::Tree, intention)
+            .and_then(|_| self.owner.locks.lock(self.id, resource, mode));
+        if result.is_err() {
+            self.rollback();
+        }
+        result
+    }
+
+    pub fn get(&mut self, key: &str) -> Result<Option<String>, LockError> {
+        self.lock(Resource::Key(key.to_string()), LockMode::Shared)?;
+        if let Some(write) = self.writes.get(key) {
+            return Ok(write.clone());
+        }
+        Ok(self.owner.tree.lock().unwrap().get(key))
+    }
+
+    pub fn scan<R: RangeBounds<String>>(
+        &mut self,
+        range: R,
+    ) -> Result<Vec<(String, String)>, LockError> {
+        let bounds = (range.start_bound().cloned(), range.end_bound().cloned());
+        self.lock(Resource::range(bounds.clone()), LockMode::Shared)?;
+        let mut result: BTreeMap<String, String> = self
+            .owner
+            .tree
+            .lock()
+            .unwrap()
+            .scan(bounds)
+            .into_iter()
+            .collect();
+
+        let (start, end) = str_bounds(&range);
+        for (key, write) in self.writes.iter() {
+            if !after_start(key, start) || !before_end(key, end) {
+                continue;
+            }
+            match write {
+                Some(value) => result.insert(key.clone(), value.clone()),
+                None => result.remove(key),
+            };
+        }
+        Ok(result.into_iter().collect())
+    }
+
+    pub fn insert(&mut self, key: String, value: String) -> Result<(), LockError> {
+        self.lock(Resource::Key(key.clone()), LockMode::Exclusive)?;
+        self.writes.insert(key, Some(value));
+        Ok(())
+    }
+
+    pub fn delete(&mut self, key: String) -> Result<(), LockError> {
+        self.lock(Resource::Key(key.clone()), LockMode::Exclusive)?;
+        self.writes.insert(key, None);
+        Ok(())
+    }
+
+    pub fn commit(mut self) -> Result<(), LockError> {
+        if self.aborted {
+            return Err(LockError::Aborted);
+        }
+        {
+            let mut tree = self.owner.tree.lock().unwrap();
+            for (key, write) in std::mem::take(&mut self.writes) {
+                match write {
+                    Some(value) => tree.insert(key, value),
+                    None => tree.delete(key),
+                }
+            }
+        }
+        self.owner.locks.release_all(self.id);
+        self.aborted = true;
+        Ok(())
+    }
+
+    pub fn abort(mut self) {
+        self.rollback();
+    }
+
+    fn rollback(&mut self) {
+        self.writes.clear();
+        self.aborted = true;
+        self.owner.locks.release_all(self.id);
+    }
+}
+
+impl Drop for Transaction<'_> {
+    fn drop(&mut self) {
+        if !self.aborted {
+            self.rollback();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Barrier;
+    use std::thread;
+
+    fn tree_with(entries: &[(&str, &str)], timeout: Duration) -> LockingTree {
+        let mut tree = BPlusTree::new();
+        for (key, value) in entries {
+            tree.insert(key.to_string(), value.to_string());
+        }
+        LockingTree::new(tree, timeout)
+    }
+
+    #[test]
+    fn shared_and_exclusive_conflict() {
+        let locks = LockManager::new(Duration::from_millis(20));
+        let key = || Resource::Key("a".to_string());
+        locks.lock(1, key(), LockMode::Shared).unwrap();
+        locks.lock(2, key(), LockMode::Shared).unwrap();
+        assert_eq!(
+            locks.lock(3, key(), LockMode::Exclusive),
+            Err(LockError::Timeout)
+        );
+        locks.release_all(1);
+        locks.release_all(2);
+        locks.lock(3, key(), LockMode::Exclusive).unwrap();
+    }
+
+    #[test]
+    fn range_locks_cover_keys() {
+        let locks = LockManager::new(Duration::from_millis(20));
+        let range = Resource::range("b".to_string().."d".to_string());
+        locks.lock(1, range, LockMode::Shared).unwrap();
+        assert_eq!(
+            locks.lock(2, Resource::Key("c".to_string()), LockMode::Exclusive),
+            Err(LockError::Timeout)
+        );
+        locks
+            .lock(2, Resource::Key("d".to_string()), LockMode::Exclusive)
+            .unwrap();
+    }
+
+    #[test]
+    fn intention_locks_conflict_with_tree_locks() {
+        let locks = LockManager::new(Duration::from_millis(20));
+        locks
+            .lock(1, Resource::Tree, LockMode::IntentionExclusive)
+            .unwrap();
+        locks
+            .lock(2, Resource::Tree, LockMode::IntentionShared)
+            .unwrap();
+        assert_eq!(
+            locks.lock(3, Resource::Tree, LockMode::Shared),
+            Err(LockError::Timeout)
+        );
+    }
+
+    #[test]
+    fn lock_wait_times_out() {
+        let db = tree_with(&[("x", "0")], Duration::from_millis(50));
+        let mut writer = db.begin();
+        writer.insert("x".to_string(), "1".to_string()).unwrap();
+
+        let mut reader = db.begin();
+        let started = Instant::now();
+        assert_eq!(reader.get("x"), Err(LockError::Timeout));
+        assert!(started.elapsed() >= Duration::from_millis(50));
+        assert_eq!(reader.get("x"), Err(LockError::Aborted));
+
+        writer.commit().unwrap();
+        assert_eq!(db.begin().get("x"), Ok(Some("1".to_string())));
+    }
+
+    #[test]
+    fn lost_update_is_prevented() {
+        let db = tree_with(&[("counter", "0")], Duration::from_secs(5));
+        let increments = 20;
+
+        thread::scope(|scope| {
+            for _ in 0..2 {
+                scope.spawn(|| {
+                    let mut done = 0;
+                    while done < increments {
+                        let mut transaction = db.begin();
+                        let result = transaction.get("counter").and_then(|value| {
+                            let next = value.unwrap().parse::<u64>().unwrap() + 1;
+                            thread::yield_now();
+                            transaction.insert("counter".to_string(), next.to_string())
+                        });
+                        if result.is_ok() && transaction.commit().is_ok() {
+                            done += 1;
+                        }
+                    }
+                });
+            }
+        });
+
+        let tree = db.into_inner();
+        assert_eq!(tree.get("counter"), Some((2 * increments).to_string()));
+    }
+
+    #[test]
+    fn write_skew_is_prevented() {
+        // both doctors are on call; each may go off call only if the other stays
+        let db = tree_with(&[("alice", "on"), ("bob", "on")], Duration::from_secs(5));
+        let barrier = Barrier::new(2);
+
+        let results: Vec<Result<(), LockError>> = thread::scope(|scope| {
+            let handles: Vec<_> = [("alice", "bob"), ("bob", "alice")]
+                .into_iter()
+                .map(|(me, other)| {
+                    let db = &db;
+                    let barrier = &barrier;
+                    scope.spawn(move || {
+                        let mut transaction = db.begin();
+                        transaction.get(me)?;
+                        let other_on_call = transaction.get(other)?;
+                        barrier.wait();
+                        if other_on_call.as_deref() == Some("on") {
+                            transaction.insert(me.to_string(), "off".to_string())?;
+                        }
+                        transaction.commit()
+                    })
+                })
+                .collect();
+            handles.into_iter().map(|h| h.join().unwrap()).collect()
+        });
+
+        assert_eq!(results.iter().filter(|r| r.is_ok()).count(), 1);
+        assert!(results.contains(&Err(LockError::Deadlock)));
+
+        let tree = db.into_inner();
+        let on_call = tree.scan(..).into_iter().filter(|(_, v)| v == "on").count();
+        assert_eq!(on_call, 1);
+    }
+
+    #[test]
+    fn phantoms_are_prevented() {
+        let db = tree_with(
+            &[("a", "1"), ("c", "1"), ("e", "1"), ("g", "1")],
+            Duration::from_secs(5),
+        );
+        let scanned = Barrier::new(2);
+
+        thread::scope(|scope| {
+            let mut reader = db.begin();
+            let first = reader.scan("b".to_string().."f".to_string()).unwrap();
+            assert_eq!(first.len(), 2);
+
+            let writer = scope.spawn(|| {
+                scanned.wait();
+                let mut transaction = db.begin();
+                transaction
+                    .insert("d".to_string(), "1".to_string())
+                    .unwrap();
+                transaction.commit().unwrap();
+            });
+            scanned.wait();
+
+            // give the writer a chance to slip the insert in
+            thread::sleep(Duration::from_millis(50));
+            let second = reader.scan("b".to_string().."f".to_string()).unwrap();
+            assert_eq!(first, second);
+            reader.commit().unwrap();
+
+            writer.join().unwrap();
+        });
+
+        let mut transaction = db.begin();
+        let after = transaction.scan("b".to_string().."f".to_string()).unwrap();
+        assert_eq!(after.len(), 3);
+    }
+}
@@ -1,475 +1,385 @@
-// Instead of tree structure, we are going to use a vector of nodes which are fixed size.
-// Each holds an optional parent index
-// Each holds a vector of n keys
-// Each holds a vector of either n+1 child indicies or n values
-
-const FANOUT: usize = 5;
-const SPLIT_AFTER: usize = FANOUT;
-const MERGE: usize = FANOUT / 2;
-
-fn borrow_mut_nodes<'a>(
-    v: &'a mut Vec<ArrayNode>,
-    indicies: (usize, usize, usize),
-) -> (&mut ArrayNode, &mut ArrayNode, &mut ArrayNode) {
-    let mut result: (
-        Option<&mut ArrayNode>,
-        Option<&mut ArrayNode>,
-        Option<&mut ArrayNode>,
-    ) = (None, None, None);
-
-    let mut current: &mut [ArrayNode];
-    let mut rest = &mut v[..];
-    let mut index = 0;
-    while rest.len() > 0 {
-        (current, rest) = rest.split_at_mut(1);
-        if index == indicies.0 {
-            result.0 = Some(&mut current[0]);
-        } else if index == indicies.1 {
-            result.1 = Some(&mut current[0]);
-        } else if index == indicies.2 {
-            result.2 = Some(&mut current[0]);
-        }
-        index += 1;
-    }
-    (result.0.unwrap(), result.1.unwrap(), result.2.unwrap())
-}
-
-#[derive(Debug)]
-enum NodeValue {
-    Internal(Vec<usize>),
-    Leaf(Vec<String>),
+// An interactive shell around a BPlusTree, for poking at the tree structure while
+// debugging without recompiling. On a terminal it reads commands with line editing
+// and keeps their history in ~/.b_plus_tree_history; given a script file, or with
+// its input piped in, it runs the commands one per line and stops at the first one
+// that fails. Lines starting with # are comments.
+//
+// Arguments are separated by whitespace; an argument with spaces or quotes in it can
+// be written in double quotes, with \" and \\ for a quote and a backslash.
+
+use std::env;
+use std::fs;
+use std::io::{self, BufRead, IsTerminal};
+use std::ops::Bound;
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+use b_plus_tree::compare::{Bytewise, CaseInsensitive, Natural};
+use b_plus_tree::{BPlusTree, MIN_FANOUT};
+use rustyline::error::ReadlineError;
+use rustyline::DefaultEditor;
+
+const HELP: &str = "\
+put KEY VALUE      insert or overwrite an entry
+get KEY            print the value of KEY
+del KEY            delete KEY
+scan [FROM [TO]]   print the entries in [FROM, TO); - leaves a bound open
+len                print the number of entries
+show [dot|json]    print every node, or the tree as Graphviz DOT or JSON
+stats              print statistics about the shape and size of the tree
+validate           check the structural invariants of the tree
+flush              apply the writes pending in lazy leaf buffers
+load FILE          replace the entries with those of a snapshot
+save FILE          write the entries to a snapshot
+config [NAME=VALUE ...]
+                   print or change the configuration:
+                     comparator=bytewise|case-insensitive|natural
+                     buffer=N|off   lazy leaf buffers of N writes
+                     overflow=N|off values over N bytes go to overflow pages
+                     fanout=N       nodes split past N keys, at least 3
+help               print this text
+quit               leave the shell";
+
+enum Flow {
+    Continue,
+    Quit,
 }
 
-#[derive(Debug)]
-struct ArrayNode {
-    parent: Option<usize>,
-    keys: Vec<String>,
-    values: NodeValue,
+struct Shell {
+    tree: BPlusTree,
+    buffer: Option<usize>,
+    overflow: Option<usize>,
 }
 
-impl ArrayNode {
-    fn new() -> Self {
-        ArrayNode {
-            parent: None,
-            keys: Vec::with_capacity(FANOUT),
-            values: NodeValue::Leaf(Vec::with_capacity(FANOUT)),
-        }
-    }
-
-    fn display(&self, indent: &str) {
-        let parent_string = match self.parent {
-            Some(index) => index.to_string(),
-            None => "Root".to_string(),
+// splits a line into arguments, honouring double quotes
+fn tokenize(line: &str) -> Result<Vec<String>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = line.chars().peekable();
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        let Some(first) = chars.next() else {
+            return Ok(tokens);
         };
-
-        let type_string = match self.values {
-            NodeValue::Internal(_) => format!("{}Internal(parent: {})", indent, parent_string),
-            NodeValue::Leaf(_) => format!("{}Leaf(parent: {})", indent, parent_string),
-        };
-        println!("{}Node: {}", indent, type_string);
-        println!("{}Keys: {:?}", indent, self.keys);
-        match self.values {
-            NodeValue::Internal(ref children) => {
-                println!("{}Children: {:?}", indent, children);
+        let mut token = String::new();
+        if first == '"' {
+            loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some('\\') => match chars.next() {
+                        Some(c @ ('"' | '\\')) => token.push(c),
+                        Some(c) => return Err(format!("unknown escape \\{}", c)),
+                        None => return Err("unterminated quote".to_string()),
+                    },
+                    Some(c) => token.push(c),
+                    None => return Err("unterminated quote".to_string()),
+                }
+            }
+        } else {
+            token.push(first);
+            while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                token.push(c);
             }
-            NodeValue::Leaf(ref values) => println!("{}Values: {:?}", indent, values),
         }
+        tokens.push(token);
     }
 }
 
-#[derive(Debug)]
-struct BPlusTree {
-    root_index: usize,
-    nodes: Vec<ArrayNode>,
+fn parse_limit(name: &str, value: &str) -> Result<Option<usize>, String> {
+    match value {
+        "off" => Ok(None),
+        _ => match value.parse::<usize>() {
+            Ok(0) | Err(_) => Err(format!("{} takes a positive number or off", name)),
+            Ok(limit) => Ok(Some(limit)),
+        },
+    }
 }
 
-impl BPlusTree {
-    fn new() -> Self {
-        BPlusTree {
-            root_index: 0,
-            nodes: vec![ArrayNode::new()],
-        }
+fn bound(argument: Option<&String>, included: bool) -> Bound<String> {
+    match argument.map(String::as_str) {
+        None | Some("-") => Bound::Unbounded,
+        Some(key) if included => Bound::Included(key.to_string()),
+        Some(key) => Bound::Excluded(key.to_string()),
     }
+}
 
-    fn display(&self) {
-        let mut stack = vec![self.root_index];
-        let mut indent = "".to_string();
-
-        println!();
-        println!("---------------------------------------------");
-        println!("Displaying BTREE");
-        println!("---------------------------------------------");
-
-        loop {
-            let mut next_stack = Vec::new();
-            for node_index in stack.iter() {
-                println!();
-                self.nodes[*node_index].display(&indent);
-                match self.nodes[*node_index].values {
-                    NodeValue::Internal(ref pointers) => {
-                        for pointer in pointers.iter() {
-                            next_stack.push(*pointer);
-                        }
-                    }
-                    NodeValue::Leaf(_) => (),
-                }
-            }
-
-            if next_stack.len() == 0 {
-                break;
-            }
-
-            stack = next_stack;
-            indent += "  ";
+impl Shell {
+    fn new() -> Self {
+        Shell {
+            tree: BPlusTree::new(),
+            buffer: None,
+            overflow: None,
         }
-        println!();
     }
 
-    fn check_split(&mut self, index: usize) {
-        if self.nodes[index].keys.len() <= SPLIT_AFTER {
-            return;
+    fn run(&mut self, line: &str) -> Result<Flow, String> {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            return Ok(Flow::Continue);
         }
-        self.split(index);
-    }
-
-    fn split(&mut self, node_index: usize) {
-        let nodes_length = self.nodes.len();
-        let mut_nodes_ref = &mut self.nodes;
-        let parent = mut_nodes_ref[node_index].parent;
-        let next_parent_index = match parent {
-            Some(index) => index,
-            None => {
-                // create new root node
-                mut_nodes_ref.len() + 1
+        let tokens = tokenize(line)?;
+        let arguments = &tokens[1..];
+        let expect = |count: usize, usage: &str| {
+            if arguments.len() == count {
+                Ok(())
+            } else {
+                Err(format!("usage: {}", usage))
             }
         };
 
-        // determine promotion index
-        let promotion_index = match mut_nodes_ref[node_index].values {
-            NodeValue::Internal(_) => FANOUT / 2,
-            NodeValue::Leaf(_) => (FANOUT + 1) / 2,
-        };
-        let promotion_key: String = mut_nodes_ref[node_index].keys[promotion_index].clone();
-
-        let mut right_keys = mut_nodes_ref[node_index].keys.split_off(promotion_index);
-        match mut_nodes_ref[node_index].values {
-            NodeValue::Internal(_) => {
-                right_keys.remove(0);
+        match tokens[0].as_str() {
+            "put" => {
+                expect(2, "put KEY VALUE")?;
+                self.tree.insert(arguments[0].clone(), arguments[1].clone());
             }
-            NodeValue::Leaf(_) => (),
-        }
-
-        // create sibling node
-        let sibling_node = ArrayNode {
-            parent: Some(next_parent_index),
-            keys: right_keys,
-            values: match mut_nodes_ref[node_index].values {
-                NodeValue::Internal(ref mut pointers) => {
-                    let mut sibling_pointers = Vec::with_capacity(FANOUT + 1);
-                    sibling_pointers.extend(pointers.split_off(promotion_index + 1));
-
-                    NodeValue::Internal(sibling_pointers)
-                }
-                NodeValue::Leaf(ref mut values) => {
-                    let mut sibling_values = Vec::with_capacity(FANOUT);
-                    sibling_values.extend(values.split_off(promotion_index));
-
-                    NodeValue::Leaf(sibling_values)
+            "get" => {
+                expect(1, "get KEY")?;
+                match self.tree.get(&arguments[0]) {
+                    Some(value) => println!("{}", value),
+                    None => println!("(not found)"),
                 }
-            },
-        };
-        mut_nodes_ref.push(sibling_node);
-
-        // update parent of original node
-        mut_nodes_ref[node_index].parent = Some(next_parent_index);
-
-        //update parent node
-        match parent {
-            Some(parent_index) => {
-                let parent_node = &mut mut_nodes_ref[parent.unwrap()];
-                let mut key_position = 0;
-                for key in parent_node.keys.iter() {
-                    if key.clone() > promotion_key {
-                        break;
-                    }
-                    key_position += 1;
+            }
+            "del" => {
+                expect(1, "del KEY")?;
+                self.tree.delete(arguments[0].clone());
+            }
+            "scan" => {
+                if arguments.len() > 2 {
+                    return Err("usage: scan [FROM [TO]]".to_string());
                 }
-                parent_node.keys.insert(key_position, promotion_key);
-                match parent_node.values {
-                    NodeValue::Internal(ref mut pointers) => {
-                        pointers.insert(key_position + 1, nodes_length);
-                    }
-                    NodeValue::Leaf(_) => panic!("Leaf node is parent"),
+                let range = (
+                    bound(arguments.first(), true),
+                    bound(arguments.get(1), false),
+                );
+                let entries = self.tree.scan(range);
+                for (key, value) in entries.iter() {
+                    println!("{} = {}", key, value);
                 }
-                self.check_split(parent_index)
+                println!("({} entries)", entries.len());
             }
-            None => {
-                // create new root node
-                let mut new_root = ArrayNode {
-                    parent: None,
-                    keys: Vec::with_capacity(FANOUT),
-                    values: NodeValue::Internal(Vec::with_capacity(FANOUT + 1)),
-                };
-                new_root.keys.push(promotion_key);
-
-                match new_root.values {
-                    NodeValue::Internal(ref mut pointers) => {
-                        pointers.push(node_index);
-                        pointers.push(self.nodes.len() - 1);
-                    }
-                    NodeValue::Leaf(_) => panic!("New root is a leaf"),
-                }
-
-                self.nodes.push(new_root);
-                self.root_index = self.nodes.len() - 1;
+            "len" => {
+                expect(0, "len")?;
+                println!("{}", self.tree.len());
             }
-        };
-    }
-
-    fn remove_node(&mut self, index: usize) {
-        // uses swap_remove so that we do not need to reorder all elements
-        if self.root_index == index {
-            panic!("Removed root node");
-        }
-
-        let swap_origin = self.nodes.len() - 1;
-
-        self.nodes.swap_remove(index);
-
-        if self.root_index == swap_origin {
-            self.root_index = index;
-        }
-
-        // update parent indicies
-        for node in self.nodes.iter_mut() {
-            match node.values {
-                NodeValue::Internal(ref mut pointers) => {
-                    for pointer in pointers.iter_mut() {
-                        if *pointer == index {
-                            panic!("Removed referenced node");
-                        }
-
-                        if *pointer == swap_origin {
-                            *pointer = index;
-                        }
-                    }
-                }
-                NodeValue::Leaf(_) => (),
+            "show" => match arguments.first().map(String::as_str) {
+                None => self.tree.display(),
+                Some("dot") => print!("{}", self.tree.to_dot()),
+                Some("json") => println!("{}", self.tree.to_json()),
+                Some(_) => return Err("usage: show [dot|json]".to_string()),
+            },
+            "stats" => {
+                expect(0, "stats")?;
+                print!("{}", self.tree.stats());
             }
-            match node.parent {
-                Some(parent_index) => {
-                    if parent_index == index {
-                        panic!("Removed referenced node");
-                    }
-                    if parent_index == swap_origin {
-                        node.parent = Some(index);
-                    }
-                }
-                None => (),
+            "validate" => {
+                expect(0, "validate")?;
+                self.tree.validate()?;
+                println!("ok");
             }
-        }
-    }
-
-    fn check_merge(&mut self, index: usize) {
-        let check_node = &self.nodes[index];
-
-        match check_node.values {
-            NodeValue::Internal(ref pointers) => {
-                let mut first_pos = 0;
-                let mut second_pos = 1;
-                while second_pos < pointers.len() {
-                    if self.nodes[pointers[first_pos]].keys.len()
-                        + self.nodes[pointers[second_pos]].keys.len()
-                        <= MERGE
-                    {
-                        self.merge(first_pos, second_pos);
-                        return;
-                    } else {
-                        first_pos += 1;
-                        second_pos += 1;
-                    }
-                }
+            "flush" => {
+                expect(0, "flush")?;
+                self.tree.flush();
+            }
+            "load" => {
+                expect(1, "load FILE")?;
+                self.tree
+                    .load(&arguments[0])
+                    .map_err(|error| format!("cannot load {}: {}", arguments[0], error))?;
+                println!("{} entries", self.tree.len());
             }
-            NodeValue::Leaf(_) => {
-                println!("Check merge called on leaf node --- noop")
+            "save" => {
+                expect(1, "save FILE")?;
+                self.tree
+                    .save(&arguments[0])
+                    .map_err(|error| format!("cannot save {}: {}", arguments[0], error))?;
             }
+            "config" => {
+                for setting in arguments {
+                    self.configure(setting)?;
+                }
+                if arguments.is_empty() {
+                    let limit =
+                        |limit: Option<usize>| limit.map_or("off".to_string(), |l| l.to_string());
+                    println!("fanout={}", self.tree.fanout());
+                    println!("comparator={}", self.tree.comparator_name());
+                    println!("buffer={}", limit(self.buffer));
+                    println!("overflow={}", limit(self.overflow));
+                }
+            }
+            "help" => println!("{}", HELP),
+            "quit" | "exit" => return Ok(Flow::Quit),
+            command => return Err(format!("unknown command {:?}, try help", command)),
         }
+        Ok(Flow::Continue)
     }
 
-    fn merge(&mut self, left_node_index: usize, right_node_index: usize) {
-        let parent_index = self.nodes[left_node_index].parent.clone().unwrap();
-
-        let (left_node, right_node, parent_node) = borrow_mut_nodes(
-            &mut self.nodes,
-            (left_node_index, right_node_index, parent_index),
-        );
-
-        let parent_remove_separator = left_node.keys[left_node.keys.len() - 1].clone();
-
-        let mut key_position = 0;
-        for node_key in parent_node.keys.iter() {
-            if node_key.clone() >= parent_remove_separator {
-                break;
+    fn configure(&mut self, setting: &str) -> Result<(), String> {
+        let (name, value) = setting.split_once('=').unwrap_or((setting, ""));
+        match name {
+            "comparator" => {
+                let tree = match value {
+                    "bytewise" => BPlusTree::with_comparator(Bytewise),
+                    "case-insensitive" => BPlusTree::with_comparator(CaseInsensitive),
+                    "natural" => BPlusTree::with_comparator(Natural),
+                    _ => return Err(format!("unknown comparator {:?}", value)),
+                };
+                self.rebuild(tree);
             }
-            key_position += 1;
-        }
-
-        parent_node.keys.remove(key_position);
-        match parent_node.values {
-            NodeValue::Internal(ref mut pointers) => {
-                pointers.remove(key_position);
+            "buffer" => {
+                self.buffer = parse_limit(name, value)?;
+                self.tree.set_update_buffer(self.buffer);
             }
-            NodeValue::Leaf(ref mut values) => {
-                values.remove(key_position);
+            "overflow" => {
+                self.overflow = parse_limit(name, value)?;
+                self.tree.set_overflow_threshold(self.overflow);
             }
-        }
-
-        // move keys and values from left node to right node
-        right_node.keys.append(&mut left_node.keys);
-        match right_node.values {
-            NodeValue::Internal(ref mut pointers) => match left_node.values {
-                NodeValue::Internal(ref mut left_pointers) => {
-                    pointers.append(left_pointers);
-                }
-                NodeValue::Leaf(_) => panic!("Sibling nodes have different types"),
-            },
-            NodeValue::Leaf(ref mut values) => match left_node.values {
-                NodeValue::Leaf(ref mut left_values) => {
-                    values.append(left_values);
-                }
-                NodeValue::Internal(_) => panic!("Sibling nodes have different types"),
+            "fanout" => match value.parse::<usize>() {
+                Ok(fanout) if fanout >= MIN_FANOUT => self.tree.set_fanout(fanout),
+                _ => return Err(format!("fanout takes a number of at least {}", MIN_FANOUT)),
             },
+            _ => return Err(format!("unknown setting {:?}", name)),
         }
+        Ok(())
+    }
 
-        // propagate merge upwards
-        match parent_node.parent {
-            Some(index) => self.check_merge(index),
-            None => {}
+    // moves every entry into `tree`, which has the current settings applied to it;
+    // keys the new comparator considers equal collapse into one entry
+    fn rebuild(&mut self, mut tree: BPlusTree) {
+        tree.set_fanout(self.tree.fanout());
+        tree.set_update_buffer(self.buffer);
+        tree.set_overflow_threshold(self.overflow);
+        for (key, value) in self.tree.scan(..) {
+            tree.insert(key, value);
         }
-
-        self.remove_node(left_node_index);
+        self.tree = tree;
     }
+}
 
-    fn get_node_for_key(&mut self, key: String) -> usize {
-        let mut target_node = &mut self.nodes[self.root_index];
-        let mut target_node_index = self.root_index;
-
-        loop {
-            match target_node.values {
-                NodeValue::Internal(ref mut children) => {
-                    // Find the index of the child to descend into
-                    let mut index = 0;
-                    for child in target_node.keys.iter() {
-                        if *child == key {
-                            break;
-                        }
-                        if *child > key {
-                            break;
-                        }
-                        index += 1;
-                    }
-                    let next_index = children[index];
+fn history_path() -> Option<PathBuf> {
+    env::var_os("HOME").map(|home| PathBuf::from(home).join(".b_plus_tree_history"))
+}
 
-                    target_node = &mut self.nodes[next_index];
-                    target_node_index = next_index;
-                }
-                NodeValue::Leaf(_) => {
-                    // Insert into the leaf node
-                    return target_node_index;
-                }
-            }
+fn interactive(shell: &mut Shell) -> Result<(), ReadlineError> {
+    let mut editor = DefaultEditor::new()?;
+    let history = history_path();
+    if let Some(ref path) = history {
+        // there is no history before the first session
+        let _ = editor.load_history(path);
+    }
+    println!("B+ tree shell, type help for the commands");
+    loop {
+        let line = match editor.readline("> ") {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(error) => return Err(error),
+        };
+        if !line.trim().is_empty() {
+            editor.add_history_entry(line.as_str())?;
+        }
+        match shell.run(&line) {
+            Ok(Flow::Continue) => (),
+            Ok(Flow::Quit) => break,
+            Err(error) => eprintln!("error: {}", error),
         }
     }
+    if let Some(ref path) = history {
+        editor.save_history(path)?;
+    }
+    Ok(())
+}
 
-    fn insert(&mut self, key: String, value: String) {
-        let target_node_index = self.get_node_for_key(key.clone());
-        let target_node = &mut self.nodes[target_node_index];
-
-        match target_node.values {
-            NodeValue::Internal(_) => panic!("Search yielded internal node"),
-            NodeValue::Leaf(ref mut children) => {
-                // Insert into the leaf node
-                let mut index = 0;
-                let mut found = false;
-
-                for child in target_node.keys.iter() {
-                    if *child == key {
-                        found = true;
-                        break;
-                    }
-                    if *child > key {
-                        break;
-                    }
-                    index += 1;
-                }
+fn scripted<R: BufRead>(shell: &mut Shell, input: R) -> Result<(), String> {
+    for (number, line) in input.lines().enumerate() {
+        let line = line.map_err(|error| error.to_string())?;
+        match shell.run(&line) {
+            Ok(Flow::Continue) => (),
+            Ok(Flow::Quit) => break,
+            Err(error) => return Err(format!("line {}: {}", number + 1, error)),
+        }
+    }
+    Ok(())
+}
 
-                if found {
-                    target_node.keys[index] = key;
-                    children[index] = value;
-                } else {
-                    target_node.keys.insert(index, key);
-                    children.insert(index, value);
-                    if target_node.keys.len() > SPLIT_AFTER {
-                        self.check_split(target_node_index)
-                    }
-                }
-            }
+fn main() -> ExitCode {
+    let mut shell = Shell::new();
+    let arguments: Vec<String> = env::args().skip(1).collect();
+    let result = match arguments.as_slice() {
+        [] if io::stdin().is_terminal() => {
+            interactive(&mut shell).map_err(|error| error.to_string())
+        }
+        [] => scripted(&mut shell, io::stdin().lock()),
+        [flag] if flag == "-h" || flag == "--help" => {
+            println!("usage: b_plus_tree [SCRIPT]\n\n{}", HELP);
+            Ok(())
+        }
+        [script] => match fs::File::open(script) {
+            Ok(file) => scripted(&mut shell, io::BufReader::new(file)),
+            Err(error) => Err(format!("cannot open {}: {}", script, error)),
+        },
+        _ => Err("usage: b_plus_tree [SCRIPT]".to_string()),
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => {
+            eprintln!("error: {}", error);
+            ExitCode::FAILURE
         }
     }
+}
 
-    fn delete(&mut self, key: String) {
-        let target_node_index = self.get_node_for_key(key.clone());
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        let nodes = &mut self.nodes;
-        let target_node = &mut nodes[target_node_index];
+    fn run_all(shell: &mut Shell, lines: &[&str]) {
+        for line in lines {
+            shell.run(line).unwrap();
+        }
+    }
 
-        match target_node.values {
-            NodeValue::Internal(_) => panic!("Search yielded internal node"),
-            NodeValue::Leaf(ref mut children) => {
-                // Insert into the leaf node
-                let mut index = 0;
+    #[test]
+    fn tokenize_quotes() {
+        assert_eq!(
+            tokenize(r#"  put "two words" x\y "q\"\\" "#),
+            Ok(vec![
+                "put".to_string(),
+                "two words".to_string(),
+                r"x\y".to_string(),
+                r#"q"\"#.to_string(),
+            ])
+        );
+        assert_eq!(tokenize(""), Ok(Vec::new()));
+        assert!(tokenize(r#"put "open"#).is_err());
+        assert!(tokenize(r#"put "\n""#).is_err());
+    }
 
-                for child in target_node.keys.iter() {
-                    if *child == key {
-                        target_node.keys.remove(index);
-                        children.remove(index);
-                        break;
-                    }
-                    if *child > key {
-                        break;
-                    }
-                    index += 1;
-                }
-            }
+    #[test]
+    fn config_rebuilds_the_tree() {
+        let mut shell = Shell::new();
+        for i in 0..40 {
+            run_all(&mut shell, &[&format!("put key{} {}", i, i)]);
         }
+        run_all(
+            &mut shell,
+            &["config fanout=3", "validate", "config comparator=natural"],
+        );
+        assert_eq!(shell.tree.fanout(), 3);
+        assert_eq!(shell.tree.comparator_name(), "natural");
+        assert_eq!(shell.tree.select(2).unwrap().0, "key2");
+        shell.tree.validate().unwrap();
+
+        run_all(
+            &mut shell,
+            &["config buffer=4 overflow=8 fanout=16", "put key0 x"],
+        );
+        assert_eq!(shell.tree.fanout(), 16);
+        assert_eq!(shell.tree.len(), 40);
+        assert_eq!(shell.tree.get("key0"), Some("x".to_string()));
+        shell.tree.validate().unwrap();
 
-        match target_node.parent {
-            Some(index) => self.check_merge(index),
-            None => {}
+        for invalid in ["config fanout=2", "config buffer=0", "config nope=1", "get"] {
+            assert!(shell.run(invalid).is_err(), "{}", invalid);
         }
+        assert!(matches!(shell.run("quit"), Ok(Flow::Quit)));
     }
 }
-
-fn main() {
-    let mut tree = BPlusTree::new();
-    tree.insert("a".to_string(), "a".to_string());
-    tree.insert("b".to_string(), "b".to_string());
-    tree.insert("c".to_string(), "c".to_string());
-    tree.insert("d".to_string(), "d".to_string());
-    tree.insert("e".to_string(), "e".to_string());
-    tree.insert("f".to_string(), "f".to_string());
-    tree.insert("g".to_string(), "g".to_string());
-    tree.insert("h".to_string(), "h".to_string());
-    tree.insert("i".to_string(), "i".to_string());
-    tree.insert("j".to_string(), "j".to_string());
-    tree.delete("a".to_string());
-    tree.delete("b".to_string());
-    tree.delete("d".to_string());
-    tree.delete("e".to_string());
-    tree.display();
-    tree.delete("f".to_string());
-    tree.display();
-    tree.insert("k".to_string(), "k".to_string());
-    tree.insert("l".to_string(), "l".to_string());
-    tree.display();
-}
@@ -0,0 +1,197 @@
+// A multimap over BPlusTree for secondary indexes, where one key maps to many values.
+//
+// Every value is stored under a composite key, the key and a sequence number encoded
+// with the tuple codec, so any string can be a key and composite keys sort by key
+// first, in byte order, and by sequence number second. The sequence number only
+// grows, so the values of a key sit next to each other in the tree in the order they
+// were inserted, however many there are, and a key with many values simply spreads
+// over as many leaves as it needs. Removing all values of a key is a single range
+// delete.
+//
+// This is a type of its own rather than a mode of BPlusTree: in a tree that keeps
+// every value of a key, `get`, `rank`, `count`, the aggregates and everything else
+// built on one entry per key would have to change meaning, so the multimap keeps the
+// composite keys to itself and only offers the operations that make sense for it.
+
+use std::ops::{Bound, RangeBounds};
+
+use crate::codec::{KeyCodec, Value};
+use crate::{str_bounds, BPlusTree};
+
+#[derive(Debug)]
+pub struct MultiMap {
+    tree: BPlusTree,
+    codec: KeyCodec,
+    next_sequence: u64,
+}
+
+impl Default for MultiMap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MultiMap {
+    pub fn new() -> Self {
+        MultiMap {
+            tree: BPlusTree::new(),
+            codec: KeyCodec::ascending(2),
+            next_sequence: 0,
+        }
+    }
+
+    // values stored under all keys
+    pub fn len(&self) -> usize {
+        self.tree.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tree.is_empty()
+    }
+
+    fn composite(&self, key: &str, sequence: u64) -> String {
+        self.codec
+            .encode_key(&[Value::from(key), Value::Int(sequence as i64)])
+    }
+
+    // the composite keys of the values stored under `key`
+    fn key_range(&self, key: &str) -> (Bound<String>, Bound<String>) {
+        self.codec.prefix_range(&[Value::from(key)])
+    }
+
+    // the composite keys of the values stored under the keys between two bounds
+    fn composite_bounds(
+        &self,
+        start: Bound<&str>,
+        end: Bound<&str>,
+    ) -> (Bound<String>, Bound<String>) {
+        let start = match start {
+            Bound::Included(key) => self.key_range(key).0,
+            Bound::Excluded(key) => match self.key_range(key).1 {
+                Bound::Excluded(after) => Bound::Included(after),
+                _ => unreachable!("prefix ranges end before the next key"),
+            },
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        let end = match end {
+            Bound::Included(key) => self.key_range(key).1,
+            Bound::Excluded(key) => match self.key_range(key).0 {
+                Bound::Included(first) => Bound::Excluded(first),
+                _ => unreachable!("prefix ranges start at the key"),
+            },
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        (start, end)
+    }
+
+    fn entries(&self, key: &str) -> Vec<(String, String)> {
+        self.tree.scan(self.key_range(key))
+    }
+
+    // adds a value after the ones `key` already has
+    pub fn insert(&mut self, key: String, value: String) {
+        let sequence = self.next_sequence;
+        self.next_sequence += 1;
+        self.tree.insert(self.composite(&key, sequence), value);
+    }
+
+    // the values of `key` in insertion order
+    pub fn get_all(&self, key: &str) -> Vec<String> {
+        self.entries(key)
+            .into_iter()
+            .map(|(_, value)| value)
+            .collect()
+    }
+
+    pub fn count(&self, key: &str) -> usize {
+        self.tree.count(self.key_range(key))
+    }
+
+    // removes the oldest occurrence of `value` under `key`
+    pub fn remove_one(&mut self, key: &str, value: &str) -> bool {
+        let found = self
+            .entries(key)
+            .into_iter()
+            .find(|(_, stored)| stored == value);
+        match found {
+            Some((composite, _)) => {
+                self.tree.delete(composite);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // removes every value of `key` and returns how many there were
+    pub fn remove_all(&mut self, key: &str) -> usize {
+        self.tree.remove_range(self.key_range(key))
+    }
+
+    // every (key, value) pair in the range, values of a key in insertion order
+    pub fn scan<R: RangeBounds<String>>(&self, range: R) -> Vec<(String, String)> {
+        let (start, end) = str_bounds(&range);
+        self.tree
+            .scan(self.composite_bounds(start, end))
+            .into_iter()
+            .map(
+                |(composite, value)| match self.codec.decode_key(&composite).as_deref() {
+                    Ok([Value::String(key), _]) => (key.clone(), value),
+                    _ => panic!("Multimap key {:?} does not decode", composite),
+                },
+            )
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn values_keep_insertion_order() {
+        let mut map = MultiMap::new();
+        for i in 0..30 {
+            map.insert(format!("k{}", i % 3), i.to_string());
+        }
+        let expected: Vec<String> = (0..30).step_by(3).map(|i| i.to_string()).collect();
+        assert_eq!(map.get_all("k0"), expected);
+        assert_eq!(map.count("k1"), 10);
+        assert!(map.remove_one("k0", "3"));
+        assert!(!map.remove_one("k0", "3"));
+        assert_eq!(map.get_all("k0")[..2], ["0".to_string(), "6".to_string()]);
+        assert_eq!(map.remove_all("k1"), 10);
+        assert_eq!(map.len(), 19);
+        map.tree.validate().unwrap();
+    }
+
+    #[test]
+    fn any_string_is_a_key() {
+        let mut map = MultiMap::new();
+        let keys = ["", "a", "a\0", "a\0b", "a\u{1}", "ab", "\u{ff}", "é"];
+        for key in keys.iter().rev() {
+            map.insert(key.to_string(), format!("{}1", key));
+            map.insert(key.to_string(), format!("{}2", key));
+        }
+        for key in keys {
+            assert_eq!(map.get_all(key), [format!("{}1", key), format!("{}2", key)]);
+        }
+        let scanned: Vec<String> = map.scan(..).into_iter().map(|(key, _)| key).collect();
+        let mut expected: Vec<String> = keys
+            .iter()
+            .flat_map(|key| [key.to_string(), key.to_string()])
+            .collect();
+        expected.sort();
+        assert_eq!(scanned, expected);
+
+        let range = map.scan((
+            Bound::Excluded("a".to_string()),
+            Bound::Included("ab".to_string()),
+        ));
+        let range: Vec<&str> = range.iter().map(|(key, _)| key.as_str()).collect();
+        assert_eq!(
+            range,
+            ["a\0", "a\0", "a\0b", "a\0b", "a\u{1}", "a\u{1}", "ab", "ab"]
+        );
+        assert_eq!(map.scan("a".to_string().."a\0".to_string()).len(), 2);
+    }
+}
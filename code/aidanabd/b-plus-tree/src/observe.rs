@@ -0,0 +1,359 @@
+// Structural events, for visualizers and teaching tools that want to replay exactly
+// what the tree did. An observer attached to a tree is told about every descent
+// through an internal node, every entry written to or deleted from a leaf, and every
+// split, merge, root change and node removal, in the order they happen.
+//
+// Writes that reach a leaf in bulk, from a WriteBatch or a lazy leaf's buffer, are
+// reported entry by entry when they are applied to the leaf, as if written one at a
+// time in key order; writes waiting in a buffer are not in any leaf yet. Bulk removals
+// report the entries they delete from leaves, except for whole subtrees dropped
+// without being searched, which the compaction removing their nodes reports.
+//
+// Nodes are named by their arena index. `remove_node` fills the hole it leaves with
+// the last node of the arena, so its event says which node moved into the slot, and
+// bulk operations that compact the arena report the old index of every surviving
+// node, so a replay can always keep its picture of the arena in sync. `split_off` and
+// `append` move whole subtrees from one tree to another, which no event describes; a
+// replay has to pick the tree up again from `to_json` after them.
+//
+// The tree merges underfull siblings but never redistributes entries between them,
+// so there is no redistribution event. `JsonTrace` writes the events as JSON Lines.
+
+use std::fmt;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+
+use crate::export::json_string;
+use crate::BPlusTree;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    // a search went from `node` to its child at `position`
+    Descend {
+        node: usize,
+        position: usize,
+        child: usize,
+    },
+    // `key` was written to `leaf` at `position`, replacing an entry for the same key
+    // if `replaced`
+    LeafInsert {
+        leaf: usize,
+        position: usize,
+        key: String,
+        replaced: bool,
+    },
+    LeafDelete {
+        leaf: usize,
+        position: usize,
+        key: String,
+    },
+    // `node` kept its lower half and moved the upper half to the new node `sibling`;
+    // `promoted` went up to the parent
+    Split {
+        node: usize,
+        sibling: usize,
+        promoted: String,
+    },
+    // `left` was merged into its right sibling `right`, pulling `separator` out of the
+    // parent; `left` is removed right after
+    Merge {
+        left: usize,
+        right: usize,
+        separator: String,
+    },
+    // a split of the root made the new node `root` with the two halves as children
+    RootGrow {
+        root: usize,
+    },
+    // the root lost its last separator and its only child `root` took over
+    RootShrink {
+        root: usize,
+    },
+    // `node` left the arena and the node at `moved`, if any, took its index
+    RemoveNode {
+        node: usize,
+        moved: Option<usize>,
+    },
+    // the arena was compacted: the node now at index `i` used to be at `kept[i]`
+    Compact {
+        kept: Vec<usize>,
+    },
+}
+
+impl Event {
+    pub fn to_json(&self) -> String {
+        let mut json = String::new();
+        let field = |json: &mut String, name: &str, value: usize| {
+            json.push_str(&format!(",\"{}\":{}", name, value));
+        };
+        let key = |json: &mut String, name: &str, value: &str| {
+            json.push_str(&format!(",\"{}\":", name));
+            json_string(value, json);
+        };
+        match self {
+            Event::Descend {
+                node,
+                position,
+                child,
+            } => {
+                json.push_str("{\"event\":\"descend\"");
+                field(&mut json, "node", *node);
+                field(&mut json, "position", *position);
+                field(&mut json, "child", *child);
+            }
+            Event::LeafInsert {
+                leaf,
+                position,
+                key: inserted,
+                replaced,
+            } => {
+                json.push_str("{\"event\":\"leaf_insert\"");
+                field(&mut json, "leaf", *leaf);
+                field(&mut json, "position", *position);
+                key(&mut json, "key", inserted);
+                json.push_str(&format!(",\"replaced\":{}", replaced));
+            }
+            Event::LeafDelete {
+                leaf,
+                position,
+                key: deleted,
+            } => {
+                json.push_str("{\"event\":\"leaf_delete\"");
+                field(&mut json, "leaf", *leaf);
+                field(&mut json, "position", *position);
+                key(&mut json, "key", deleted);
+            }
+            Event::Split {
+                node,
+                sibling,
+                promoted,
+            } => {
+                json.push_str("{\"event\":\"split\"");
+                field(&mut json, "node", *node);
+                field(&mut json, "sibling", *sibling);
+                key(&mut json, "promoted", promoted);
+            }
+            Event::Merge {
+                left,
+                right,
+                separator,
+            } => {
+                json.push_str("{\"event\":\"merge\"");
+                field(&mut json, "left", *left);
+                field(&mut json, "right", *right);
+                key(&mut json, "separator", separator);
+            }
+            Event::RootGrow { root } => {
+                json.push_str("{\"event\":\"root_grow\"");
+                field(&mut json, "root", *root);
+            }
+            Event::RootShrink { root } => {
+                json.push_str("{\"event\":\"root_shrink\"");
+                field(&mut json, "root", *root);
+            }
+            Event::RemoveNode { node, moved } => {
+                json.push_str("{\"event\":\"remove_node\"");
+                field(&mut json, "node", *node);
+                match moved {
+                    Some(moved) => field(&mut json, "moved", *moved),
+                    None => json.push_str(",\"moved\":null"),
+                }
+            }
+            Event::Compact { kept } => {
+                json.push_str("{\"event\":\"compact\",\"kept\":[");
+                let kept: Vec<String> = kept.iter().map(|index| index.to_string()).collect();
+                json.push_str(&kept.join(","));
+                json.push(']');
+            }
+        }
+        json.push('}');
+        json
+    }
+}
+
+pub trait Observer: Send + Sync {
+    // called while the tree is in the middle of the operation, so it cannot look at
+    // the tree itself
+    fn on_event(&self, event: &Event);
+}
+
+// keeps every event in memory
+#[derive(Debug, Default)]
+pub struct EventLog {
+    events: Mutex<Vec<Event>>,
+}
+
+impl EventLog {
+    pub fn new() -> Self {
+        EventLog::default()
+    }
+
+    // the events recorded so far, leaving the log empty
+    pub fn take(&self) -> Vec<Event> {
+        std::mem::take(&mut *self.events.lock().unwrap())
+    }
+}
+
+impl Observer for EventLog {
+    fn on_event(&self, event: &Event) {
+        self.events.lock().unwrap().push(event.clone());
+    }
+}
+
+// writes one JSON object per event and line; write errors end the trace
+#[derive(Debug)]
+pub struct JsonTrace<W: Write + Send> {
+    out: Mutex<Option<W>>,
+}
+
+impl<W: Write + Send> JsonTrace<W> {
+    pub fn new(out: W) -> Self {
+        JsonTrace {
+            out: Mutex::new(Some(out)),
+        }
+    }
+
+    // the writer, unless writing to it failed
+    pub fn into_inner(self) -> Option<W> {
+        self.out.into_inner().unwrap()
+    }
+}
+
+impl<W: Write + Send> Observer for JsonTrace<W> {
+    fn on_event(&self, event: &Event) {
+        let mut out = self.out.lock().unwrap();
+        if let Some(writer) = out.as_mut() {
+            if writeln!(writer, "{}", event.to_json()).is_err() {
+                *out = None;
+            }
+        }
+    }
+}
+
+#[derive(Default, Clone)]
+pub(crate) struct ObserverSlot(Option<Arc<dyn Observer>>);
+
+impl fmt::Debug for ObserverSlot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Some(_) => write!(f, "ObserverSlot(attached)"),
+            None => write!(f, "ObserverSlot(none)"),
+        }
+    }
+}
+
+impl ObserverSlot {
+    pub(crate) fn is_attached(&self) -> bool {
+        self.0.is_some()
+    }
+}
+
+impl BPlusTree {
+    // sends every structural event from now on to `observer`, in place of the
+    // previous one
+    pub fn observe(&mut self, observer: Arc<dyn Observer>) {
+        self.observer = ObserverSlot(Some(observer));
+    }
+
+    pub fn stop_observing(&mut self) -> Option<Arc<dyn Observer>> {
+        self.observer.0.take()
+    }
+
+    // events are only built when someone is listening
+    pub(crate) fn emit(&self, event: impl FnOnce() -> Event) {
+        if let Some(ref observer) = self.observer.0 {
+            observer.on_event(&event());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::batch::WriteBatch;
+    use std::collections::BTreeSet;
+
+    // the keys the events say are in the leaves, checking that every write is
+    // consistent with what came before it
+    fn replay(events: &[Event], keys: &mut BTreeSet<String>) {
+        for event in events {
+            match event {
+                Event::LeafInsert { key, replaced, .. } => {
+                    assert_eq!(!keys.insert(key.clone()), *replaced, "{:?}", event)
+                }
+                Event::LeafDelete { key, .. } => assert!(keys.remove(key), "{:?}", event),
+                _ => {}
+            }
+        }
+    }
+
+    fn keys_of(tree: &BPlusTree) -> BTreeSet<String> {
+        tree.scan(..).into_iter().map(|(key, _)| key).collect()
+    }
+
+    #[test]
+    fn every_leaf_write_is_reported() {
+        let log = Arc::new(EventLog::new());
+        let mut tree = BPlusTree::new();
+        tree.observe(log.clone());
+        let mut keys = BTreeSet::new();
+
+        for i in 0..60 {
+            tree.insert(format!("{:03}", i), i.to_string());
+        }
+        tree.insert("007".to_string(), "again".to_string());
+        tree.delete("008".to_string());
+        replay(&log.take(), &mut keys);
+        assert_eq!(keys, keys_of(&tree));
+
+        let mut batch = WriteBatch::new();
+        batch
+            .put("010".to_string(), "batch".to_string())
+            .put("100".to_string(), "batch".to_string())
+            .delete("011".to_string())
+            .delete_range("020".to_string().."030".to_string())
+            .put("025".to_string(), "batch".to_string());
+        tree.apply(batch);
+        replay(&log.take(), &mut keys);
+        assert_eq!(keys, keys_of(&tree));
+
+        tree.set_update_buffer(Some(3));
+        for i in (0..60).step_by(3) {
+            tree.insert(format!("{:03}x", i), String::new());
+            tree.delete(format!("{:03}", i + 1));
+        }
+        tree.flush();
+        replay(&log.take(), &mut keys);
+        assert_eq!(keys, keys_of(&tree));
+
+        tree.retain(|key, _| !key.ends_with('5'));
+        replay(&log.take(), &mut keys);
+        assert_eq!(keys, keys_of(&tree));
+        tree.validate().unwrap();
+    }
+
+    #[test]
+    fn events_as_json() {
+        let event = Event::LeafInsert {
+            leaf: 3,
+            position: 1,
+            key: "a\"b".to_string(),
+            replaced: false,
+        };
+        assert_eq!(
+            event.to_json(),
+            r#"{"event":"leaf_insert","leaf":3,"position":1,"key":"a\"b","replaced":false}"#
+        );
+        let trace = JsonTrace::new(Vec::new());
+        trace.on_event(&Event::RemoveNode {
+            node: 4,
+            moved: None,
+        });
+        trace.on_event(&Event::Compact { kept: vec![0, 2] });
+        assert_eq!(
+            String::from_utf8(trace.into_inner().unwrap()).unwrap(),
+            "{\"event\":\"remove_node\",\"node\":4,\"moved\":null}\n\
+             {\"event\":\"compact\",\"kept\":[0,2]}\n"
+        );
+    }
+}
@@ -0,0 +1,175 @@
+// Order statistics. Every internal node keeps the number of entries below each of its
+// children, so rank, select and range counts descend a single path and add up the
+// counts of the children they skip instead of visiting them. Lazy leaves count their
+// entries with the buffered writes applied, like lookups and scans see them.
+
+use std::cmp::Ordering;
+use std::ops::{Bound, RangeBounds};
+
+use crate::{str_bounds, BPlusTree, NodeValue};
+
+impl BPlusTree {
+    pub(crate) fn subtree_count(&self, index: usize) -> usize {
+        let node = &self.nodes[index];
+        match node.values {
+            NodeValue::Internal(_) => node.counts.iter().sum(),
+            NodeValue::Leaf(_) => node.leaf_entries(&self.key_order).len(),
+        }
+    }
+
+    // updates the counts and aggregate summaries on the path from `index` to the root
+    // after its entries changed
+    pub(crate) fn refresh_ancestors(&mut self, index: usize) {
+        let mut child = index;
+        while let Some(parent) = self.nodes[child].parent {
+            let count = self.subtree_count(child);
+            let summaries = self.subtree_summaries(child);
+            let parent_node = &mut self.nodes[parent];
+            let NodeValue::Internal(ref pointers) = parent_node.values else {
+                panic!("Leaf node is parent");
+            };
+            let position = pointers.iter().position(|p| *p == child).unwrap();
+            parent_node.counts[position] = count;
+            parent_node.summaries[position] = summaries;
+            child = parent;
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.subtree_count(self.root_index)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // the number of keys below `key`, or up to and including it when `inclusive` is set
+    fn count_before(&self, key: &str, inclusive: bool) -> usize {
+        let mut index = self.root_index;
+        let mut count = 0;
+        loop {
+            let node = &self.nodes[index];
+            match node.values {
+                NodeValue::Internal(ref pointers) => {
+                    let position = node.child_position(key, &self.key_order);
+                    count += node.counts[..position].iter().sum::<usize>();
+                    index = pointers[position];
+                }
+                NodeValue::Leaf(_) => {
+                    let entries = node.leaf_entries(&self.key_order);
+                    return count
+                        + entries.partition_point(|(k, _)| {
+                            match self.key_order.cmp_key(*k, key) {
+                                Ordering::Less => true,
+                                Ordering::Equal => inclusive,
+                                Ordering::Greater => false,
+                            }
+                        });
+                }
+            }
+        }
+    }
+
+    // the number of keys smaller than `key`, whether or not `key` is present
+    pub fn rank(&self, key: &str) -> usize {
+        self.count_before(key, false)
+    }
+
+    // the entry with exactly `position` smaller keys
+    pub fn select(&self, position: usize) -> Option<(String, String)> {
+        let mut index = self.root_index;
+        let mut remaining = position;
+        loop {
+            let node = &self.nodes[index];
+            match node.values {
+                NodeValue::Internal(ref pointers) => {
+                    let mut child = 0;
+                    while remaining >= node.counts[child] {
+                        remaining -= node.counts[child];
+                        child += 1;
+                        if child == pointers.len() {
+                            return None;
+                        }
+                    }
+                    index = pointers[child];
+                }
+                NodeValue::Leaf(_) => {
+                    return node
+                        .leaf_entries(&self.key_order)
+                        .get(remaining)
+                        .map(|(key, value)| (key.to_string(), self.overflow.read(*value)));
+                }
+            }
+        }
+    }
+
+    pub fn count<R: RangeBounds<String>>(&self, range: R) -> usize {
+        let (start, end) = str_bounds(&range);
+        let below_start = match start {
+            Bound::Included(key) => self.count_before(key, false),
+            Bound::Excluded(key) => self.count_before(key, true),
+            Bound::Unbounded => 0,
+        };
+        let up_to_end = match end {
+            Bound::Included(key) => self.count_before(key, true),
+            Bound::Excluded(key) => self.count_before(key, false),
+            Bound::Unbounded => self.len(),
+        };
+        up_to_end.saturating_sub(below_start)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(i: usize) -> String {
+        format!("{:04}", i)
+    }
+
+    // the even keys below 2000, some of them still in lazy buffers
+    fn evens() -> BPlusTree {
+        let mut tree = BPlusTree::with_update_buffer(4);
+        for i in (0..2000).rev() {
+            tree.insert(key(i), i.to_string());
+        }
+        for i in (1..2000).step_by(2) {
+            tree.delete(key(i));
+        }
+        tree
+    }
+
+    #[test]
+    fn rank_and_select_are_inverse() {
+        let tree = evens();
+        assert!(tree.pending_writes() > 0);
+        assert_eq!(tree.len(), 1000);
+        for position in 0..1000 {
+            let (key, value) = tree.select(position).unwrap();
+            assert_eq!(value, (position * 2).to_string());
+            assert_eq!(tree.rank(&key), position);
+        }
+        assert_eq!(tree.select(1000), None);
+        // absent keys rank where they would be inserted
+        assert_eq!(tree.rank(""), 0);
+        assert_eq!(tree.rank(&key(3)), 2);
+        assert_eq!(tree.rank("9999"), 1000);
+    }
+
+    #[test]
+    fn counts_respect_bounds() {
+        let tree = evens();
+        assert_eq!(tree.count(..), 1000);
+        assert_eq!(tree.count(key(10)..key(20)), 5);
+        assert_eq!(tree.count(key(10)..=key(20)), 6);
+        assert_eq!(tree.count(key(11)..=key(19)), 4);
+        assert_eq!(
+            tree.count((Bound::Excluded(key(10)), Bound::Included(key(20)))),
+            5
+        );
+        assert_eq!(tree.count(..key(100)), 50);
+        assert_eq!(tree.count(key(1900)..), 50);
+        assert_eq!(tree.count(key(20)..key(10)), 0);
+        assert_eq!(BPlusTree::new().count(..), 0);
+    }
+}
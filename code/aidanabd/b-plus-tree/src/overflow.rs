@@ -0,0 +1,407 @@
+// Overflow pages for large values. A value longer than the tree's overflow threshold is
+// not stored in its leaf: it is cut into OVERFLOW_PAGE sized pages chained through
+// `next`, and the leaf only keeps the first page and the length. Leaves then stay
+// small whatever the values are, and splits keep moving a handful of references.
+//
+// Pages live in an arena next to the nodes and freed pages are reused. `get` and
+// `scan` reassemble values transparently; `insert_from_reader` and `value_reader`
+// stream a value into and out of its pages without holding all of it in memory.
+// Values waiting in a lazy leaf's update buffer stay inline until the buffer is
+// reconciled.
+
+use std::io::{self, Read};
+use std::mem::size_of;
+
+use crate::{BPlusTree, NodeValue};
+
+const OVERFLOW_PAGE: usize = 4096;
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum LeafValue {
+    Inline(String),
+    Overflow { first_page: usize, length: usize },
+}
+
+// a leaf value or a buffered one, borrowed from its node
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum ValueRef<'a> {
+    Inline(&'a str),
+    Overflow { first_page: usize, length: usize },
+}
+
+impl LeafValue {
+    pub(crate) fn as_ref(&self) -> ValueRef<'_> {
+        match self {
+            LeafValue::Inline(value) => ValueRef::Inline(value),
+            LeafValue::Overflow { first_page, length } => ValueRef::Overflow {
+                first_page: *first_page,
+                length: *length,
+            },
+        }
+    }
+}
+
+#[derive(Debug)]
+struct OverflowPage {
+    data: Vec<u8>,
+    next: Option<usize>,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct OverflowStore {
+    pages: Vec<OverflowPage>,
+    free: Vec<usize>,
+    // values longer than this many bytes go to overflow pages
+    pub(crate) threshold: Option<usize>,
+}
+
+impl OverflowStore {
+    fn allocate(&mut self, data: Vec<u8>) -> usize {
+        let page = OverflowPage { data, next: None };
+        match self.free.pop() {
+            Some(index) => {
+                self.pages[index] = page;
+                index
+            }
+            None => {
+                self.pages.push(page);
+                self.pages.len() - 1
+            }
+        }
+    }
+
+    // appends bytes to a chain, filling its last page first; returns the new last page
+    fn extend(
+        &mut self,
+        first: &mut Option<usize>,
+        mut last: Option<usize>,
+        mut bytes: &[u8],
+    ) -> Option<usize> {
+        while !bytes.is_empty() {
+            if let Some(index) = last {
+                let page = &mut self.pages[index];
+                let room = OVERFLOW_PAGE - page.data.len();
+                if room > 0 {
+                    let taken = room.min(bytes.len());
+                    page.data.extend_from_slice(&bytes[..taken]);
+                    bytes = &bytes[taken..];
+                    continue;
+                }
+            }
+            let page = self.allocate(Vec::with_capacity(OVERFLOW_PAGE));
+            match last {
+                Some(index) => self.pages[index].next = Some(page),
+                None => *first = Some(page),
+            }
+            last = Some(page);
+        }
+        last
+    }
+
+    pub(crate) fn store(&mut self, value: String) -> LeafValue {
+        if self
+            .threshold
+            .is_none_or(|threshold| value.len() <= threshold)
+        {
+            return LeafValue::Inline(value);
+        }
+        let mut first = None;
+        self.extend(&mut first, None, value.as_bytes());
+        LeafValue::Overflow {
+            first_page: first.unwrap(),
+            length: value.len(),
+        }
+    }
+
+    pub(crate) fn release(&mut self, value: LeafValue) {
+        if let LeafValue::Overflow { first_page, .. } = value {
+            self.release_chain(Some(first_page));
+        }
+    }
+
+    fn release_chain(&mut self, mut page: Option<usize>) {
+        while let Some(index) = page {
+            page = self.pages[index].next.take();
+            self.pages[index].data = Vec::new();
+            self.free.push(index);
+        }
+    }
+
+    // moves the pages of a value into another tree's store, page by page
+    pub(crate) fn transfer(&mut self, value: LeafValue, to: &mut OverflowStore) -> LeafValue {
+        let LeafValue::Overflow { first_page, length } = value else {
+            return value;
+        };
+        let mut first = None;
+        let mut last = None;
+        let mut page = Some(first_page);
+        while let Some(index) = page {
+            last = to.extend(&mut first, last, &self.pages[index].data);
+            page = self.pages[index].next;
+        }
+        self.release_chain(Some(first_page));
+        LeafValue::Overflow {
+            first_page: first.unwrap(),
+            length,
+        }
+    }
+
+    pub(crate) fn read(&self, value: ValueRef<'_>) -> String {
+        match value {
+            ValueRef::Inline(value) => value.to_string(),
+            ValueRef::Overflow { length, .. } => {
+                let mut bytes = Vec::with_capacity(length);
+                ValueReader::new(self, value)
+                    .read_to_end(&mut bytes)
+                    .expect("reading overflow pages cannot fail");
+                String::from_utf8(bytes).expect("overflow pages hold a valid string")
+            }
+        }
+    }
+
+    // bytes of the pages, used and allocated, freed pages counting as allocated only
+    pub(crate) fn heap_bytes(&self) -> (usize, usize) {
+        let mut used = self.pages.len() * size_of::<OverflowPage>();
+        let mut allocated = self.pages.capacity() * size_of::<OverflowPage>()
+            + self.free.capacity() * size_of::<usize>();
+        used += self.free.len() * size_of::<usize>();
+        for page in self.pages.iter() {
+            used += page.data.len();
+            allocated += page.data.capacity();
+        }
+        (used, allocated)
+    }
+
+    pub(crate) fn pages_in_use(&self) -> usize {
+        self.pages.len() - self.free.len()
+    }
+}
+
+// reads a value page by page
+#[derive(Debug)]
+pub struct ValueReader<'a> {
+    store: &'a OverflowStore,
+    current: &'a [u8],
+    next: Option<usize>,
+}
+
+impl<'a> ValueReader<'a> {
+    fn new(store: &'a OverflowStore, value: ValueRef<'a>) -> Self {
+        let (current, next) = match value {
+            ValueRef::Inline(value) => (value.as_bytes(), None),
+            ValueRef::Overflow { first_page, .. } => (&[][..], Some(first_page)),
+        };
+        ValueReader {
+            store,
+            current,
+            next,
+        }
+    }
+}
+
+impl Read for ValueReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.current.is_empty() {
+            let Some(index) = self.next else {
+                return Ok(0);
+            };
+            let page = &self.store.pages[index];
+            self.current = &page.data;
+            self.next = page.next;
+        }
+        let length = buf.len().min(self.current.len());
+        buf[..length].copy_from_slice(&self.current[..length]);
+        self.current = &self.current[length..];
+        Ok(length)
+    }
+}
+
+impl BPlusTree {
+    // stores values longer than `threshold` bytes in overflow pages
+    pub fn with_overflow_threshold(threshold: usize) -> Self {
+        let mut tree = BPlusTree::new();
+        tree.overflow.threshold = Some(threshold);
+        tree
+    }
+
+    // applies to values written from now on; stored values stay where they are
+    pub fn set_overflow_threshold(&mut self, threshold: Option<usize>) {
+        self.overflow.threshold = threshold;
+    }
+
+    pub fn overflow_pages(&self) -> usize {
+        self.overflow.pages_in_use()
+    }
+
+    pub fn value_reader(&self, key: &str) -> Option<ValueReader<'_>> {
+        let value = self.value_ref(key)?;
+        Some(ValueReader::new(&self.overflow, value))
+    }
+
+    fn value_ref(&self, key: &str) -> Option<ValueRef<'_>> {
+        if self.is_expired(key) {
+            return None;
+        }
+        let node = &self.nodes[self.get_node_for_key(key)];
+        if let Ok(index) = node
+            .buffer
+            .binary_search_by(|(pending, _)| self.key_order.cmp(pending, key))
+        {
+            return node.buffer[index]
+                .1
+                .leaf_value()
+                .map(|value| ValueRef::Inline(value));
+        }
+        let NodeValue::Leaf(ref values) = node.values else {
+            panic!("Search yielded internal node");
+        };
+        node.keys
+            .binary_search(key, &self.key_order)
+            .ok()
+            .map(|index| values[index].as_ref())
+    }
+
+    // writes a value straight from `reader` into overflow pages, checking that it is
+    // valid UTF-8 on the way
+    pub fn insert_from_reader<R: Read>(&mut self, key: String, mut reader: R) -> io::Result<()> {
+        let mut first = None;
+        let mut last = None;
+        let mut length = 0;
+        // the start of a character cut off at the end of the previous read
+        let mut carry = Vec::new();
+        let mut chunk = vec![0u8; OVERFLOW_PAGE];
+
+        let result = loop {
+            let read = match reader.read(&mut chunk) {
+                Ok(0) if carry.is_empty() => break Ok(()),
+                Ok(0) => {
+                    break Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "truncated UTF-8",
+                    ))
+                }
+                Ok(read) => read,
+                Err(error) if error.kind() == io::ErrorKind::Interrupted => continue,
+                Err(error) => break Err(error),
+            };
+            carry.extend_from_slice(&chunk[..read]);
+            let valid = match std::str::from_utf8(&carry) {
+                Ok(valid) => valid.len(),
+                Err(error) if error.error_len().is_none() => error.valid_up_to(),
+                Err(error) => break Err(io::Error::new(io::ErrorKind::InvalidData, error)),
+            };
+            last = self.overflow.extend(&mut first, last, &carry[..valid]);
+            length += valid;
+            carry.drain(..valid);
+        };
+        if let Err(error) = result {
+            self.overflow.release_chain(first);
+            return Err(error);
+        }
+
+        self.forget_deadline(&key);
+        let value = match first {
+            Some(first_page) => LeafValue::Overflow { first_page, length },
+            None => LeafValue::Inline(String::new()),
+        };
+        self.insert_value(key, value);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // hands out at most `step` bytes per read, cutting characters apart
+    struct Trickle<'a> {
+        data: &'a [u8],
+        step: usize,
+    }
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let length = self.step.min(buf.len()).min(self.data.len());
+            buf[..length].copy_from_slice(&self.data[..length]);
+            self.data = &self.data[length..];
+            Ok(length)
+        }
+    }
+
+    fn large(seed: usize) -> String {
+        (0..3 * OVERFLOW_PAGE + seed)
+            .map(|i| ['a', 'é', '日', '🌲'][(i + seed) % 4])
+            .collect()
+    }
+
+    fn read_back(tree: &BPlusTree, key: &str) -> String {
+        let mut value = String::new();
+        tree.value_reader(key)
+            .unwrap()
+            .read_to_string(&mut value)
+            .unwrap();
+        value
+    }
+
+    #[test]
+    fn large_values_round_trip_through_pages() {
+        let mut tree = BPlusTree::with_overflow_threshold(64);
+        for i in 0..20 {
+            tree.insert(format!("{:02}", i), large(i));
+        }
+        tree.insert("small".to_string(), "inline".to_string());
+        assert!(tree.overflow_pages() >= 20 * 3);
+
+        for i in 0..20 {
+            let key = format!("{:02}", i);
+            assert_eq!(tree.get(&key), Some(large(i)));
+            assert_eq!(read_back(&tree, &key), large(i));
+        }
+        assert_eq!(read_back(&tree, "small"), "inline");
+        assert!(tree.value_reader("missing").is_none());
+
+        // overwritten and deleted values give their pages back
+        for i in 0..20 {
+            if i % 2 == 0 {
+                tree.delete(format!("{:02}", i));
+            } else {
+                tree.insert(format!("{:02}", i), "short".to_string());
+            }
+        }
+        assert_eq!(tree.overflow_pages(), 0);
+        tree.validate().unwrap();
+    }
+
+    #[test]
+    fn values_stream_in_from_readers() {
+        let mut tree = BPlusTree::new();
+        let value = large(7);
+        for step in [1, 3, OVERFLOW_PAGE + 1] {
+            let reader = Trickle {
+                data: value.as_bytes(),
+                step,
+            };
+            tree.insert_from_reader(step.to_string(), reader).unwrap();
+            assert_eq!(read_back(&tree, &step.to_string()), value);
+        }
+        tree.insert_from_reader("empty".to_string(), io::empty())
+            .unwrap();
+        assert_eq!(tree.get("empty"), Some(String::new()));
+
+        // invalid input leaves neither an entry nor pages behind
+        let pages = tree.overflow_pages();
+        let mut invalid = value.clone().into_bytes();
+        invalid[2 * OVERFLOW_PAGE] = 0xff;
+        let error = tree
+            .insert_from_reader("invalid".to_string(), &invalid[..])
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        let truncated = &value.as_bytes()[..2];
+        assert!(tree
+            .insert_from_reader("truncated".to_string(), truncated)
+            .is_err());
+        assert_eq!(tree.get("invalid"), None);
+        assert_eq!(tree.get("truncated"), None);
+        assert_eq!(tree.overflow_pages(), pages);
+    }
+}
@@ -0,0 +1,484 @@
+// Splitting and joining whole trees, for resharding. `split_off` cuts the tree along
+// the search path of a key: every node on the path is split in two at the key, the
+// halves left of the path stay and the halves right of it, with all the subtrees
+// hanging off them, become a tree of their own. No entry is searched or re-inserted,
+// only the O(height) nodes on the path are split, and halves the cut leaves empty are
+// dropped.
+//
+// `append` does the reverse for a tree whose keys all lie after (or all before) ours:
+// the shorter tree is grafted as the last (or first) child of the node of the taller
+// one at the matching height, which then splits like after any insert. Trees whose
+// key ranges interleave fall back to a batch of puts.
+//
+// Either way, merges are checked along the seam afterwards. Nodes move between the
+// arenas of the two trees, and so do the overflow pages of their values.
+
+use std::cmp::Ordering;
+
+use crate::batch::WriteBatch;
+use crate::compare::KeyOrder;
+use crate::keys::NodeKeys;
+use crate::observe::Event;
+use crate::overflow::LeafValue;
+use crate::{ArrayNode, BPlusTree, Message, NodeValue};
+
+// splits off the pending writes for `key` and after
+fn split_buffer(
+    buffer: &mut Vec<(String, Message)>,
+    key: &str,
+    order: &KeyOrder,
+) -> Vec<(String, Message)> {
+    let at = buffer.partition_point(|(pending, _)| order.cmp(pending, key) == Ordering::Less);
+    buffer.split_off(at)
+}
+
+impl BPlusTree {
+    // an empty tree with the comparator, configuration and aggregates of this one
+    pub(crate) fn empty_like(&self) -> BPlusTree {
+        let mut tree = BPlusTree::new();
+        tree.buffer_capacity = self.buffer_capacity;
+        tree.aggregates = self.aggregates.clone();
+        tree.overflow.threshold = self.overflow.threshold;
+        tree.key_order = self.key_order.clone();
+        tree.fanout = self.fanout;
+        tree.nodes = vec![ArrayNode::new(self.fanout)];
+        tree
+    }
+
+    pub(crate) fn height(&self, mut index: usize) -> usize {
+        let mut height = 1;
+        while let NodeValue::Internal(ref children) = self.nodes[index].values {
+            index = children[0];
+            height += 1;
+        }
+        height
+    }
+
+    // moves the subtree below `index` out of the arena, renumbered from 0 for the
+    // root, and marks the nodes it leaves behind for removal
+    fn take_subtree(&mut self, index: usize, removed: &mut Vec<usize>) -> Vec<ArrayNode> {
+        let mut taken = vec![index];
+        let mut next = 0;
+        while next < taken.len() {
+            if let NodeValue::Internal(ref children) = self.nodes[taken[next]].values {
+                taken.extend(children.iter().copied());
+            }
+            next += 1;
+        }
+        let mut renumbered = vec![0; self.nodes.len()];
+        for (position, index) in taken.iter().enumerate() {
+            renumbered[*index] = position;
+        }
+
+        let mut nodes: Vec<ArrayNode> = taken
+            .iter()
+            .map(|index| std::mem::replace(&mut self.nodes[*index], ArrayNode::new(self.fanout)))
+            .collect();
+        for node in nodes.iter_mut() {
+            node.parent = node.parent.map(|parent| renumbered[parent]);
+            if let NodeValue::Internal(ref mut pointers) = node.values {
+                for pointer in pointers.iter_mut() {
+                    *pointer = renumbered[*pointer];
+                }
+            }
+        }
+        nodes[0].parent = None;
+        removed.extend(taken);
+        nodes
+    }
+
+    // moves the overflow pages of the values in `nodes` from `from`'s store into ours
+    fn adopt_values(&mut self, nodes: &mut [ArrayNode], from: &mut BPlusTree) {
+        for node in nodes.iter_mut() {
+            if let NodeValue::Leaf(ref mut values) = node.values {
+                for value in values.iter_mut() {
+                    let moved = std::mem::replace(value, LeafValue::Inline(String::new()));
+                    *value = from.overflow.transfer(moved, &mut self.overflow);
+                }
+            }
+        }
+    }
+
+    // moves every entry at or after `key` into a new tree, which shares the comparator,
+    // the configuration and the registered aggregates of this one
+    pub fn split_off(&mut self, key: &str) -> BPlusTree {
+        let mut path = Vec::new();
+        let mut index = self.root_index;
+        while let NodeValue::Internal(ref children) = self.nodes[index].values {
+            let position = self.nodes[index].child_position(key, &self.key_order);
+            path.push((index, position));
+            index = children[position];
+        }
+
+        let leaf = &mut self.nodes[index];
+        let at = match leaf.keys.binary_search(key, &self.key_order) {
+            Ok(at) | Err(at) => at,
+        };
+        let NodeValue::Leaf(ref mut values) = leaf.values else {
+            panic!("Search yielded internal node");
+        };
+        let right_leaf = ArrayNode {
+            parent: None,
+            keys: leaf.keys.split_off(at),
+            values: NodeValue::Leaf(values.split_off(at)),
+            buffer: split_buffer(&mut leaf.buffer, key, &self.key_order),
+            counts: Vec::new(),
+            summaries: Vec::new(),
+        };
+        self.nodes.push(right_leaf);
+
+        // the halves of the node cut one level down; the left one keeps its index
+        let mut left = index;
+        let mut right = self.nodes.len() - 1;
+        let mut removed = Vec::new();
+        for (index, position) in path.into_iter().rev() {
+            // halves left empty by the cut are dropped rather than kept as empty nodes
+            let left_count = self.subtree_count(left);
+            let right_count = self.subtree_count(right);
+            let left_summaries = self.subtree_summaries(left);
+            let right_summaries = self.subtree_summaries(right);
+            if left_count == 0 {
+                self.drop_subtree(left, &mut removed);
+            }
+            if right_count == 0 {
+                self.drop_subtree(right, &mut removed);
+            }
+
+            let node = &mut self.nodes[index];
+            let NodeValue::Internal(ref mut pointers) = node.values else {
+                panic!("Leaf node is parent");
+            };
+            let mut sibling_pointers = pointers.split_off(position + 1);
+            let mut sibling_keys = node.keys.split_off(position);
+            let mut sibling_counts = node.counts.split_off(position + 1);
+            let mut sibling_summaries = node.summaries.split_off(position + 1);
+            if left_count == 0 {
+                pointers.pop();
+                node.counts.pop();
+                node.summaries.pop();
+                if !node.keys.is_empty() {
+                    node.keys.remove(node.keys.len() - 1);
+                }
+            } else {
+                node.counts[position] = left_count;
+                node.summaries[position] = left_summaries;
+            }
+            if right_count == 0 {
+                if !sibling_keys.is_empty() {
+                    sibling_keys.remove(0);
+                }
+            } else {
+                sibling_pointers.insert(0, right);
+                sibling_counts.insert(0, right_count);
+                sibling_summaries.insert(0, right_summaries);
+            }
+
+            let sibling = ArrayNode {
+                parent: None,
+                keys: sibling_keys,
+                values: NodeValue::Internal(sibling_pointers.clone()),
+                buffer: split_buffer(&mut node.buffer, key, &self.key_order),
+                counts: sibling_counts,
+                summaries: sibling_summaries,
+            };
+            self.nodes.push(sibling);
+            let sibling_index = self.nodes.len() - 1;
+            for pointer in sibling_pointers {
+                self.nodes[pointer].parent = Some(sibling_index);
+            }
+            left = index;
+            right = sibling_index;
+        }
+
+        let mut other = self.empty_like();
+        other.expiry = self.split_off_deadlines(key);
+        if self.subtree_count(right) == 0 {
+            self.drop_subtree(right, &mut removed);
+        } else {
+            let mut nodes = self.take_subtree(right, &mut removed);
+            other.adopt_values(&mut nodes, self);
+            other.nodes = nodes;
+            other.root_index = 0;
+        }
+        if self.subtree_count(left) == 0 {
+            self.drop_subtree(left, &mut removed);
+            self.nodes.push(ArrayNode::new(self.fanout));
+            self.root_index = self.nodes.len() - 1;
+        }
+
+        self.hand_over_root(&mut removed);
+        self.compact_nodes(removed);
+        // the cut ran down our rightmost path and the other tree's leftmost one
+        self.rebalance_path(key);
+        let mut other_removed = Vec::new();
+        other.hand_over_root(&mut other_removed);
+        other.compact_nodes(other_removed);
+        other.rebalance_path("");
+        other
+    }
+
+    // moves every entry of `other` into this tree and leaves `other` empty; keys
+    // present in both end up with the value from `other`
+    pub fn append(&mut self, other: &mut BPlusTree) {
+        assert_eq!(
+            self.comparator_name(),
+            other.comparator_name(),
+            "cannot append a tree ordered by another comparator"
+        );
+        let empty = other.empty_like();
+        let mut other = std::mem::replace(other, empty);
+        if other.is_empty() {
+            return;
+        }
+        // the values from `other` replace ours along with our deadlines, and its own
+        // deadlines are taken over once its entries are in, since writing them one by
+        // one clears deadlines
+        if self.expiry.is_some() {
+            for (key, _) in other.scan(..) {
+                self.forget_deadline(&key);
+            }
+        }
+        let deadlines = other.expiry.take();
+
+        // keys deleted by pending lazy writes still sit in their leaves and could end
+        // up on the wrong side of the seam
+        self.flush();
+        other.flush();
+        self.graft(other);
+        self.append_deadlines(deadlines);
+    }
+
+    // moves the entries of a flushed, non-empty `other` into this tree
+    fn graft(&mut self, mut other: BPlusTree) {
+        let order = self.key_order.clone();
+        let first_and_last = |tree: &BPlusTree| {
+            let first = tree.select(0).unwrap().0;
+            let last = tree.select(tree.len() - 1).unwrap().0;
+            (first, last)
+        };
+        let (other_first, other_last) = first_and_last(&other);
+        // the separator between the trees, and whether `other` goes after this one
+        let seam = if self.fanout != other.fanout {
+            // nodes sized for another fanout cannot be grafted, and a batch would pile
+            // all of them into a leaf of an empty tree before splitting it
+            for (key, value) in other.scan(..) {
+                self.insert(key, value);
+            }
+            return;
+        } else if self.is_empty() {
+            None
+        } else {
+            let (first, last) = first_and_last(self);
+            if order.cmp(&last, &other_first) == Ordering::Less {
+                Some((order.separator(&last, &other_first), true))
+            } else if order.cmp(&other_last, &first) == Ordering::Less {
+                Some((order.separator(&other_last, &first), false))
+            } else {
+                // interleaved key ranges cannot be grafted
+                let mut batch = WriteBatch::new();
+                for (key, value) in other.scan(..) {
+                    batch.put(key, value);
+                }
+                self.apply(batch);
+                return;
+            }
+        };
+
+        let offset = self.nodes.len();
+        let mut nodes = std::mem::take(&mut other.nodes);
+        self.adopt_values(&mut nodes, &mut other);
+        for node in nodes.iter_mut() {
+            node.parent = node.parent.map(|parent| parent + offset);
+            if let NodeValue::Internal(ref mut pointers) = node.values {
+                for pointer in pointers.iter_mut() {
+                    *pointer += offset;
+                }
+            }
+        }
+        self.nodes.extend(nodes);
+        let grafted = other.root_index + offset;
+        if !self.aggregates.shared_with(&other.aggregates) {
+            self.resummarize(grafted);
+        }
+
+        match seam {
+            None => {
+                let mut removed = Vec::new();
+                self.drop_subtree(self.root_index, &mut removed);
+                self.root_index = grafted;
+                self.compact_nodes(removed);
+            }
+            Some((separator, true)) => self.join(self.root_index, grafted, separator),
+            Some((separator, false)) => self.join(grafted, self.root_index, separator),
+        }
+    }
+
+    // joins the trees rooted at `left` and `right`, whose keys are separated by
+    // `separator`, into one rooted tree
+    fn join(&mut self, left: usize, right: usize, separator: String) {
+        let left_height = self.height(left);
+        let right_height = self.height(right);
+        match left_height.cmp(&right_height) {
+            Ordering::Equal => {
+                let mut keys = NodeKeys::with_capacity(1);
+                keys.push(separator.clone());
+                self.nodes.push(ArrayNode {
+                    parent: None,
+                    keys,
+                    values: NodeValue::Internal(vec![left, right]),
+                    buffer: Vec::new(),
+                    counts: vec![self.subtree_count(left), self.subtree_count(right)],
+                    summaries: vec![self.subtree_summaries(left), self.subtree_summaries(right)],
+                });
+                self.root_index = self.nodes.len() - 1;
+                self.nodes[left].parent = Some(self.root_index);
+                self.nodes[right].parent = Some(self.root_index);
+                let root = self.root_index;
+                self.emit(|| Event::RootGrow { root });
+            }
+            Ordering::Greater => {
+                // the node on the right edge whose children are as tall as `right`
+                let mut attach = left;
+                for _ in right_height + 1..left_height {
+                    let NodeValue::Internal(ref children) = self.nodes[attach].values else {
+                        panic!("Leaf node is parent");
+                    };
+                    attach = *children.last().unwrap();
+                }
+                let count = self.subtree_count(right);
+                let summaries = self.subtree_summaries(right);
+                let node = &mut self.nodes[attach];
+                let NodeValue::Internal(ref mut pointers) = node.values else {
+                    panic!("Leaf node is parent");
+                };
+                pointers.push(right);
+                node.keys.push(separator.clone());
+                node.counts.push(count);
+                node.summaries.push(summaries);
+                self.nodes[right].parent = Some(attach);
+                self.root_index = left;
+                self.refresh_ancestors(attach);
+                self.check_split(attach);
+            }
+            Ordering::Less => {
+                let mut attach = right;
+                for _ in left_height + 1..right_height {
+                    let NodeValue::Internal(ref children) = self.nodes[attach].values else {
+                        panic!("Leaf node is parent");
+                    };
+                    attach = children[0];
+                }
+                let count = self.subtree_count(left);
+                let summaries = self.subtree_summaries(left);
+                let node = &mut self.nodes[attach];
+                let NodeValue::Internal(ref mut pointers) = node.values else {
+                    panic!("Leaf node is parent");
+                };
+                pointers.insert(0, left);
+                node.keys.insert(0, separator.clone());
+                node.counts.insert(0, count);
+                node.summaries.insert(0, summaries);
+                self.nodes[left].parent = Some(attach);
+                self.root_index = right;
+                self.refresh_ancestors(attach);
+                self.check_split(attach);
+            }
+        }
+        self.rebalance_path(&separator);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(i: usize) -> String {
+        format!("{:05}", i)
+    }
+
+    fn tree_of(keys: impl Iterator<Item = usize>) -> BPlusTree {
+        let mut tree = BPlusTree::with_overflow_threshold(8);
+        for i in keys {
+            // every tenth value lives in overflow pages, which move with their nodes
+            let value = if i % 10 == 0 {
+                format!("a long value for {}", i)
+            } else {
+                i.to_string()
+            };
+            tree.insert(key(i), value);
+        }
+        tree
+    }
+
+    fn entries(keys: impl Iterator<Item = usize>) -> Vec<(String, String)> {
+        tree_of(keys).scan(..)
+    }
+
+    #[test]
+    fn split_off_cuts_at_every_key() {
+        for at in [0, 1, 17, 500, 998, 999, 1000, 5000] {
+            let mut left = tree_of(0..1000);
+            let right = left.split_off(&key(at));
+            left.validate().unwrap();
+            right.validate().unwrap();
+            assert_eq!(left.scan(..), entries(0..at.min(1000)));
+            assert_eq!(right.scan(..), entries(at.min(1000)..1000));
+            assert_eq!(
+                left.overflow_pages() + right.overflow_pages(),
+                tree_of(0..1000).overflow_pages()
+            );
+        }
+    }
+
+    #[test]
+    fn append_joins_trees_of_any_height() {
+        let cases = [(0..1000, 1000..1003), (0..3, 3..1000), (500..1000, 0..500)];
+        for (ours, theirs) in cases {
+            let mut tree = tree_of(ours.clone());
+            let mut other = tree_of(theirs.clone());
+            tree.append(&mut other);
+            assert!(other.is_empty());
+            tree.validate().unwrap();
+            assert_eq!(tree.scan(..), entries(ours.chain(theirs)));
+        }
+
+        // interleaved keys, with `other` winning on the keys both hold
+        let mut tree = tree_of((0..1000).step_by(2));
+        let mut other = tree_of((0..1000).step_by(3));
+        for i in (0..1000).step_by(3) {
+            other.insert(key(i), "other".to_string());
+        }
+        tree.append(&mut other);
+        tree.validate().unwrap();
+        assert_eq!(tree.len(), 667);
+        assert_eq!(tree.get(&key(6)), Some("other".to_string()));
+        assert_eq!(tree.get(&key(4)), Some("4".to_string()));
+
+        let mut empty = BPlusTree::new();
+        empty.append(&mut tree);
+        empty.validate().unwrap();
+        assert_eq!(empty.len(), 667);
+    }
+
+    #[test]
+    fn split_and_append_round_trip() {
+        let mut tree = BPlusTree::with_update_buffer(4);
+        for i in (0..2000).rev() {
+            tree.insert(key(i), i.to_string());
+        }
+        let mut right = tree.split_off(&key(1234));
+        let mut middle = tree.split_off(&key(600));
+        right.validate().unwrap();
+        middle.validate().unwrap();
+        middle.append(&mut right);
+        tree.append(&mut middle);
+        tree.validate().unwrap();
+        assert_eq!(tree.len(), 2000);
+        assert_eq!(
+            tree.scan(..),
+            (0..2000)
+                .map(|i| (key(i), i.to_string()))
+                .collect::<Vec<_>>()
+        );
+    }
+}
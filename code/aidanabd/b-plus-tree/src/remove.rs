@@ -0,0 +1,416 @@
+// Bulk removal. `delete` descends once per key and checks for merges every time, so
+// purging a large range costs a descent and a cascade of merges per key.
+// `remove_range` instead walks down the two boundaries of the range: a child lying
+// entirely inside the range is dropped with its whole subtree without searching it,
+// the boundary leaves are trimmed, and the nodes on the boundary paths have their
+// counts and summaries recomputed on the way back up. `retain` is the same pass with
+// a predicate deciding per entry, so it visits every leaf once.
+//
+// Dropped nodes are cut out of the arena in one compaction instead of one
+// `remove_node` each, and merges are only checked afterwards, along the paths to the
+// nodes that lost entries or children.
+
+use std::ops::RangeBounds;
+
+use crate::aggregate::covers;
+use crate::keys::{Key, NodeKeys};
+use crate::observe::Event;
+use crate::{str_bounds, ArrayNode, BPlusTree, NodeValue};
+
+// where a child subtree stands relative to what is being removed
+enum Coverage {
+    Outside,
+    Inside,
+    Partial,
+}
+
+type Classify<'a> = &'a dyn Fn(&ArrayNode, usize, Option<Key<'_>>, Option<Key<'_>>) -> Coverage;
+
+struct Pruning<'a> {
+    // the coverage of the child at a position, given the keys it can hold
+    classify: Classify<'a>,
+    // whether an entry of a partially covered leaf stays
+    keep: &'a mut dyn FnMut(&str, &str) -> bool,
+    // nodes to cut out of the arena
+    removed: Vec<usize>,
+    // keys leading to the nodes that lost entries or children
+    probes: Vec<String>,
+    // the first keys of leaves that outgrew a node when their buffer was applied
+    oversized: Vec<String>,
+}
+
+impl BPlusTree {
+    // removes every entry in the range and returns how many there were
+    pub fn remove_range<R: RangeBounds<String>>(&mut self, range: R) -> usize {
+        self.forget_deadlines((range.start_bound().cloned(), range.end_bound().cloned()));
+        let (start, end) = str_bounds(&range);
+        let order = self.key_order.clone();
+        let classify: Classify = &|node, position, lower, upper| {
+            if !node.child_overlaps(position, start, end, &order) {
+                Coverage::Outside
+            } else if covers(lower, upper, start, end, &order) {
+                Coverage::Inside
+            } else {
+                Coverage::Partial
+            }
+        };
+        let mut keep = |key: &str, _: &str| {
+            !(order.after_start(Key::full(key), start) && order.before_end(Key::full(key), end))
+        };
+
+        let before = self.len();
+        self.prune_tree(Pruning {
+            classify,
+            keep: &mut keep,
+            removed: Vec::new(),
+            probes: Vec::new(),
+            oversized: Vec::new(),
+        });
+        before - self.len()
+    }
+
+    // keeps only the entries for which `keep(key, value)` holds
+    pub fn retain<F: FnMut(&str, &str) -> bool>(&mut self, mut keep: F) {
+        // dropped keys lose their deadlines
+        let mut dropped = Vec::new();
+        let tracked = self.expiry.is_some();
+        let mut keep = |key: &str, value: &str| {
+            let kept = keep(key, value);
+            if !kept && tracked {
+                dropped.push(key.to_string());
+            }
+            kept
+        };
+        self.prune_tree(Pruning {
+            classify: &|_, _, _, _| Coverage::Partial,
+            keep: &mut keep,
+            removed: Vec::new(),
+            probes: Vec::new(),
+            oversized: Vec::new(),
+        });
+        for key in dropped {
+            self.forget_deadline(&key);
+        }
+    }
+
+    fn prune_tree(&mut self, mut pruning: Pruning) {
+        let root = self.root_index;
+        if !self.prune(root, None, None, &mut pruning) {
+            if let NodeValue::Internal(_) = self.nodes[root].values {
+                self.nodes[root] = ArrayNode::new(self.fanout);
+            }
+        }
+        self.hand_over_root(&mut pruning.removed);
+        self.compact_nodes(pruning.removed);
+        for key in pruning.oversized {
+            let leaf = self.get_node_for_key(&key);
+            self.split_until_fits(leaf);
+        }
+
+        let mut probes = pruning.probes;
+        probes.sort_unstable();
+        probes.dedup();
+        for probe in probes {
+            self.rebalance_path(&probe);
+        }
+    }
+
+    // removes what the pruning asks for below `index`, which holds keys in
+    // [lower, upper); returns whether anything is left of the node
+    fn prune(
+        &mut self,
+        index: usize,
+        lower: Option<&str>,
+        upper: Option<&str>,
+        pruning: &mut Pruning,
+    ) -> bool {
+        if let NodeValue::Leaf(_) = self.nodes[index].values {
+            // pending lazy writes have to be applied before entries can be dropped
+            let pending = self.take_leaf_buffer(index);
+            self.merge_into_leaf(index, &pending);
+
+            let dropped = self.retain_in_leaf(index, |key, value, overflow| {
+                (pruning.keep)(key, &overflow.read(value.as_ref()))
+            });
+            let leaf = &self.nodes[index];
+            let left = !leaf.keys.is_empty();
+            if leaf.keys.len() > self.fanout {
+                pruning.oversized.push(leaf.keys.key(0).to_string());
+            }
+            if dropped > 0 {
+                pruning.probes.push(lower.unwrap_or("").to_string());
+            }
+            return left;
+        }
+
+        let node = &self.nodes[index];
+        let mut coverage = Vec::with_capacity(node.counts.len());
+        for position in 0..node.counts.len() {
+            let child_lower = match position {
+                0 => lower.map(Key::full),
+                _ => Some(node.keys.key(position - 1)),
+            };
+            let child_upper = node.keys.get(position).or(upper.map(Key::full));
+            coverage.push(
+                match (pruning.classify)(node, position, child_lower, child_upper) {
+                    Coverage::Partial => (
+                        Coverage::Partial,
+                        child_lower.map(|key| key.to_string()),
+                        child_upper.map(|key| key.to_string()),
+                    ),
+                    other => (other, None, None),
+                },
+            );
+        }
+
+        let node = &mut self.nodes[index];
+        let NodeValue::Internal(children) =
+            std::mem::replace(&mut node.values, NodeValue::Internal(Vec::new()))
+        else {
+            unreachable!();
+        };
+        let mut separators = std::mem::take(&mut node.keys).into_vec();
+        let counts = std::mem::take(&mut node.counts);
+        let summaries = std::mem::take(&mut node.summaries);
+
+        let mut keys = Vec::new();
+        let mut kept = Vec::new();
+        let mut kept_counts = Vec::new();
+        let mut kept_summaries = Vec::new();
+        let children = children
+            .into_iter()
+            .zip(coverage)
+            .zip(counts)
+            .zip(summaries);
+        for (position, (((child, coverage), count), summary)) in children.enumerate() {
+            let (count, summary) = match coverage {
+                (Coverage::Outside, _, _) => (count, summary),
+                (Coverage::Inside, _, _) => {
+                    self.drop_subtree(child, &mut pruning.removed);
+                    continue;
+                }
+                (Coverage::Partial, child_lower, child_upper) => {
+                    if !self.prune(
+                        child,
+                        child_lower.as_deref(),
+                        child_upper.as_deref(),
+                        pruning,
+                    ) {
+                        pruning.removed.push(child);
+                        continue;
+                    }
+                    (self.subtree_count(child), self.subtree_summaries(child))
+                }
+            };
+            // kept children keep their own lower separator, so the key space of dropped
+            // children goes to the kept child before them
+            if !kept.is_empty() {
+                keys.push(std::mem::take(&mut separators[position - 1]));
+            }
+            kept.push(child);
+            kept_counts.push(count);
+            kept_summaries.push(summary);
+        }
+
+        if kept.len() < separators.len() + 1 {
+            pruning.probes.push(lower.unwrap_or("").to_string());
+        }
+        let left = !kept.is_empty();
+        let node = &mut self.nodes[index];
+        node.keys = NodeKeys::from(keys);
+        node.values = NodeValue::Internal(kept);
+        node.counts = kept_counts;
+        node.summaries = kept_summaries;
+        left
+    }
+
+    // a root left with a single child hands over to it, as often as needed
+    pub(crate) fn hand_over_root(&mut self, removed: &mut Vec<usize>) {
+        while let NodeValue::Internal(ref children) = self.nodes[self.root_index].values {
+            if children.len() > 1 {
+                break;
+            }
+            let child = children[0];
+            removed.push(self.root_index);
+            self.nodes[child].parent = None;
+            self.root_index = child;
+            self.emit(|| Event::RootShrink { root: child });
+        }
+    }
+
+    // releases the values below `index` and marks its nodes for removal
+    pub(crate) fn drop_subtree(&mut self, index: usize, removed: &mut Vec<usize>) {
+        let mut stack = vec![index];
+        while let Some(index) = stack.pop() {
+            removed.push(index);
+            let values =
+                std::mem::replace(&mut self.nodes[index].values, NodeValue::Leaf(Vec::new()));
+            match values {
+                NodeValue::Internal(children) => stack.extend(children),
+                NodeValue::Leaf(values) => {
+                    for value in values {
+                        self.overflow.release(value);
+                    }
+                }
+            }
+        }
+    }
+
+    // cuts the removed nodes out of the arena in one pass and renumbers the rest
+    pub(crate) fn compact_nodes(&mut self, removed: Vec<usize>) {
+        if removed.is_empty() {
+            return;
+        }
+        let mut gone = vec![false; self.nodes.len()];
+        for index in removed {
+            gone[index] = true;
+        }
+        let mut renumbered = Vec::with_capacity(gone.len());
+        let mut next = 0;
+        for gone in gone.iter() {
+            renumbered.push(next);
+            if !gone {
+                next += 1;
+            }
+        }
+
+        let kept: Vec<usize> = (0..gone.len()).filter(|index| !gone[*index]).collect();
+        let nodes = std::mem::take(&mut self.nodes);
+        self.nodes = nodes
+            .into_iter()
+            .zip(gone)
+            .filter(|(_, gone)| !gone)
+            .map(|(node, _)| node)
+            .collect();
+        for node in self.nodes.iter_mut() {
+            node.parent = node.parent.map(|parent| renumbered[parent]);
+            if let NodeValue::Internal(ref mut pointers) = node.values {
+                for pointer in pointers.iter_mut() {
+                    *pointer = renumbered[*pointer];
+                }
+            }
+        }
+        self.root_index = renumbered[self.root_index];
+        self.emit(|| Event::Compact { kept });
+    }
+
+    // checks for merges on every level of the path to the leaf owning `key`, from the
+    // bottom up, starting over whenever a merge reshaped the path
+    pub(crate) fn rebalance_path(&mut self, key: &str) {
+        'descend: loop {
+            let mut path = Vec::new();
+            let mut index = self.root_index;
+            while let NodeValue::Internal(ref children) = self.nodes[index].values {
+                path.push(index);
+                index = children[self.nodes[index].child_position(key, &self.key_order)];
+            }
+            for node in path.into_iter().rev() {
+                let nodes_before = self.nodes.len();
+                self.check_merge(node);
+                if self.nodes.len() != nodes_before {
+                    continue 'descend;
+                }
+            }
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::ops::Bound;
+
+    use super::*;
+
+    fn key(i: usize) -> String {
+        format!("{:04}", i)
+    }
+
+    fn build(count: usize, lazy: bool) -> (BPlusTree, BTreeMap<String, String>) {
+        let mut tree = if lazy {
+            BPlusTree::with_update_buffer(4)
+        } else {
+            BPlusTree::new()
+        };
+        let mut reference = BTreeMap::new();
+        for i in 0..count {
+            let i = i * 7919 % count;
+            tree.insert(key(i), i.to_string());
+            reference.insert(key(i), i.to_string());
+        }
+        (tree, reference)
+    }
+
+    fn check(tree: &BPlusTree, reference: &BTreeMap<String, String>) {
+        tree.validate().unwrap();
+        let expected: Vec<_> = reference
+            .iter()
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect();
+        assert_eq!(tree.scan(..), expected);
+    }
+
+    #[test]
+    fn ranges_are_removed() {
+        let ranges = [
+            (Bound::Included(key(100)), Bound::Excluded(key(900))),
+            (Bound::Excluded(key(0)), Bound::Included(key(1))),
+            (Bound::Included(key(500)), Bound::Unbounded),
+            (Bound::Unbounded, Bound::Excluded(key(250))),
+            (Bound::Included(key(333)), Bound::Included(key(333))),
+            (Bound::Included(key(5000)), Bound::Unbounded),
+            (Bound::Unbounded, Bound::Unbounded),
+        ];
+        for lazy in [false, true] {
+            for range in ranges.iter() {
+                let (mut tree, mut reference) = build(1000, lazy);
+                let removed: Vec<String> = reference
+                    .range(range.clone())
+                    .map(|(k, _)| k.clone())
+                    .collect();
+                for key in removed.iter() {
+                    reference.remove(key);
+                }
+                assert_eq!(tree.remove_range(range.clone()), removed.len());
+                check(&tree, &reference);
+
+                // the tree keeps working after the pruning
+                for i in (0..1000).step_by(9) {
+                    tree.insert(key(i), "back".to_string());
+                    reference.insert(key(i), "back".to_string());
+                }
+                check(&tree, &reference);
+            }
+        }
+    }
+
+    #[test]
+    fn repeated_removals_shrink_the_tree() {
+        let (mut tree, mut reference) = build(2000, false);
+        for start in (0..2000).step_by(100).rev() {
+            tree.remove_range(key(start + 10)..key(start + 90));
+            reference.retain(|k, _| !(*k >= key(start + 10) && *k < key(start + 90)));
+            check(&tree, &reference);
+        }
+        assert_eq!(tree.len(), 400);
+    }
+
+    #[test]
+    fn retain_keeps_matching_entries() {
+        for lazy in [false, true] {
+            let (mut tree, mut reference) = build(1000, lazy);
+            tree.retain(|key, _| key.ends_with('3') || key < "0100");
+            reference.retain(|key, _| key.ends_with('3') || key.as_str() < "0100");
+            check(&tree, &reference);
+
+            tree.retain(|_, value| value.len() < 3);
+            reference.retain(|_, value| value.len() < 3);
+            check(&tree, &reference);
+
+            tree.retain(|_, _| false);
+            assert!(tree.is_empty());
+            tree.validate().unwrap();
+        }
+    }
+}
@@ -0,0 +1,125 @@
+// RESP, the Redis serialization protocol, as far as the server needs it. Clients send
+// a command as an array of bulk strings, or as an inline line of words the way telnet
+// users type them, and get back simple strings, errors, integers, bulk strings and
+// arrays. Lengths are checked against the same limits Redis uses before anything is
+// allocated for them.
+
+use std::io::{self, BufRead, Read, Write};
+
+const MAX_ARGUMENTS: usize = 1024 * 1024;
+const MAX_BULK: usize = 512 * 1024 * 1024;
+const MAX_INLINE: usize = 64 * 1024;
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Reply {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    // None is the null bulk string
+    Bulk(Option<String>),
+    Array(Vec<Reply>),
+}
+
+impl Reply {
+    pub(crate) fn ok() -> Reply {
+        Reply::Simple("OK".to_string())
+    }
+
+    pub(crate) fn error(message: impl Into<String>) -> Reply {
+        Reply::Error(message.into())
+    }
+
+    pub(crate) fn bulk(text: impl Into<String>) -> Reply {
+        Reply::Bulk(Some(text.into()))
+    }
+
+    pub(crate) fn write_to<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        match self {
+            Reply::Simple(text) => write!(out, "+{}\r\n", text),
+            Reply::Error(message) => write!(out, "-{}\r\n", message),
+            Reply::Integer(value) => write!(out, ":{}\r\n", value),
+            Reply::Bulk(None) => out.write_all(b"$-1\r\n"),
+            Reply::Bulk(Some(text)) => {
+                write!(out, "${}\r\n", text.len())?;
+                out.write_all(text.as_bytes())?;
+                out.write_all(b"\r\n")
+            }
+            Reply::Array(items) => {
+                write!(out, "*{}\r\n", items.len())?;
+                for item in items {
+                    item.write_to(out)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+fn protocol_error(message: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Protocol error: {}", message),
+    )
+}
+
+// a line ending in CRLF, or LF as netcat sends it, without the line ending; None at
+// the end of the input
+fn read_line<R: BufRead>(input: &mut R, limit: usize) -> io::Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    let read = Read::take(input, limit as u64 + 2).read_until(b'\n', &mut line)?;
+    if read == 0 {
+        return Ok(None);
+    }
+    if line.pop() != Some(b'\n') {
+        return Err(if line.len() > limit {
+            protocol_error("too big line")
+        } else {
+            io::Error::from(io::ErrorKind::UnexpectedEof)
+        });
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+fn parse_length(bytes: &[u8], limit: usize, what: &str) -> io::Result<usize> {
+    std::str::from_utf8(bytes)
+        .ok()
+        .and_then(|text| text.parse::<usize>().ok())
+        .filter(|length| *length <= limit)
+        .ok_or_else(|| protocol_error(&format!("invalid {} length", what)))
+}
+
+// the arguments of the next command, None once the client closed the connection
+pub(crate) fn read_command<R: BufRead>(input: &mut R) -> io::Result<Option<Vec<Vec<u8>>>> {
+    let Some(line) = read_line(input, MAX_INLINE)? else {
+        return Ok(None);
+    };
+    let Some(count) = line.strip_prefix(b"*") else {
+        let inline = line
+            .split(|byte| byte.is_ascii_whitespace())
+            .filter(|word| !word.is_empty())
+            .map(|word| word.to_vec())
+            .collect();
+        return Ok(Some(inline));
+    };
+    let count = parse_length(count, MAX_ARGUMENTS, "multibulk")?;
+
+    let mut arguments = Vec::with_capacity(count.min(64));
+    for _ in 0..count {
+        let header = read_line(input, MAX_INLINE)?.ok_or(io::ErrorKind::UnexpectedEof)?;
+        let Some(length) = header.strip_prefix(b"$") else {
+            return Err(protocol_error("expected '$'"));
+        };
+        let length = parse_length(length, MAX_BULK, "bulk")?;
+        let mut argument = vec![0; length + 2];
+        input.read_exact(&mut argument)?;
+        if !argument.ends_with(b"\r\n") {
+            return Err(protocol_error("bulk string not terminated by CRLF"));
+        }
+        argument.truncate(length);
+        arguments.push(argument);
+    }
+    Ok(Some(arguments))
+}
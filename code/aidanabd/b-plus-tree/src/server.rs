@@ -0,0 +1,902 @@
+// A key-value server speaking a subset of RESP, so that redis-cli and other Redis
+// tooling can talk to a BPlusTree over TCP. Besides GET, SET (with the NX, XX, IFEQ
+// and GET options of Redis' SET), DEL, EXISTS, DBSIZE and SCAN it treats the whole
+// key space as one ordered set the way Redis sorted sets with equal scores behave:
+// RANGEBYLEX and REVRANGEBYLEX take the bounds of ZRANGEBYLEX ("[key", "(key", "-"
+// and "+") with an optional LIMIT and WITHVALUES, LEXCOUNT counts a range and RANK
+// gives the position of a key. PING, ECHO, COMMAND, QUIT, SAVE and SHUTDOWN
+// [NOSAVE|SAVE] are there for tools and operators.
+//
+// Every connection gets its own thread, and the threads share the tree behind a
+// RwLock. A command holds the lock for as long as it runs, so every command is atomic
+// and reads proceed in parallel. Clients may pipeline: replies are buffered and only
+// written out once no further request is waiting in the connection's input buffer.
+//
+// SCAN continues after the last key it returned, so it returns every key present for
+// the whole scan exactly once whatever else changes in between. Redis clients expect
+// numeric cursors, so the server numbers the keys scans stopped at and remembers the
+// most recent MAX_CURSORS of them; an older cursor gets an error.
+//
+// Shutting down stops accepting connections, lets every connection finish the command
+// it is running, closes them and, unless told not to, saves the tree to the snapshot
+// file the server was started with.
+
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::ops::Bound;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::Duration;
+
+use crate::resp::{read_command, Reply};
+use crate::BPlusTree;
+
+// how often idle connections check whether the server is shutting down
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+const MAX_CURSORS: usize = 1024;
+const DEFAULT_SCAN_COUNT: usize = 10;
+
+#[derive(Debug, Default)]
+struct Cursors {
+    next: u64,
+    // the last key returned by every open scan
+    open: HashMap<u64, String>,
+    opened: VecDeque<u64>,
+}
+
+impl Cursors {
+    fn open(&mut self, key: String) -> u64 {
+        if self.opened.len() == MAX_CURSORS {
+            let oldest = self.opened.pop_front().unwrap();
+            self.open.remove(&oldest);
+        }
+        // 0 is the cursor that starts and ends a scan
+        self.next += 1;
+        self.open.insert(self.next, key);
+        self.opened.push_back(self.next);
+        self.next
+    }
+
+    fn take(&mut self, cursor: u64) -> Option<String> {
+        let key = self.open.remove(&cursor)?;
+        self.opened.retain(|open| *open != cursor);
+        Some(key)
+    }
+}
+
+#[derive(Debug)]
+struct Shared {
+    tree: RwLock<BPlusTree>,
+    snapshot: Option<PathBuf>,
+    cursors: Mutex<Cursors>,
+    shutdown: AtomicBool,
+    save_on_shutdown: AtomicBool,
+    address: SocketAddr,
+}
+
+impl Shared {
+    fn begin_shutdown(&self) {
+        if self.shutdown.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        // wake the accept loop up, it checks the flag before serving a connection
+        let mut address = self.address;
+        if address.ip().is_unspecified() {
+            match address {
+                SocketAddr::V4(_) => address.set_ip([127, 0, 0, 1].into()),
+                SocketAddr::V6(_) => address.set_ip(std::net::Ipv6Addr::LOCALHOST.into()),
+            }
+        }
+        let _ = TcpStream::connect(address);
+    }
+}
+
+#[derive(Debug)]
+pub struct Server {
+    listener: TcpListener,
+    shared: Arc<Shared>,
+}
+
+// stops the server it was taken from, from any thread
+#[derive(Debug, Clone)]
+pub struct ShutdownHandle {
+    shared: Arc<Shared>,
+}
+
+impl ShutdownHandle {
+    pub fn shutdown(&self) {
+        self.shared.begin_shutdown();
+    }
+
+    pub fn shutdown_without_saving(&self) {
+        self.shared.save_on_shutdown.store(false, Ordering::SeqCst);
+        self.shared.begin_shutdown();
+    }
+}
+
+// what the connection does after replying
+#[derive(Debug, PartialEq)]
+enum Next {
+    Continue,
+    Close,
+    Shutdown,
+}
+
+impl Server {
+    // serves `tree` on `address`; with a snapshot file, SAVE and shutting down save
+    // the tree to it
+    pub fn bind<A: ToSocketAddrs>(
+        address: A,
+        tree: BPlusTree,
+        snapshot: Option<PathBuf>,
+    ) -> io::Result<Server> {
+        let listener = TcpListener::bind(address)?;
+        let shared = Arc::new(Shared {
+            tree: RwLock::new(tree),
+            save_on_shutdown: AtomicBool::new(snapshot.is_some()),
+            snapshot,
+            cursors: Mutex::new(Cursors::default()),
+            shutdown: AtomicBool::new(false),
+            address: listener.local_addr()?,
+        });
+        Ok(Server { listener, shared })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.shared.address
+    }
+
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle {
+            shared: self.shared.clone(),
+        }
+    }
+
+    // serves connections until the server is shut down and returns the tree
+    pub fn run(self) -> io::Result<BPlusTree> {
+        let mut connections = Vec::new();
+        for stream in self.listener.incoming() {
+            if self.shared.shutdown.load(Ordering::SeqCst) {
+                break;
+            }
+            let Ok(stream) = stream else {
+                continue;
+            };
+            let shared = self.shared.clone();
+            connections.push(thread::spawn(move || serve(stream, &shared)));
+            connections.retain(|connection| !connection.is_finished());
+        }
+        drop(self.listener);
+        for connection in connections {
+            let _ = connection.join();
+        }
+
+        let tree = std::mem::take(&mut *self.shared.tree.write().unwrap());
+        if self.shared.save_on_shutdown.load(Ordering::SeqCst) {
+            if let Some(ref path) = self.shared.snapshot {
+                tree.save(path)?;
+            }
+        }
+        Ok(tree)
+    }
+}
+
+// reads from a connection, waiting out read timeouts until the server shuts down
+struct Patient<'a> {
+    stream: &'a TcpStream,
+    shutdown: &'a AtomicBool,
+}
+
+impl Read for Patient<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match self.stream.read(buf) {
+                Err(error)
+                    if matches!(
+                        error.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) && !self.shutdown.load(Ordering::SeqCst) =>
+                {
+                    continue
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+fn serve(stream: TcpStream, shared: &Shared) -> io::Result<()> {
+    stream.set_read_timeout(Some(POLL_INTERVAL))?;
+    stream.set_nodelay(true)?;
+    let mut reader = BufReader::new(Patient {
+        stream: &stream,
+        shutdown: &shared.shutdown,
+    });
+    let mut writer = BufWriter::new(&stream);
+    loop {
+        let arguments = match read_command(&mut reader) {
+            Ok(Some(arguments)) => arguments,
+            Ok(None) => return writer.flush(),
+            Err(error) if error.kind() == io::ErrorKind::InvalidData => {
+                Reply::error(format!("ERR {}", error)).write_to(&mut writer)?;
+                return writer.flush();
+            }
+            Err(error) => return Err(error),
+        };
+        if arguments.is_empty() {
+            continue;
+        }
+        let (reply, next) = execute(arguments, shared);
+        if let Some(reply) = reply {
+            reply.write_to(&mut writer)?;
+        }
+        if next != Next::Continue || reader.buffer().is_empty() {
+            writer.flush()?;
+        }
+        match next {
+            Next::Continue => (),
+            Next::Close => return Ok(()),
+            Next::Shutdown => {
+                shared.begin_shutdown();
+                return Ok(());
+            }
+        }
+    }
+}
+
+fn wrong_arguments(command: &str) -> Reply {
+    Reply::error(format!(
+        "ERR wrong number of arguments for '{}' command",
+        command.to_ascii_lowercase()
+    ))
+}
+
+fn syntax_error() -> Reply {
+    Reply::error("ERR syntax error")
+}
+
+fn parse_integer(text: &str) -> Result<i64, Reply> {
+    text.parse()
+        .map_err(|_| Reply::error("ERR value is not an integer or out of range"))
+}
+
+// whether `text` matches a Redis glob pattern with *, ?, [...] and \ escapes
+fn glob_match(pattern: &[char], text: &[char]) -> bool {
+    match pattern.split_first() {
+        None => text.is_empty(),
+        Some(('*', rest)) => {
+            let rest = rest
+                .iter()
+                .position(|c| *c != '*')
+                .map_or(&[][..], |at| &rest[at..]);
+            (0..=text.len()).any(|skip| glob_match(rest, &text[skip..]))
+        }
+        Some(('?', rest)) => !text.is_empty() && glob_match(rest, &text[1..]),
+        Some(('[', rest)) if rest.contains(&']') => {
+            let Some((first, text_rest)) = text.split_first() else {
+                return false;
+            };
+            let negated = rest.first() == Some(&'^');
+            let mut class = if negated { &rest[1..] } else { rest };
+            let mut matched = false;
+            loop {
+                match class {
+                    [']', after @ ..] => {
+                        class = after;
+                        break;
+                    }
+                    ['\\', c, after @ ..] => {
+                        matched |= c == first;
+                        class = after;
+                    }
+                    [low, '-', high, after @ ..] if *high != ']' => {
+                        matched |= (low.min(high)..=low.max(high)).contains(&first);
+                        class = after;
+                    }
+                    [c, after @ ..] => {
+                        matched |= c == first;
+                        class = after;
+                    }
+                    [] => return false,
+                }
+            }
+            matched != negated && glob_match(class, text_rest)
+        }
+        Some(('\\', [escaped, rest @ ..])) => {
+            text.first() == Some(escaped) && glob_match(rest, &text[1..])
+        }
+        Some((c, rest)) => text.first() == Some(c) && glob_match(rest, &text[1..]),
+    }
+}
+
+// a ZRANGEBYLEX bound; None for "+" as a lower or "-" as an upper bound, which
+// nothing lies beyond
+fn lex_bound(text: &str, lower: bool) -> Result<Option<Bound<String>>, Reply> {
+    match (text, text.chars().next()) {
+        ("-", _) if lower => Ok(Some(Bound::Unbounded)),
+        ("+", _) if !lower => Ok(Some(Bound::Unbounded)),
+        ("-", _) | ("+", _) => Ok(None),
+        (_, Some('[')) => Ok(Some(Bound::Included(text[1..].to_string()))),
+        (_, Some('(')) => Ok(Some(Bound::Excluded(text[1..].to_string()))),
+        _ => Err(Reply::error("ERR min or max not valid string range item")),
+    }
+}
+
+fn execute(arguments: Vec<Vec<u8>>, shared: &Shared) -> (Option<Reply>, Next) {
+    let arguments: Result<Vec<String>, _> = arguments.into_iter().map(String::from_utf8).collect();
+    let Ok(arguments) = arguments else {
+        return (
+            Some(Reply::error("ERR keys and values must be UTF-8")),
+            Next::Continue,
+        );
+    };
+    let command = arguments[0].to_ascii_uppercase();
+    let arguments = &arguments[1..];
+    let reply = match command.as_str() {
+        "QUIT" => return (Some(Reply::ok()), Next::Close),
+        "SHUTDOWN" => match arguments {
+            [] => return (None, Next::Shutdown),
+            [option] if option.eq_ignore_ascii_case("NOSAVE") => {
+                shared.save_on_shutdown.store(false, Ordering::SeqCst);
+                return (None, Next::Shutdown);
+            }
+            [option] if option.eq_ignore_ascii_case("SAVE") => {
+                if shared.snapshot.is_none() {
+                    Reply::error("ERR no snapshot file configured")
+                } else {
+                    shared.save_on_shutdown.store(true, Ordering::SeqCst);
+                    return (None, Next::Shutdown);
+                }
+            }
+            _ => syntax_error(),
+        },
+        _ => run(&command, arguments, shared).unwrap_or_else(|error| error),
+    };
+    (Some(reply), Next::Continue)
+}
+
+fn run(command: &str, arguments: &[String], shared: &Shared) -> Result<Reply, Reply> {
+    let expect = |count: usize| {
+        if arguments.len() == count {
+            Ok(())
+        } else {
+            Err(wrong_arguments(command))
+        }
+    };
+    let at_least = |count: usize| {
+        if arguments.len() >= count {
+            Ok(())
+        } else {
+            Err(wrong_arguments(command))
+        }
+    };
+    let tree = || shared.tree.read().unwrap();
+    let contains = |tree: &BPlusTree, key: &str| {
+        tree.count((
+            Bound::Included(key.to_string()),
+            Bound::Included(key.to_string()),
+        )) > 0
+    };
+
+    Ok(match command {
+        "PING" => match arguments {
+            [] => Reply::Simple("PONG".to_string()),
+            [message] => Reply::bulk(message.as_str()),
+            _ => return Err(wrong_arguments(command)),
+        },
+        "ECHO" => {
+            expect(1)?;
+            Reply::bulk(arguments[0].as_str())
+        }
+        // tools ask for the command table on connecting and cope without one
+        "COMMAND" => Reply::Array(Vec::new()),
+        "GET" => {
+            expect(1)?;
+            Reply::Bulk(tree().get(&arguments[0]))
+        }
+        "SET" => {
+            at_least(2)?;
+            set(arguments, &mut shared.tree.write().unwrap())?
+        }
+        "DEL" => {
+            at_least(1)?;
+            let mut tree = shared.tree.write().unwrap();
+            let mut deleted = 0;
+            for key in arguments {
+                if contains(&tree, key) {
+                    tree.delete(key.clone());
+                    deleted += 1;
+                }
+            }
+            Reply::Integer(deleted)
+        }
+        "EXISTS" => {
+            at_least(1)?;
+            let tree = tree();
+            let found = arguments.iter().filter(|key| contains(&tree, key)).count();
+            Reply::Integer(found as i64)
+        }
+        "DBSIZE" => {
+            expect(0)?;
+            Reply::Integer(tree().len() as i64)
+        }
+        "SCAN" => scan(arguments, shared)?,
+        "RANGEBYLEX" | "REVRANGEBYLEX" => {
+            range_by_lex(arguments, command == "REVRANGEBYLEX", &tree())?
+        }
+        "LEXCOUNT" => {
+            expect(2)?;
+            match (
+                lex_bound(&arguments[0], true)?,
+                lex_bound(&arguments[1], false)?,
+            ) {
+                (Some(start), Some(end)) => Reply::Integer(tree().count((start, end)) as i64),
+                _ => Reply::Integer(0),
+            }
+        }
+        "RANK" => {
+            expect(1)?;
+            let tree = tree();
+            if contains(&tree, &arguments[0]) {
+                Reply::Integer(tree.rank(&arguments[0]) as i64)
+            } else {
+                Reply::Bulk(None)
+            }
+        }
+        "SAVE" => {
+            expect(0)?;
+            let Some(ref path) = shared.snapshot else {
+                return Err(Reply::error("ERR no snapshot file configured"));
+            };
+            tree()
+                .save(path)
+                .map_err(|error| Reply::error(format!("ERR {}", error)))?;
+            Reply::ok()
+        }
+        _ => {
+            return Err(Reply::error(format!(
+                "ERR unknown command '{}'",
+                command.to_ascii_lowercase()
+            )))
+        }
+    })
+}
+
+// the condition of SET key value [NX | XX | IFEQ expected] [GET]
+enum SetIf<'a> {
+    Always,
+    Absent,
+    Present,
+    Equal(&'a str),
+}
+
+fn set(arguments: &[String], tree: &mut BPlusTree) -> Result<Reply, Reply> {
+    let (key, value) = (arguments[0].clone(), arguments[1].clone());
+    let mut condition = SetIf::Always;
+    let mut get = false;
+    let mut options = arguments[2..].iter();
+    while let Some(option) = options.next() {
+        let unconditional = matches!(condition, SetIf::Always);
+        match option.to_ascii_uppercase().as_str() {
+            "GET" if !get => get = true,
+            "NX" if unconditional => condition = SetIf::Absent,
+            "XX" if unconditional => condition = SetIf::Present,
+            "IFEQ" if unconditional => {
+                condition = SetIf::Equal(options.next().ok_or_else(syntax_error)?);
+            }
+            _ => return Err(syntax_error()),
+        }
+    }
+    let outcome = match condition {
+        SetIf::Always => tree.update_with(key, |_| Some(value)),
+        SetIf::Absent => tree.insert_if_absent(key, value),
+        SetIf::Present => tree.update_with(key, |current| current.map(|_| value)),
+        SetIf::Equal(expected) => tree.compare_and_swap(key, Some(expected), value),
+    };
+    Ok(if get {
+        Reply::Bulk(outcome.into_observed())
+    } else if outcome.is_written() {
+        Reply::ok()
+    } else {
+        Reply::Bulk(None)
+    })
+}
+
+// SCAN cursor [MATCH pattern] [COUNT count]
+fn scan(arguments: &[String], shared: &Shared) -> Result<Reply, Reply> {
+    let Some(cursor) = arguments.first() else {
+        return Err(wrong_arguments("SCAN"));
+    };
+    let cursor: u64 = cursor
+        .parse()
+        .map_err(|_| Reply::error("ERR invalid cursor"))?;
+    let mut pattern = None;
+    let mut count = DEFAULT_SCAN_COUNT;
+    let mut options = arguments[1..].iter();
+    while let Some(option) = options.next() {
+        let value = options.next().ok_or_else(syntax_error)?;
+        if option.eq_ignore_ascii_case("MATCH") {
+            pattern = Some(value.chars().collect::<Vec<char>>());
+        } else if option.eq_ignore_ascii_case("COUNT") {
+            count = match parse_integer(value)? {
+                count if count < 1 => return Err(syntax_error()),
+                count => count as usize,
+            };
+        } else {
+            return Err(syntax_error());
+        }
+    }
+
+    let after = match cursor {
+        0 => None,
+        _ => match shared.cursors.lock().unwrap().take(cursor) {
+            Some(key) => Some(key),
+            None => return Err(Reply::error("ERR invalid cursor")),
+        },
+    };
+    let tree = shared.tree.read().unwrap();
+    let start = match after {
+        Some(ref key) => tree.count((Bound::Unbounded, Bound::Included(key.clone()))),
+        None => 0,
+    };
+    let mut keys = Vec::new();
+    let mut last = None;
+    for position in start..start.saturating_add(count) {
+        let Some((key, _)) = tree.select(position) else {
+            last = None;
+            break;
+        };
+        last = Some(key.clone());
+        keys.push(key);
+    }
+    drop(tree);
+
+    let next = match last {
+        Some(key) => shared.cursors.lock().unwrap().open(key),
+        None => 0,
+    };
+    if let Some(pattern) = pattern {
+        keys.retain(|key| glob_match(&pattern, &key.chars().collect::<Vec<char>>()));
+    }
+    Ok(Reply::Array(vec![
+        Reply::bulk(next.to_string()),
+        Reply::Array(keys.into_iter().map(Reply::bulk).collect()),
+    ]))
+}
+
+// [REV]RANGEBYLEX from to [LIMIT offset count] [WITHVALUES], where `from` is the
+// upper bound when reversed
+fn range_by_lex(arguments: &[String], reverse: bool, tree: &BPlusTree) -> Result<Reply, Reply> {
+    let command = if reverse {
+        "REVRANGEBYLEX"
+    } else {
+        "RANGEBYLEX"
+    };
+    if arguments.len() < 2 {
+        return Err(wrong_arguments(command));
+    }
+    let (lower, upper) = if reverse {
+        (&arguments[1], &arguments[0])
+    } else {
+        (&arguments[0], &arguments[1])
+    };
+    let bounds = (lex_bound(lower, true)?, lex_bound(upper, false)?);
+
+    let mut limit = None;
+    let mut with_values = false;
+    let mut options = arguments[2..].iter();
+    while let Some(option) = options.next() {
+        if option.eq_ignore_ascii_case("WITHVALUES") {
+            with_values = true;
+        } else if option.eq_ignore_ascii_case("LIMIT") {
+            let (Some(offset), Some(count)) = (options.next(), options.next()) else {
+                return Err(syntax_error());
+            };
+            limit = Some((parse_integer(offset)?, parse_integer(count)?));
+        } else {
+            return Err(syntax_error());
+        }
+    }
+
+    let (Some(start), Some(end)) = bounds else {
+        return Ok(Reply::Array(Vec::new()));
+    };
+    let entries = match limit {
+        None => {
+            let mut entries = tree.scan((start, end));
+            if reverse {
+                entries.reverse();
+            }
+            entries
+        }
+        Some((offset, _)) if offset < 0 => Vec::new(),
+        Some((offset, count)) => {
+            // positions of the entries in the range, by order statistics
+            let first = tree.len() - tree.count((start, Bound::Unbounded));
+            let end = tree.count((Bound::Unbounded, end));
+            let length = end.saturating_sub(first);
+            let skipped = (offset as usize).min(length);
+            let taken = match count {
+                count if count < 0 => length - skipped,
+                count => (count as usize).min(length - skipped),
+            };
+            (0..taken)
+                .filter_map(|step| {
+                    if reverse {
+                        tree.select(end - 1 - skipped - step)
+                    } else {
+                        tree.select(first + skipped + step)
+                    }
+                })
+                .collect()
+        }
+    };
+
+    let mut items = Vec::with_capacity(entries.len() * if with_values { 2 } else { 1 });
+    for (key, value) in entries {
+        items.push(Reply::bulk(key));
+        if with_values {
+            items.push(Reply::bulk(value));
+        }
+    }
+    Ok(Reply::Array(items))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::BufRead;
+
+    struct Client {
+        reader: BufReader<TcpStream>,
+        writer: TcpStream,
+    }
+
+    impl Client {
+        fn connect(address: SocketAddr) -> Client {
+            let stream = TcpStream::connect(address).unwrap();
+            Client {
+                reader: BufReader::new(stream.try_clone().unwrap()),
+                writer: stream,
+            }
+        }
+
+        fn send(&mut self, command: &[&str]) {
+            let mut request = format!("*{}\r\n", command.len());
+            for argument in command {
+                request.push_str(&format!("${}\r\n{}\r\n", argument.len(), argument));
+            }
+            self.writer.write_all(request.as_bytes()).unwrap();
+        }
+
+        fn receive(&mut self) -> Reply {
+            let mut line = String::new();
+            self.reader.read_line(&mut line).unwrap();
+            let (kind, rest) = line.trim_end().split_at(1);
+            match kind {
+                "+" => Reply::Simple(rest.to_string()),
+                "-" => Reply::Error(rest.to_string()),
+                ":" => Reply::Integer(rest.parse().unwrap()),
+                "$" if rest == "-1" => Reply::Bulk(None),
+                "$" => {
+                    let mut bulk = vec![0; rest.parse::<usize>().unwrap() + 2];
+                    self.reader.read_exact(&mut bulk).unwrap();
+                    bulk.truncate(bulk.len() - 2);
+                    Reply::bulk(String::from_utf8(bulk).unwrap())
+                }
+                "*" => Reply::Array(
+                    (0..rest.parse::<usize>().unwrap())
+                        .map(|_| self.receive())
+                        .collect(),
+                ),
+                _ => panic!("unexpected reply {:?}", line),
+            }
+        }
+
+        fn call(&mut self, command: &[&str]) -> Reply {
+            self.send(command);
+            self.receive()
+        }
+    }
+
+    fn bulks(items: &[&str]) -> Reply {
+        Reply::Array(items.iter().map(|item| Reply::bulk(*item)).collect())
+    }
+
+    fn start(
+        tree: BPlusTree,
+        snapshot: Option<PathBuf>,
+    ) -> (
+        SocketAddr,
+        ShutdownHandle,
+        thread::JoinHandle<io::Result<BPlusTree>>,
+    ) {
+        let server = Server::bind("127.0.0.1:0", tree, snapshot).unwrap();
+        let address = server.local_addr();
+        let handle = server.shutdown_handle();
+        (address, handle, thread::spawn(move || server.run()))
+    }
+
+    #[test]
+    fn commands_and_pipelining() {
+        let (address, handle, server) = start(BPlusTree::new(), None);
+        let mut client = Client::connect(address);
+        assert_eq!(client.call(&["PING"]), Reply::Simple("PONG".to_string()));
+        assert_eq!(client.call(&["set", "a", "1"]), Reply::ok());
+        assert_eq!(client.call(&["GET", "a"]), Reply::bulk("1"));
+        assert_eq!(client.call(&["GET", "b"]), Reply::Bulk(None));
+        assert!(matches!(client.call(&["GET"]), Reply::Error(_)));
+        assert!(matches!(client.call(&["NOPE"]), Reply::Error(_)));
+
+        // many requests in one write, answered in order
+        for key in ["b", "c", "d"] {
+            client.send(&["SET", key, key]);
+        }
+        client.send(&["EXISTS", "a", "b", "x", "a"]);
+        client.send(&["DEL", "a", "x"]);
+        client.send(&["DBSIZE"]);
+        for _ in 0..3 {
+            assert_eq!(client.receive(), Reply::ok());
+        }
+        assert_eq!(client.receive(), Reply::Integer(3));
+        assert_eq!(client.receive(), Reply::Integer(1));
+        assert_eq!(client.receive(), Reply::Integer(3));
+
+        // inline commands as telnet sends them
+        client.writer.write_all(b"GET b\r\n").unwrap();
+        assert_eq!(client.receive(), Reply::bulk("b"));
+
+        handle.shutdown();
+        assert_eq!(server.join().unwrap().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn conditional_sets() {
+        let (address, handle, server) = start(BPlusTree::new(), None);
+        let mut client = Client::connect(address);
+        assert_eq!(client.call(&["SET", "a", "1", "XX"]), Reply::Bulk(None));
+        assert_eq!(client.call(&["SET", "a", "1", "NX"]), Reply::ok());
+        assert_eq!(client.call(&["SET", "a", "2", "NX"]), Reply::Bulk(None));
+        assert_eq!(
+            client.call(&["SET", "a", "2", "XX", "GET"]),
+            Reply::bulk("1")
+        );
+        assert_eq!(
+            client.call(&["SET", "a", "3", "IFEQ", "1"]),
+            Reply::Bulk(None)
+        );
+        assert_eq!(
+            client.call(&["SET", "a", "3", "ifeq", "2", "get"]),
+            Reply::bulk("2")
+        );
+        assert_eq!(client.call(&["SET", "b", "1", "GET"]), Reply::Bulk(None));
+        assert_eq!(client.call(&["GET", "a"]), Reply::bulk("3"));
+        for invalid in [
+            &["SET", "a", "1", "NX", "XX"][..],
+            &["SET", "a", "1", "IFEQ"],
+            &["SET", "a", "1", "GET", "GET"],
+            &["SET", "a", "1", "EX", "10"],
+        ] {
+            assert!(matches!(client.call(invalid), Reply::Error(_)));
+        }
+        handle.shutdown();
+        assert_eq!(server.join().unwrap().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn lexicographic_ranges() {
+        let mut tree = BPlusTree::new();
+        for key in ["a", "b", "c", "d", "e", "f"] {
+            tree.insert(key.to_string(), key.to_uppercase());
+        }
+        let (address, handle, server) = start(tree, None);
+        let mut client = Client::connect(address);
+        assert_eq!(
+            client.call(&["RANGEBYLEX", "[b", "(e"]),
+            bulks(&["b", "c", "d"])
+        );
+        assert_eq!(
+            client.call(&["REVRANGEBYLEX", "+", "(d"]),
+            bulks(&["f", "e"])
+        );
+        assert_eq!(
+            client.call(&["RANGEBYLEX", "-", "+", "LIMIT", "1", "2", "WITHVALUES"]),
+            bulks(&["b", "B", "c", "C"])
+        );
+        assert_eq!(
+            client.call(&["REVRANGEBYLEX", "[e", "-", "LIMIT", "1", "-1"]),
+            bulks(&["d", "c", "b", "a"])
+        );
+        assert_eq!(client.call(&["RANGEBYLEX", "+", "-"]), bulks(&[]));
+        assert_eq!(client.call(&["LEXCOUNT", "(a", "[c"]), Reply::Integer(2));
+        assert_eq!(client.call(&["RANK", "d"]), Reply::Integer(3));
+        assert_eq!(client.call(&["RANK", "dd"]), Reply::Bulk(None));
+        assert!(matches!(
+            client.call(&["LEXCOUNT", "a", "b"]),
+            Reply::Error(_)
+        ));
+        handle.shutdown();
+        server.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn scan_survives_concurrent_writes() {
+        let mut tree = BPlusTree::new();
+        for i in 0..200 {
+            tree.insert(format!("key{:03}", i), String::new());
+        }
+        let (address, handle, server) = start(tree, None);
+        let mut client = Client::connect(address);
+        let mut writer = Client::connect(address);
+        let mut seen = Vec::new();
+        let mut cursor = "0".to_string();
+        let mut round = 0;
+        loop {
+            let Reply::Array(reply) =
+                client.call(&["SCAN", &cursor, "COUNT", "7", "MATCH", "key*[02468]"])
+            else {
+                panic!("SCAN did not reply with an array");
+            };
+            let [Reply::Bulk(Some(next)), Reply::Array(keys)] = &reply[..] else {
+                panic!("unexpected SCAN reply {:?}", reply);
+            };
+            for key in keys {
+                let Reply::Bulk(Some(key)) = key else {
+                    panic!()
+                };
+                seen.push(key.clone());
+            }
+            // odd keys come and go while the scan runs
+            let odd = format!("key{:03}", round * 2 + 1);
+            writer.call(&["DEL", &odd]);
+            writer.call(&["SET", &format!("{}x", odd), "new"]);
+            round += 1;
+            cursor = next.clone();
+            if cursor == "0" {
+                break;
+            }
+        }
+        let expected: Vec<String> = (0..200)
+            .step_by(2)
+            .map(|i| format!("key{:03}", i))
+            .collect();
+        assert_eq!(seen, expected);
+        assert!(matches!(client.call(&["SCAN", "12345"]), Reply::Error(_)));
+        handle.shutdown();
+        server.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn shutdown_saves_the_snapshot() {
+        let path =
+            std::env::temp_dir().join(format!("resp_server_{}.snapshot", std::process::id()));
+        let (address, _, server) = start(BPlusTree::new(), Some(path.clone()));
+        let writers: Vec<_> = (0..8)
+            .map(|writer| {
+                thread::spawn(move || {
+                    let mut client = Client::connect(address);
+                    for i in 0..50 {
+                        let key = format!("{}-{}", writer, i);
+                        assert_eq!(client.call(&["SET", &key, "v"]), Reply::ok());
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        // an idle connection must not hold the shutdown up
+        let _idle = Client::connect(address);
+        let mut client = Client::connect(address);
+        client.send(&["SHUTDOWN"]);
+        assert_eq!(server.join().unwrap().unwrap().len(), 400);
+
+        let mut loaded = BPlusTree::new();
+        loaded.load(&path).unwrap();
+        assert_eq!(loaded.len(), 400);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
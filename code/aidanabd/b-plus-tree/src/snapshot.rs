@@ -0,0 +1,212 @@
+// Snapshots of a tree's entries in a file. A snapshot starts with a magic header, the
+// name of the comparator the tree was ordered with and the number of entries,
+// followed by every entry in key order as its key length and value length, both
+// little-endian u32, and the key and value bytes, the way the WiscKey value log
+// stores its records. Only entries are written, not the node layout, so a snapshot
+// can be loaded whatever the fanout and settings of the loading tree.
+//
+// `save` writes to a temporary file next to the target, syncs it and renames it over
+// the target, so a crash leaves either the old snapshot or the new one and never a
+// torn file. `load` refuses a snapshot written with a comparator of another name.
+
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use crate::BPlusTree;
+
+const MAGIC: &[u8; 8] = b"BPTSNAP1";
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn write_bytes<W: Write>(out: &mut W, bytes: &[u8]) -> io::Result<()> {
+    let length = u32::try_from(bytes.len()).map_err(|_| invalid("snapshot field too long"))?;
+    out.write_all(&length.to_le_bytes())?;
+    out.write_all(bytes)
+}
+
+fn read_u32<R: Read>(input: &mut R) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    input.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_string<R: Read>(input: &mut R, length: u32) -> io::Result<String> {
+    let mut bytes = vec![0; length as usize];
+    input.read_exact(&mut bytes)?;
+    String::from_utf8(bytes).map_err(|_| invalid("snapshot string is not UTF-8"))
+}
+
+impl BPlusTree {
+    pub fn write_snapshot<W: Write>(&self, mut out: W) -> io::Result<()> {
+        out.write_all(MAGIC)?;
+        write_bytes(&mut out, self.comparator_name().as_bytes())?;
+        // `len` still counts expired entries that were not swept, `scan` skips them
+        let entries = self.scan(..);
+        out.write_all(&(entries.len() as u64).to_le_bytes())?;
+        for (key, value) in entries {
+            let key_length =
+                u32::try_from(key.len()).map_err(|_| invalid("snapshot key too long"))?;
+            let value_length =
+                u32::try_from(value.len()).map_err(|_| invalid("snapshot value too long"))?;
+            out.write_all(&key_length.to_le_bytes())?;
+            out.write_all(&value_length.to_le_bytes())?;
+            out.write_all(key.as_bytes())?;
+            out.write_all(value.as_bytes())?;
+        }
+        out.flush()
+    }
+
+    // replaces the entries of the tree with those of the snapshot; the tree is left
+    // unchanged if the snapshot cannot be read
+    pub fn read_snapshot<R: Read>(&mut self, mut input: R) -> io::Result<()> {
+        let mut magic = [0; 8];
+        input.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid("not a B+ tree snapshot"));
+        }
+        let length = read_u32(&mut input)?;
+        let recorded = read_string(&mut input, length)?;
+        if recorded != self.comparator_name() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "snapshot was written with comparator {:?}, not {:?}",
+                    recorded,
+                    self.comparator_name()
+                ),
+            ));
+        }
+        let mut count = [0; 8];
+        input.read_exact(&mut count)?;
+        let count = u64::from_le_bytes(count);
+
+        let mut entries = Vec::new();
+        for _ in 0..count {
+            let key_length = read_u32(&mut input)?;
+            let value_length = read_u32(&mut input)?;
+            let key = read_string(&mut input, key_length)?;
+            let value = read_string(&mut input, value_length)?;
+            entries.push((key, value));
+        }
+        if input.read(&mut [0])? != 0 {
+            return Err(invalid("trailing bytes after snapshot entries"));
+        }
+
+        self.remove_range::<std::ops::RangeFull>(..);
+        for (key, value) in entries {
+            self.insert(key, value);
+        }
+        Ok(())
+    }
+
+    // writes a snapshot to `path`, atomically replacing any file already there
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let path = path.as_ref();
+        let mut temporary = path.as_os_str().to_owned();
+        temporary.push(".tmp");
+        let file = File::create(&temporary)?;
+        let mut out = BufWriter::new(file);
+        let written = self.write_snapshot(&mut out).and_then(|_| {
+            let file = out.into_inner().map_err(|error| error.into_error())?;
+            file.sync_all()
+        });
+        if let Err(error) = written {
+            let _ = fs::remove_file(&temporary);
+            return Err(error);
+        }
+        fs::rename(&temporary, path)?;
+        // make the rename itself durable
+        if let Some(dir) = path.parent() {
+            let dir = if dir.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                dir
+            };
+            File::open(dir)?.sync_all()?;
+        }
+        Ok(())
+    }
+
+    pub fn load<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        self.read_snapshot(BufReader::new(File::open(path)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compare::Natural;
+
+    fn tree() -> BPlusTree {
+        let mut tree = BPlusTree::with_overflow_threshold(16);
+        for i in 0..500 {
+            tree.insert(format!("{:03}", i), i.to_string());
+        }
+        tree.insert(String::new(), "empty key".to_string());
+        tree.insert("line\nbreak".to_string(), "日本語".to_string());
+        tree.insert("large".to_string(), "x".repeat(10_000));
+        tree
+    }
+
+    #[test]
+    fn snapshots_round_trip() {
+        let tree = tree();
+        let path = std::env::temp_dir().join(format!("snapshot_{}", std::process::id()));
+        tree.save(&path).unwrap();
+        // a second save replaces the first
+        tree.save(&path).unwrap();
+
+        // the layout is not saved, so a tree with other settings can load it
+        let mut loaded = BPlusTree::with_update_buffer(4);
+        loaded.insert("replaced".to_string(), String::new());
+        loaded.load(&path).unwrap();
+        loaded.validate().unwrap();
+        assert_eq!(loaded.scan(..), tree.scan(..));
+        fs::remove_file(&path).unwrap();
+
+        let mut natural = BPlusTree::with_comparator(Natural);
+        natural.insert("file10".to_string(), String::new());
+        natural.insert("file9".to_string(), String::new());
+        let mut bytes = Vec::new();
+        natural.write_snapshot(&mut bytes).unwrap();
+        let mut reloaded = BPlusTree::with_comparator(Natural);
+        reloaded.read_snapshot(&bytes[..]).unwrap();
+        assert_eq!(reloaded.scan(..), natural.scan(..));
+
+        let mut bytewise = BPlusTree::new();
+        let error = bytewise.read_snapshot(&bytes[..]).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        assert!(bytewise.is_empty());
+    }
+
+    #[test]
+    fn broken_snapshots_leave_the_tree_alone() {
+        let mut bytes = Vec::new();
+        tree().write_snapshot(&mut bytes).unwrap();
+        let mut target = BPlusTree::new();
+        target.insert("kept".to_string(), String::new());
+
+        let mut bad_magic = bytes.clone();
+        bad_magic[0] ^= 1;
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        let mut bad_utf8 = bytes.clone();
+        let last = bad_utf8.len() - 1;
+        bad_utf8[last] = 0xff;
+        for broken in [
+            &bad_magic[..],
+            &bytes[..bytes.len() - 1],
+            &bytes[..3],
+            &trailing[..],
+            &bad_utf8[..],
+        ] {
+            assert!(target.read_snapshot(broken).is_err());
+            assert_eq!(target.scan(..), vec![("kept".to_string(), String::new())]);
+        }
+        let missing = std::env::temp_dir().join("no_such_snapshot_file");
+        assert!(target.load(missing).is_err());
+    }
+}
@@ -0,0 +1,797 @@
+// Serializable snapshot isolation over a versioned BPlusTree, following the approach
+// PostgreSQL takes (Cahill et al. and Ports & Grittner).
+//
+// Every committed write is stored in the tree under `key\0<commit timestamp>`, so a
+// transaction reads the newest version at or below its snapshot and never blocks.
+// Reads (including the ranges covered by scans) and writes are tracked per
+// transaction, and a rw-antidependency T1 -rw-> T2 is recorded whenever T1 reads
+// something that a concurrent T2 writes a newer version of. Two consecutive edges
+// T1 -rw-> T2 -rw-> T3 where T3 committed first form the dangerous structure every
+// non-serializable history contains, and one of the transactions is aborted.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ops::{Bound, RangeBounds};
+use std::sync::Mutex;
+
+use crate::lock::TransactionId;
+use crate::{after_start, before_end, str_bounds, BPlusTree};
+
+const VERSION_SEPARATOR: char = '\0';
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SsiError {
+    // the transaction was aborted so that the remaining ones stay serializable
+    SerializationFailure,
+    // the transaction already failed and can only be dropped
+    Aborted,
+    // the key contains the NUL character versions are separated by; nothing was
+    // written and the transaction carries on
+    InvalidKey,
+}
+
+// callers reject keys containing the separator before they get here
+pub(crate) fn version_key(key: &str, timestamp: u64) -> String {
+    format!("{}{}{:020}", key, VERSION_SEPARATOR, timestamp)
+}
+
+pub(crate) fn parse_version_key(versioned: &str) -> (&str, u64) {
+    let (key, timestamp) = versioned.rsplit_once(VERSION_SEPARATOR).unwrap();
+    (key, timestamp.parse().unwrap())
+}
+
+// translates a range of user keys into the range covering all of their versions
+pub(crate) fn version_bounds(
+    start: Bound<&str>,
+    end: Bound<&str>,
+) -> (Bound<String>, Bound<String>) {
+    let start = match start {
+        Bound::Included(key) => Bound::Included(key.to_string()),
+        Bound::Excluded(key) => Bound::Included(format!("{}\u{1}", key)),
+        Bound::Unbounded => Bound::Unbounded,
+    };
+    let end = match end {
+        Bound::Included(key) => Bound::Excluded(format!("{}\u{1}", key)),
+        Bound::Excluded(key) => Bound::Excluded(key.to_string()),
+        Bound::Unbounded => Bound::Unbounded,
+    };
+    (start, end)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Status {
+    Active,
+    Committed(u64),
+    Aborted,
+}
+
+#[derive(Debug)]
+struct TransactionRecord {
+    snapshot: u64,
+    status: Status,
+    reads: HashSet<String>,
+    predicates: Vec<(Bound<String>, Bound<String>)>,
+    writes: BTreeMap<String, Option<String>>,
+    // transactions with an rw-antidependency into this one
+    in_conflicts: HashSet<TransactionId>,
+    // transactions this one has an rw-antidependency into
+    out_conflicts: HashSet<TransactionId>,
+}
+
+impl TransactionRecord {
+    fn read_covers(&self, key: &str) -> bool {
+        self.reads.contains(key)
+            || self.predicates.iter().any(|(start, end)| {
+                after_start(key, start.as_ref().map(|s| s.as_str()))
+                    && before_end(key, end.as_ref().map(|s| s.as_str()))
+            })
+    }
+
+    // uncommitted transactions are treated as committing at the end of time
+    fn commit_order(&self) -> u64 {
+        match self.status {
+            Status::Committed(timestamp) => timestamp,
+            _ => u64::MAX,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct SsiState {
+    tree: BPlusTree,
+    clock: u64,
+    next_transaction: TransactionId,
+    transactions: HashMap<TransactionId, TransactionRecord>,
+    committed_by: HashMap<u64, TransactionId>,
+}
+
+impl SsiState {
+    fn live(&self, id: TransactionId) -> Option<&TransactionRecord> {
+        self.transactions
+            .get(&id)
+            .filter(|record| record.status != Status::Aborted)
+    }
+
+    fn check_active(&self, id: TransactionId) -> Result<(), SsiError> {
+        match self.transactions.get(&id).map(|record| record.status) {
+            Some(Status::Active) => Ok(()),
+            _ => Err(SsiError::SerializationFailure),
+        }
+    }
+
+    fn concurrent(&self, a: TransactionId, b: TransactionId) -> bool {
+        match (self.live(a), self.live(b)) {
+            (Some(a), Some(b)) => a.commit_order() > b.snapshot && b.commit_order() > a.snapshot,
+            _ => false,
+        }
+    }
+
+    // aborts either the pivot or, if it already committed, the reader leading into it
+    fn resolve(
+        &mut self,
+        current: TransactionId,
+        pivot: TransactionId,
+        reader: TransactionId,
+    ) -> Result<(), SsiError> {
+        let victim = if self.transactions[&pivot].status == Status::Active {
+            pivot
+        } else {
+            reader
+        };
+        self.transactions.get_mut(&victim).unwrap().status = Status::Aborted;
+        if victim == current {
+            return Err(SsiError::SerializationFailure);
+        }
+        Ok(())
+    }
+
+    fn add_conflict(
+        &mut self,
+        current: TransactionId,
+        reader: TransactionId,
+        writer: TransactionId,
+    ) -> Result<(), SsiError> {
+        if reader == writer || !self.concurrent(reader, writer) {
+            return Ok(());
+        }
+        self.transactions
+            .get_mut(&reader)
+            .unwrap()
+            .out_conflicts
+            .insert(writer);
+        self.transactions
+            .get_mut(&writer)
+            .unwrap()
+            .in_conflicts
+            .insert(reader);
+
+        // writer as the pivot: reader -rw-> writer -rw-> out, with out committed first
+        let writer_record = &self.transactions[&writer];
+        let reader_order = self.transactions[&reader].commit_order();
+        let pivot_out = writer_record
+            .out_conflicts
+            .iter()
+            .any(|out| match self.live(*out) {
+                Some(out) => out.commit_order() < writer_record.commit_order().min(reader_order),
+                None => false,
+            });
+        if pivot_out {
+            return self.resolve(current, writer, reader);
+        }
+
+        // reader as the pivot: in -rw-> reader -rw-> writer, with writer committed first
+        let reader_record = &self.transactions[&reader];
+        let writer_order = writer_record.commit_order();
+        let pivot_in =
+            reader_record
+                .in_conflicts
+                .iter()
+                .copied()
+                .find(|source| match self.live(*source) {
+                    Some(source) => writer_order < source.commit_order().min(reader_order),
+                    None => false,
+                });
+        if let Some(source) = pivot_in {
+            return self.resolve(current, reader, source);
+        }
+        Ok(())
+    }
+
+    // the transactions that wrote a version of `key` invisible to `id`
+    fn hidden_writers(&self, id: TransactionId, key: &str) -> Vec<TransactionId> {
+        let snapshot = self.transactions[&id].snapshot;
+        let (start, end) = version_bounds(Bound::Included(key), Bound::Included(key));
+        let mut writers: Vec<TransactionId> = self
+            .tree
+            .scan((start, end))
+            .iter()
+            .map(|(versioned, _)| parse_version_key(versioned).1)
+            .filter(|timestamp| *timestamp > snapshot)
+            .filter_map(|timestamp| self.committed_by.get(&timestamp).copied())
+            .collect();
+        for (other, record) in self.transactions.iter() {
+            if *other != id && record.status == Status::Active && record.writes.contains_key(key) {
+                writers.push(*other);
+            }
+        }
+        writers
+    }
+
+    fn record_read(&mut self, id: TransactionId, key: &str) -> Result<(), SsiError> {
+        self.transactions
+            .get_mut(&id)
+            .unwrap()
+            .reads
+            .insert(key.to_string());
+        for writer in self.hidden_writers(id, key) {
+            self.add_conflict(id, id, writer)?;
+        }
+        Ok(())
+    }
+
+    fn record_write(&mut self, id: TransactionId, key: &str) -> Result<(), SsiError> {
+        // first committer wins between concurrent writers of the same key
+        let snapshot = self.transactions[&id].snapshot;
+        let (start, end) = version_bounds(Bound::Included(key), Bound::Included(key));
+        let newer = self
+            .tree
+            .scan((start, end))
+            .iter()
+            .any(|(versioned, _)| parse_version_key(versioned).1 > snapshot);
+        if newer {
+            self.transactions.get_mut(&id).unwrap().status = Status::Aborted;
+            return Err(SsiError::SerializationFailure);
+        }
+
+        let readers: Vec<TransactionId> = self
+            .transactions
+            .iter()
+            .filter(|(other, record)| **other != id && record.read_covers(key))
+            .map(|(other, _)| *other)
+            .collect();
+        for reader in readers {
+            self.add_conflict(id, reader, id)?;
+        }
+        Ok(())
+    }
+
+    // the newest version of every key in the range visible at `snapshot`
+    fn snapshot_scan(
+        &self,
+        snapshot: u64,
+        start: Bound<&str>,
+        end: Bound<&str>,
+    ) -> BTreeMap<String, String> {
+        let mut visible = BTreeMap::new();
+        for (versioned, value) in self.tree.scan(version_bounds(start, end)) {
+            let (key, timestamp) = parse_version_key(&versioned);
+            if timestamp <= snapshot {
+                visible.insert(key.to_string(), value);
+            }
+        }
+        visible
+    }
+
+    fn oldest_snapshot(&self) -> u64 {
+        self.transactions
+            .values()
+            .filter(|record| record.status == Status::Active)
+            .map(|record| record.snapshot)
+            .min()
+            .unwrap_or(self.clock)
+    }
+
+    fn finish(&mut self) {
+        // a committed transaction only matters while some active one overlaps it
+        let oldest_snapshot = self.oldest_snapshot();
+        self.transactions.retain(|_, record| match record.status {
+            Status::Active => true,
+            Status::Committed(timestamp) => timestamp > oldest_snapshot,
+            Status::Aborted => false,
+        });
+        let transactions = &self.transactions;
+        self.committed_by
+            .retain(|_, id| transactions.contains_key(id));
+    }
+}
+
+#[derive(Debug)]
+pub struct SsiTree {
+    state: Mutex<SsiState>,
+}
+
+impl Default for SsiTree {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SsiTree {
+    pub fn new() -> Self {
+        SsiTree {
+            state: Mutex::new(SsiState {
+                tree: BPlusTree::new(),
+                clock: 0,
+                next_transaction: 1,
+                transactions: HashMap::new(),
+                committed_by: HashMap::new(),
+            }),
+        }
+    }
+
+    pub fn begin(&self) -> SsiTransaction<'_> {
+        let mut state = self.state.lock().unwrap();
+        let id = state.next_transaction;
+        state.next_transaction += 1;
+        let snapshot = state.clock;
+        state.transactions.insert(
+            id,
+            TransactionRecord {
+                snapshot,
+                status: Status::Active,
+                reads: HashSet::new(),
+                predicates: Vec::new(),
+                writes: BTreeMap::new(),
+                in_conflicts: HashSet::new(),
+                out_conflicts: HashSet::new(),
+            },
+        );
+        SsiTransaction {
+            owner: self,
+            id,
+            finished: false,
+        }
+    }
+
+    // drops versions that no active or future snapshot can see anymore
+    pub fn vacuum(&self) {
+        let mut state = self.state.lock().unwrap();
+        let oldest_snapshot = state.oldest_snapshot();
+
+        let mut obsolete = Vec::new();
+        let entries = state.tree.scan(..);
+        for versions in
+            entries.chunk_by(|a, b| parse_version_key(&a.0).0 == parse_version_key(&b.0).0)
+        {
+            let visible = versions
+                .iter()
+                .take_while(|(versioned, _)| parse_version_key(versioned).1 <= oldest_snapshot)
+                .count();
+            // everything below the newest version visible to all snapshots is shadowed
+            for (versioned, _) in versions.iter().take(visible.saturating_sub(1)) {
+                obsolete.push(versioned.clone());
+            }
+            // and a tombstone with nothing newer hides nothing anymore
+            if visible == versions.len() && versions[visible - 1].1 == "-" {
+                obsolete.push(versions[visible - 1].0.clone());
+            }
+        }
+        for versioned in obsolete {
+            state.tree.delete(versioned);
+        }
+    }
+}
+
+// Writes are buffered in the transaction record and only become versions on commit.
+#[derive(Debug)]
+pub struct SsiTransaction<'a> {
+    owner: &'a SsiTree,
+    id: TransactionId,
+    finished: bool,
+}
+
+impl SsiTransaction<'_> {
+    pub fn id(&self) -> TransactionId {
+        self.id
+    }
+
+    fn state(&self) -> Result<std::sync::MutexGuard<'_, SsiState>, SsiError> {
+        if self.finished {
+            return Err(SsiError::Aborted);
+        }
+        let state = self.owner.state.lock().unwrap();
+        state.check_active(self.id)?;
+        Ok(state)
+    }
+
+    fn fail<T>(&mut self, result: Result<T, SsiError>) -> Result<T, SsiError> {
+        if result.is_err() {
+            self.abort_in_place();
+        }
+        result
+    }
+
+    pub fn get(&mut self, key: &str) -> Result<Option<String>, SsiError> {
+        let result = self.state().and_then(|mut state| {
+            if let Some(write) = state.transactions[&self.id].writes.get(key) {
+                return Ok(write.clone());
+            }
+            state.record_read(self.id, key)?;
+            let snapshot = state.transactions[&self.id].snapshot;
+            let visible = state.snapshot_scan(snapshot, Bound::Included(key), Bound::Included(key));
+            Ok(visible
+                .into_values()
+                .next()
+                .and_then(|value| value.strip_prefix('+').map(|v| v.to_string())))
+        });
+        self.fail(result)
+    }
+
+    pub fn scan<R: RangeBounds<String>>(
+        &mut self,
+        range: R,
+    ) -> Result<Vec<(String, String)>, SsiError> {
+        let result = self.state().and_then(|mut state| {
+            let (start, end) = str_bounds(&range);
+            let snapshot = state.transactions[&self.id].snapshot;
+
+            // the scanned range is a predicate read, covering keys that do not exist yet
+            state
+                .transactions
+                .get_mut(&self.id)
+                .unwrap()
+                .predicates
+                .push((range.start_bound().cloned(), range.end_bound().cloned()));
+
+            let (version_start, version_end) = version_bounds(start, end);
+            let mut writers: Vec<TransactionId> = state
+                .tree
+                .scan((version_start, version_end))
+                .iter()
+                .map(|(versioned, _)| parse_version_key(versioned).1)
+                .filter(|timestamp| *timestamp > snapshot)
+                .filter_map(|timestamp| state.committed_by.get(&timestamp).copied())
+                .collect();
+            for (other, record) in state.transactions.iter() {
+                let writes_in_range = record
+                    .writes
+                    .keys()
+                    .any(|key| after_start(key, start) && before_end(key, end));
+                if *other != self.id && record.status == Status::Active && writes_in_range {
+                    writers.push(*other);
+                }
+            }
+            for writer in writers {
+                state.add_conflict(self.id, self.id, writer)?;
+            }
+
+            let mut result: BTreeMap<String, String> = state
+                .snapshot_scan(snapshot, start, end)
+                .into_iter()
+                .filter_map(|(key, value)| value.strip_prefix('+').map(|v| (key, v.to_string())))
+                .collect();
+            for (key, write) in state.transactions[&self.id].writes.iter() {
+                if !after_start(key, start) || !before_end(key, end) {
+                    continue;
+                }
+                match write {
+                    Some(value) => result.insert(key.clone(), value.clone()),
+                    None => result.remove(key),
+                };
+            }
+            Ok(result.into_iter().collect())
+        });
+        self.fail(result)
+    }
+
+    pub(crate) fn write(&mut self, key: String, value: Option<String>) -> Result<(), SsiError> {
+        if key.contains(VERSION_SEPARATOR) {
+            return Err(SsiError::InvalidKey);
+        }
+        let result = self.state().and_then(|mut state| {
+            state.record_write(self.id, &key)?;
+            state
+                .transactions
+                .get_mut(&self.id)
+                .unwrap()
+                .writes
+                .insert(key, value);
+            Ok(())
+        });
+        self.fail(result)
+    }
+
+    pub fn insert(&mut self, key: String, value: String) -> Result<(), SsiError> {
+        self.write(key, Some(value))
+    }
+
+    pub fn delete(&mut self, key: String) -> Result<(), SsiError> {
+        self.write(key, None)
+    }
+
+    pub fn commit(mut self) -> Result<(), SsiError> {
+        let id = self.id;
+        let result = self.state().and_then(|mut state| {
+            let commit_timestamp = state.clock + 1;
+            let record = &state.transactions[&id];
+
+            // first committer wins, also against writers that committed after our write
+            let snapshot = record.snapshot;
+            let overwritten = record.writes.keys().any(|key| {
+                let (start, end) = version_bounds(Bound::Included(key), Bound::Included(key));
+                state
+                    .tree
+                    .scan((start, end))
+                    .iter()
+                    .any(|(versioned, _)| parse_version_key(versioned).1 > snapshot)
+            });
+            if overwritten {
+                state.transactions.get_mut(&id).unwrap().status = Status::Aborted;
+                return Err(SsiError::SerializationFailure);
+            }
+
+            // this transaction as the pivot, with an out-conflict that already committed
+            let out_committed = record
+                .out_conflicts
+                .iter()
+                .filter_map(|out| state.live(*out))
+                .filter_map(|out| match out.status {
+                    Status::Committed(timestamp) => Some(timestamp),
+                    _ => None,
+                })
+                .min();
+            if let Some(out_timestamp) = out_committed {
+                let has_in = record.in_conflicts.iter().any(|source| {
+                    state
+                        .live(*source)
+                        .is_some_and(|source| source.commit_order() > out_timestamp)
+                });
+                if has_in {
+                    state.transactions.get_mut(&id).unwrap().status = Status::Aborted;
+                    return Err(SsiError::SerializationFailure);
+                }
+            }
+
+            // this transaction as the first committer after a pivot that is still running
+            let pivots: Vec<TransactionId> = record
+                .in_conflicts
+                .iter()
+                .copied()
+                .filter(|pivot| {
+                    state
+                        .live(*pivot)
+                        .is_some_and(|pivot| pivot.status == Status::Active)
+                })
+                .collect();
+            for pivot in pivots {
+                let source = state.transactions[&pivot]
+                    .in_conflicts
+                    .iter()
+                    .copied()
+                    .find(|source| {
+                        state
+                            .live(*source)
+                            .is_some_and(|source| source.status == Status::Active)
+                    });
+                if let Some(source) = source {
+                    state.resolve(id, pivot, source)?;
+                }
+            }
+
+            let writes = std::mem::take(&mut state.transactions.get_mut(&id).unwrap().writes);
+            for (key, write) in writes.iter() {
+                let encoded = match write {
+                    Some(value) => format!("+{}", value),
+                    None => "-".to_string(),
+                };
+                state
+                    .tree
+                    .insert(version_key(key, commit_timestamp), encoded);
+            }
+            state.transactions.get_mut(&id).unwrap().status = Status::Committed(commit_timestamp);
+            state.clock = commit_timestamp;
+            state.committed_by.insert(commit_timestamp, id);
+            state.finish();
+            Ok(())
+        });
+        self.finished = true;
+        if result.is_err() {
+            self.owner.state.lock().unwrap().finish();
+        }
+        result
+    }
+
+    pub fn abort(mut self) {
+        self.abort_in_place();
+    }
+
+    fn abort_in_place(&mut self) {
+        if self.finished {
+            return;
+        }
+        self.finished = true;
+        // a panic elsewhere poisoned the state, and unwinding must not panic again
+        let Ok(mut state) = self.owner.state.lock() else {
+            return;
+        };
+        if let Some(record) = state.transactions.get_mut(&self.id) {
+            if record.status == Status::Active {
+                record.status = Status::Aborted;
+            }
+        }
+        state.finish();
+    }
+}
+
+impl Drop for SsiTransaction<'_> {
+    fn drop(&mut self) {
+        self.abort_in_place();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tree_with(entries: &[(&str, &str)]) -> SsiTree {
+        let tree = SsiTree::new();
+        let mut transaction = tree.begin();
+        for (key, value) in entries {
+            transaction
+                .insert(key.to_string(), value.to_string())
+                .unwrap();
+        }
+        transaction.commit().unwrap();
+        tree
+    }
+
+    #[test]
+    fn reads_come_from_the_snapshot() {
+        let tree = tree_with(&[("x", "1")]);
+        let mut reader = tree.begin();
+
+        let mut writer = tree.begin();
+        writer.insert("x".to_string(), "2".to_string()).unwrap();
+        writer.insert("y".to_string(), "2".to_string()).unwrap();
+        writer.commit().unwrap();
+
+        assert_eq!(reader.get("x"), Ok(Some("1".to_string())));
+        assert_eq!(
+            reader.scan(..),
+            Ok(vec![("x".to_string(), "1".to_string())])
+        );
+        reader.commit().unwrap();
+
+        let mut later = tree.begin();
+        assert_eq!(later.get("x"), Ok(Some("2".to_string())));
+        assert_eq!(later.scan(..).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn keys_with_nul_are_rejected() {
+        let tree = tree_with(&[("x", "1")]);
+        let mut transaction = tree.begin();
+        assert_eq!(
+            transaction.insert("a\0b".to_string(), "1".to_string()),
+            Err(SsiError::InvalidKey)
+        );
+        assert_eq!(
+            transaction.delete("\0".to_string()),
+            Err(SsiError::InvalidKey)
+        );
+        transaction
+            .insert("y".to_string(), "2".to_string())
+            .unwrap();
+        transaction.commit().unwrap();
+        assert_eq!(tree.begin().scan(..).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn concurrent_writers_first_committer_wins() {
+        let tree = tree_with(&[("x", "0")]);
+        let mut first = tree.begin();
+        let mut second = tree.begin();
+        first.insert("x".to_string(), "1".to_string()).unwrap();
+        second.insert("x".to_string(), "2".to_string()).unwrap();
+
+        first.commit().unwrap();
+        assert_eq!(second.commit(), Err(SsiError::SerializationFailure));
+        assert_eq!(tree.begin().get("x"), Ok(Some("1".to_string())));
+    }
+
+    #[test]
+    fn write_skew_is_rejected() {
+        // both doctors are on call; each may go off call only if the other stays
+        let tree = tree_with(&[("alice", "on"), ("bob", "on")]);
+        let mut alice = tree.begin();
+        let mut bob = tree.begin();
+
+        assert_eq!(alice.get("bob"), Ok(Some("on".to_string())));
+        assert_eq!(bob.get("alice"), Ok(Some("on".to_string())));
+        alice
+            .insert("alice".to_string(), "off".to_string())
+            .unwrap();
+        bob.insert("bob".to_string(), "off".to_string()).unwrap();
+
+        alice.commit().unwrap();
+        assert_eq!(bob.commit(), Err(SsiError::SerializationFailure));
+
+        let mut check = tree.begin();
+        assert_eq!(check.get("bob"), Ok(Some("on".to_string())));
+    }
+
+    #[test]
+    fn write_skew_through_predicate_reads_is_rejected() {
+        // a room may only be booked once; both transactions see it free
+        let tree = tree_with(&[("room1/", "")]);
+        let range = || "room1/".to_string().."room1/\u{10ffff}".to_string();
+        let mut first = tree.begin();
+        let mut second = tree.begin();
+
+        assert_eq!(first.scan(range()).unwrap().len(), 1);
+        assert_eq!(second.scan(range()).unwrap().len(), 1);
+        first
+            .insert("room1/alice".to_string(), "9am".to_string())
+            .unwrap();
+        second
+            .insert("room1/bob".to_string(), "9am".to_string())
+            .unwrap();
+
+        first.commit().unwrap();
+        assert_eq!(second.commit(), Err(SsiError::SerializationFailure));
+        assert_eq!(tree.begin().scan(range()).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn read_only_anomaly_is_rejected() {
+        // Fekete et al.: checking x, savings y, withdrawals from x overdrawn past
+        // x + y cost a penalty
+        let tree = tree_with(&[("x", "0"), ("y", "0")]);
+        let mut withdraw = tree.begin();
+        withdraw.get("x").unwrap();
+        withdraw.get("y").unwrap();
+
+        let mut deposit = tree.begin();
+        deposit.get("y").unwrap();
+        deposit.insert("y".to_string(), "20".to_string()).unwrap();
+        deposit.commit().unwrap();
+
+        let mut report = tree.begin();
+        assert_eq!(report.get("x"), Ok(Some("0".to_string())));
+        assert_eq!(report.get("y"), Ok(Some("20".to_string())));
+        report.commit().unwrap();
+
+        // the report already saw the deposit without the penalty-free withdrawal
+        assert_eq!(
+            withdraw.insert("x".to_string(), "-11".to_string()),
+            Err(SsiError::SerializationFailure)
+        );
+        assert_eq!(withdraw.commit(), Err(SsiError::Aborted));
+    }
+
+    #[test]
+    fn disjoint_transactions_both_commit() {
+        let tree = tree_with(&[("a", "0"), ("b", "0")]);
+        let mut first = tree.begin();
+        let mut second = tree.begin();
+
+        first.get("a").unwrap();
+        second.get("b").unwrap();
+        first.insert("a".to_string(), "1".to_string()).unwrap();
+        second.insert("b".to_string(), "1".to_string()).unwrap();
+
+        first.commit().unwrap();
+        second.commit().unwrap();
+    }
+
+    #[test]
+    fn vacuum_keeps_visible_versions() {
+        let tree = tree_with(&[("a", "0"), ("b", "0")]);
+        let mut old_reader = tree.begin();
+
+        for value in 1..4 {
+            let mut writer = tree.begin();
+            writer.insert("a".to_string(), value.to_string()).unwrap();
+            writer.delete("b".to_string()).unwrap();
+            writer.commit().unwrap();
+        }
+        tree.vacuum();
+
+        assert_eq!(old_reader.get("a"), Ok(Some("0".to_string())));
+        assert_eq!(old_reader.get("b"), Ok(Some("0".to_string())));
+        old_reader.commit().unwrap();
+
+        tree.vacuum();
+        assert_eq!(tree.state.lock().unwrap().tree.scan(..).len(), 1);
+        assert_eq!(tree.begin().get("a"), Ok(Some("3".to_string())));
+    }
+}
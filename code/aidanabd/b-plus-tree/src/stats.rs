@@ -0,0 +1,287 @@
+// Statistics for capacity planning and fanout tuning. `stats` walks the whole arena
+// once and reports the shape of the tree, how full its nodes are, how large the
+// stored keys and values are on average, and how much memory the tree occupies
+// against how much it has reserved. Splits, merges and `remove_node` calls are
+// counted as they happen, from the creation of the tree; the tree never
+// redistributes entries between siblings, so there is nothing to count for that.
+//
+// Bytes are counted for the arena, the vectors and strings of every node and the
+// overflow pages: "used" at their current lengths, "allocated" at their capacities.
+// Shared allocator overhead is not included.
+
+use std::fmt;
+use std::mem::size_of;
+
+use crate::overflow::LeafValue;
+use crate::{ArrayNode, BPlusTree, NodeValue};
+
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct Counters {
+    pub(crate) splits: usize,
+    pub(crate) merges: usize,
+    pub(crate) removed_nodes: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Stats {
+    // levels from the root to the leaves, a lone leaf being 1
+    pub height: usize,
+    pub internal_nodes: usize,
+    pub leaf_nodes: usize,
+    pub entries: usize,
+    // writes waiting in lazy leaf buffers, not counted in `entries`
+    pub pending_writes: usize,
+    // how many leaves and internal nodes hold each number of keys
+    pub leaf_fill: Vec<usize>,
+    pub internal_fill: Vec<usize>,
+    // keys per node against the fanout, the keys a node holds before splitting
+    pub average_fill: f64,
+    // in bytes, over the entries stored in leaves
+    pub average_key_size: f64,
+    pub average_value_size: f64,
+    pub bytes_used: usize,
+    pub bytes_allocated: usize,
+    pub splits: usize,
+    pub merges: usize,
+    pub removed_nodes: usize,
+}
+
+// bytes of a vector's elements, used and allocated
+fn vec_bytes<T>(items: &Vec<T>) -> (usize, usize) {
+    (
+        items.len() * size_of::<T>(),
+        items.capacity() * size_of::<T>(),
+    )
+}
+
+fn count_fill(histogram: &mut Vec<usize>, keys: usize) {
+    if histogram.len() <= keys {
+        histogram.resize(keys + 1, 0);
+    }
+    histogram[keys] += 1;
+}
+
+impl ArrayNode {
+    // bytes behind the node, not counting the node itself
+    fn heap_bytes(&self) -> (usize, usize) {
+        let mut parts = vec![
+            self.keys.heap_bytes(),
+            vec_bytes(&self.buffer),
+            vec_bytes(&self.counts),
+            vec_bytes(&self.summaries),
+        ];
+        for (key, message) in self.buffer.iter() {
+            parts.push((key.len(), key.capacity()));
+            if let Some(value) = message.leaf_value() {
+                parts.push((value.len(), value.capacity()));
+            }
+        }
+        for summaries in self.summaries.iter() {
+            parts.push(vec_bytes(summaries));
+        }
+        match self.values {
+            NodeValue::Internal(ref children) => parts.push(vec_bytes(children)),
+            NodeValue::Leaf(ref values) => {
+                parts.push(vec_bytes(values));
+                for value in values.iter() {
+                    if let LeafValue::Inline(value) = value {
+                        parts.push((value.len(), value.capacity()));
+                    }
+                }
+            }
+        }
+        parts
+            .into_iter()
+            .fold((0, 0), |(used, allocated), (more_used, more_allocated)| {
+                (used + more_used, allocated + more_allocated)
+            })
+    }
+}
+
+impl BPlusTree {
+    pub fn stats(&self) -> Stats {
+        let mut stats = Stats {
+            height: self.height(self.root_index),
+            internal_nodes: 0,
+            leaf_nodes: 0,
+            entries: 0,
+            pending_writes: 0,
+            leaf_fill: vec![0; self.fanout + 1],
+            internal_fill: vec![0; self.fanout + 1],
+            average_fill: 0.0,
+            average_key_size: 0.0,
+            average_value_size: 0.0,
+            bytes_used: 0,
+            bytes_allocated: 0,
+            splits: self.counters.splits,
+            merges: self.counters.merges,
+            removed_nodes: self.counters.removed_nodes,
+        };
+
+        let (used, allocated) = vec_bytes(&self.nodes);
+        let (pages_used, pages_allocated) = self.overflow.heap_bytes();
+        stats.bytes_used = used + pages_used;
+        stats.bytes_allocated = allocated + pages_allocated;
+
+        let mut keys_in_nodes = 0;
+        let mut key_bytes = 0;
+        let mut value_bytes = 0;
+        for node in self.nodes.iter() {
+            let (used, allocated) = node.heap_bytes();
+            stats.bytes_used += used;
+            stats.bytes_allocated += allocated;
+            stats.pending_writes += node.buffer.len();
+            keys_in_nodes += node.keys.len();
+
+            match node.values {
+                NodeValue::Internal(_) => {
+                    stats.internal_nodes += 1;
+                    count_fill(&mut stats.internal_fill, node.keys.len());
+                }
+                NodeValue::Leaf(ref values) => {
+                    stats.leaf_nodes += 1;
+                    stats.entries += values.len();
+                    count_fill(&mut stats.leaf_fill, node.keys.len());
+                    key_bytes += node
+                        .keys
+                        .iter()
+                        .map(|key| key.to_cow().len())
+                        .sum::<usize>();
+                    value_bytes += values
+                        .iter()
+                        .map(|value| match value {
+                            LeafValue::Inline(value) => value.len(),
+                            LeafValue::Overflow { length, .. } => *length,
+                        })
+                        .sum::<usize>();
+                }
+            }
+        }
+
+        stats.average_fill = keys_in_nodes as f64 / (self.nodes.len() * self.fanout) as f64;
+        if stats.entries > 0 {
+            stats.average_key_size = key_bytes as f64 / stats.entries as f64;
+            stats.average_value_size = value_bytes as f64 / stats.entries as f64;
+        }
+        stats
+    }
+}
+
+// a report with one bar per fill level, for a terminal
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "height {}, {} internal and {} leaf nodes, {} entries, {} pending writes",
+            self.height, self.internal_nodes, self.leaf_nodes, self.entries, self.pending_writes
+        )?;
+        writeln!(
+            f,
+            "average fill {:.1}%, average key {:.1} bytes, average value {:.1} bytes",
+            self.average_fill * 100.0,
+            self.average_key_size,
+            self.average_value_size
+        )?;
+        writeln!(
+            f,
+            "{} bytes used of {} allocated",
+            self.bytes_used, self.bytes_allocated
+        )?;
+        writeln!(
+            f,
+            "{} splits, {} merges, {} nodes removed",
+            self.splits, self.merges, self.removed_nodes
+        )?;
+        for (kind, histogram, total) in [
+            ("leaf", &self.leaf_fill, self.leaf_nodes),
+            ("internal", &self.internal_fill, self.internal_nodes),
+        ] {
+            if total == 0 {
+                continue;
+            }
+            writeln!(f, "{} fill:", kind)?;
+            for (keys, nodes) in histogram.iter().enumerate() {
+                let width = (nodes * 40).div_ceil(total);
+                writeln!(f, "  {:>2} keys {:>8} {}", keys, nodes, "#".repeat(width))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(i: usize) -> String {
+        format!("{:04}", i)
+    }
+
+    fn check_shape(tree: &BPlusTree, stats: &Stats) {
+        assert_eq!(stats.entries, tree.len());
+        assert_eq!(stats.internal_nodes + stats.leaf_nodes, tree.nodes.len());
+        assert_eq!(stats.leaf_fill.iter().sum::<usize>(), stats.leaf_nodes);
+        assert_eq!(
+            stats.internal_fill.iter().sum::<usize>(),
+            stats.internal_nodes
+        );
+        assert!(stats.bytes_used <= stats.bytes_allocated);
+    }
+
+    #[test]
+    fn counters_follow_splits_and_merges() {
+        let mut tree = BPlusTree::new();
+        let stats = tree.stats();
+        assert_eq!((stats.height, stats.leaf_nodes, stats.entries), (1, 1, 0));
+        assert_eq!((stats.splits, stats.merges, stats.removed_nodes), (0, 0, 0));
+
+        for i in 0..=crate::FANOUT {
+            tree.insert(key(i), "value".to_string());
+        }
+        let stats = tree.stats();
+        check_shape(&tree, &stats);
+        assert_eq!((stats.height, stats.splits, stats.merges), (2, 1, 0));
+        assert_eq!(stats.average_key_size, 4.0);
+        assert_eq!(stats.average_value_size, 5.0);
+
+        for i in 0..1000 {
+            tree.insert(key(i), "value".to_string());
+        }
+        let grown = tree.stats();
+        check_shape(&tree, &grown);
+        assert!(grown.splits > stats.splits);
+        assert_eq!(grown.merges, 0);
+
+        for i in 0..1000 {
+            tree.delete(key(i));
+        }
+        let shrunk = tree.stats();
+        check_shape(&tree, &shrunk);
+        assert_eq!(
+            (shrunk.height, shrunk.leaf_nodes, shrunk.entries),
+            (1, 1, 0)
+        );
+        // counters only ever grow
+        assert_eq!(shrunk.splits, grown.splits);
+        assert!(shrunk.merges > 0);
+        assert_eq!(
+            shrunk.removed_nodes,
+            grown.leaf_nodes + grown.internal_nodes - 1
+        );
+    }
+
+    #[test]
+    fn pending_writes_are_counted_apart() {
+        let mut tree = BPlusTree::with_update_buffer(16);
+        for i in 0..10 {
+            tree.insert(key(i), String::new());
+        }
+        let stats = tree.stats();
+        assert_eq!((stats.entries, stats.pending_writes), (0, 10));
+        tree.flush();
+        let stats = tree.stats();
+        assert_eq!((stats.entries, stats.pending_writes), (10, 0));
+        check_shape(&tree, &stats);
+        assert!(stats.to_string().contains("10 entries, 0 pending writes"));
+    }
+}
@@ -0,0 +1,464 @@
+// Time-to-live expiry. `insert_with_ttl` gives an entry a deadline, kept in two
+// secondary trees next to the entries: one maps every key with a deadline to its place
+// in the other, which orders all deadlines by time, so that the entries due for
+// removal are always at its front and sweeping never looks at entries that are not.
+// Deadlines have millisecond resolution and are read from a Clock, the system clock
+// unless another one is injected, so that tests can move time by hand.
+//
+// Expired entries disappear from `get`, `scan` and `value_reader` as soon as their
+// deadline passes, but stay in the leaves until they are swept: `sweep_expired`
+// deletes up to a given number of them, merging underfull nodes the way `delete`
+// does, and every `insert_with_ttl` sweeps a few on the way so that a tree written
+// with deadlines does not grow without bound. Like Redis' DBSIZE, `len`, counts, ranks
+// and aggregates include expired entries until they are swept, and so do the positions
+// `select` takes, which can therefore return an expired entry.
+//
+// A plain `insert`, `delete` or any bulk removal of a key clears its deadline.
+// Snapshots hold the live entries without their deadlines.
+
+use std::fmt;
+use std::ops::{Bound, RangeBounds};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::compare::KeyOrder;
+use crate::BPlusTree;
+
+// expired entries removed by every insert_with_ttl
+const SWEEP_ON_WRITE: usize = 4;
+// the width of the deadline at the front of a queue key
+const DEADLINE_DIGITS: usize = 20;
+
+pub trait Clock: Send + Sync {
+    // the time elapsed since the Unix epoch
+    fn now(&self) -> Duration;
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Duration {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("system clock is before the Unix epoch")
+    }
+}
+
+// a clock that only moves when told to
+#[derive(Debug, Default)]
+pub struct ManualClock {
+    millis: AtomicU64,
+}
+
+impl ManualClock {
+    pub fn new(now: Duration) -> Self {
+        ManualClock {
+            millis: AtomicU64::new(now.as_millis() as u64),
+        }
+    }
+
+    pub fn advance(&self, by: Duration) {
+        self.millis
+            .fetch_add(by.as_millis() as u64, Ordering::SeqCst);
+    }
+
+    pub fn set(&self, now: Duration) {
+        self.millis.store(now.as_millis() as u64, Ordering::SeqCst);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Duration {
+        Duration::from_millis(self.millis.load(Ordering::SeqCst))
+    }
+}
+
+// keys in deadline order: the deadline in milliseconds, zero-padded so that byte order
+// is time order, followed by the key
+fn queue_key(deadline: u64, key: &str) -> String {
+    format!("{:0width$}{}", deadline, key, width = DEADLINE_DIGITS)
+}
+
+fn deadline_of(queue_key: &str) -> u64 {
+    queue_key[..DEADLINE_DIGITS]
+        .parse()
+        .expect("queue keys start with their deadline")
+}
+
+pub(crate) struct Expiry {
+    clock: Arc<dyn Clock>,
+    // every key with a deadline, ordered like the entries, to its queue key
+    deadlines: BPlusTree,
+    // queue keys in byte order, to the key spelled as in `deadlines`
+    queue: BPlusTree,
+}
+
+impl fmt::Debug for Expiry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Expiry({} deadlines)", self.deadlines.len())
+    }
+}
+
+impl Expiry {
+    fn new(clock: Arc<dyn Clock>, order: &KeyOrder) -> Self {
+        let mut deadlines = BPlusTree::new();
+        deadlines.key_order = order.clone();
+        Expiry {
+            clock,
+            deadlines,
+            queue: BPlusTree::new(),
+        }
+    }
+
+    fn now(&self) -> u64 {
+        self.clock.now().as_millis() as u64
+    }
+
+    fn set(&mut self, key: String, deadline: u64) {
+        self.forget(&key);
+        let queued = queue_key(deadline, &key);
+        self.queue.insert(queued.clone(), key.clone());
+        self.deadlines.insert(key, queued);
+    }
+
+    fn forget(&mut self, key: &str) {
+        if let Some(queued) = self.deadlines.get(key) {
+            self.queue.delete(queued);
+            self.deadlines.delete(key.to_string());
+        }
+    }
+
+    fn deadline(&self, key: &str) -> Option<u64> {
+        self.deadlines.get(key).map(|queued| deadline_of(&queued))
+    }
+
+    // whether any deadline has passed, looking at the front of the queue only
+    fn any_due(&self, now: u64) -> bool {
+        self.queue
+            .select(0)
+            .is_some_and(|(queued, _)| deadline_of(&queued) <= now)
+    }
+
+    // the keys of the first `limit` entries whose deadline passed
+    fn due(&self, now: u64, limit: usize) -> Vec<String> {
+        (0..limit)
+            .map_while(|position| self.queue.select(position))
+            .take_while(|(queued, _)| deadline_of(queued) <= now)
+            .map(|(_, key)| key)
+            .collect()
+    }
+}
+
+impl BPlusTree {
+    // reads deadlines from `clock` from now on
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        match self.expiry {
+            Some(ref mut expiry) => expiry.clock = clock,
+            None => self.expiry = Some(Box::new(Expiry::new(clock, &self.key_order))),
+        }
+    }
+
+    // inserts an entry that expires once `ttl` has passed
+    pub fn insert_with_ttl(&mut self, key: String, value: String, ttl: Duration) {
+        self.insert(key.clone(), value);
+        let key_order = &self.key_order;
+        let expiry = self
+            .expiry
+            .get_or_insert_with(|| Box::new(Expiry::new(Arc::new(SystemClock), key_order)));
+        let deadline = expiry.now().saturating_add(ttl.as_millis() as u64);
+        expiry.set(key, deadline);
+        self.sweep_expired(SWEEP_ON_WRITE);
+    }
+
+    // the time `key` has left, or None if it has no deadline or is not present
+    pub fn ttl(&self, key: &str) -> Option<Duration> {
+        let expiry = self.expiry.as_ref()?;
+        let deadline = expiry.deadline(key)?;
+        let now = expiry.now();
+        (deadline > now).then(|| Duration::from_millis(deadline - now))
+    }
+
+    // removes the deadline of `key`, returning whether it had one that had not passed
+    pub fn persist(&mut self, key: &str) -> bool {
+        if self.ttl(key).is_none() {
+            return false;
+        }
+        self.forget_deadline(key);
+        true
+    }
+
+    // deletes up to `limit` expired entries and returns how many it deleted
+    pub fn sweep_expired(&mut self, limit: usize) -> usize {
+        let Some(ref expiry) = self.expiry else {
+            return 0;
+        };
+        let due = expiry.due(expiry.now(), limit);
+        let swept = due.len();
+        for key in due {
+            self.delete(key);
+        }
+        swept
+    }
+
+    pub(crate) fn is_expired(&self, key: &str) -> bool {
+        self.expiry.as_ref().is_some_and(|expiry| {
+            expiry
+                .deadline(key)
+                .is_some_and(|deadline| deadline <= expiry.now())
+        })
+    }
+
+    pub(crate) fn hide_expired(&self, entries: &mut Vec<(String, String)>) {
+        let Some(ref expiry) = self.expiry else {
+            return;
+        };
+        let now = expiry.now();
+        if expiry.any_due(now) {
+            entries.retain(|(key, _)| expiry.deadline(key).is_none_or(|deadline| deadline > now));
+        }
+    }
+
+    pub(crate) fn forget_deadline(&mut self, key: &str) {
+        if let Some(ref mut expiry) = self.expiry {
+            expiry.forget(key);
+        }
+    }
+
+    pub(crate) fn forget_deadlines<R: RangeBounds<String>>(&mut self, range: R) {
+        let Some(ref mut expiry) = self.expiry else {
+            return;
+        };
+        let bounds = (range.start_bound().cloned(), range.end_bound().cloned());
+        for (_, queued) in expiry.deadlines.scan(bounds.clone()) {
+            expiry.queue.delete(queued);
+        }
+        expiry.deadlines.remove_range(bounds);
+    }
+
+    // the deadlines of keys at or after `key`, for the tree split off at `key`
+    pub(crate) fn split_off_deadlines(&mut self, key: &str) -> Option<Box<Expiry>> {
+        let expiry = self.expiry.as_mut()?;
+        let mut moved = Expiry::new(expiry.clock.clone(), &self.key_order);
+        for (key, queued) in expiry
+            .deadlines
+            .scan((Bound::Included(key.to_string()), Bound::Unbounded))
+        {
+            expiry.queue.delete(queued.clone());
+            moved.queue.insert(queued, key);
+        }
+        moved.deadlines = expiry.deadlines.split_off(key);
+        Some(Box::new(moved))
+    }
+
+    // takes over the deadlines of an appended tree
+    pub(crate) fn append_deadlines(&mut self, other: Option<Box<Expiry>>) {
+        let Some(other) = other else {
+            return;
+        };
+        let key_order = &self.key_order;
+        let clock = other.clock.clone();
+        let expiry = self
+            .expiry
+            .get_or_insert_with(|| Box::new(Expiry::new(clock, key_order)));
+        for (key, queued) in other.deadlines.scan(..) {
+            expiry.set(key, deadline_of(&queued));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aggregate::Monoid;
+
+    fn tree_at(clock: &Arc<ManualClock>) -> BPlusTree {
+        let mut tree = BPlusTree::new();
+        tree.set_clock(clock.clone());
+        tree
+    }
+
+    fn key(i: usize) -> String {
+        format!("k{:04}", i)
+    }
+
+    #[test]
+    fn expired_entries_are_hidden_then_swept() {
+        let clock = Arc::new(ManualClock::new(Duration::from_secs(1000)));
+        let mut tree = tree_at(&clock);
+        for i in 0..200 {
+            if i % 2 == 0 {
+                let ttl = Duration::from_millis(10 + i as u64);
+                tree.insert_with_ttl(key(i), i.to_string(), ttl);
+            } else {
+                tree.insert(key(i), i.to_string());
+            }
+        }
+        assert_eq!(tree.ttl(&key(0)), Some(Duration::from_millis(10)));
+        assert_eq!(tree.ttl(&key(1)), None);
+
+        clock.advance(Duration::from_millis(60));
+        assert_eq!(tree.get(&key(0)), None);
+        assert_eq!(tree.get(&key(60)), Some("60".to_string()));
+        assert_eq!(tree.get(&key(1)), Some("1".to_string()));
+        assert_eq!(tree.scan(..).len(), 200 - 26);
+        // expired entries stay counted until they are swept
+        assert_eq!(tree.len(), 200);
+
+        assert_eq!(tree.sweep_expired(10), 10);
+        assert_eq!(tree.sweep_expired(usize::MAX), 16);
+        assert_eq!(tree.sweep_expired(usize::MAX), 0);
+        assert_eq!(tree.len(), 200 - 26);
+        tree.validate().unwrap();
+    }
+
+    #[test]
+    fn writes_and_persist_clear_deadlines() {
+        let clock = Arc::new(ManualClock::new(Duration::ZERO));
+        let mut tree = tree_at(&clock);
+        let second = Duration::from_secs(1);
+        for i in 0..4 {
+            tree.insert_with_ttl(key(i), String::new(), second);
+        }
+        assert!(tree.persist(&key(0)));
+        assert!(!tree.persist(&key(0)));
+        tree.insert(key(1), "again".to_string());
+        tree.delete(key(2));
+        tree.insert(key(2), String::new());
+
+        clock.set(second * 2);
+        assert!(!tree.persist(&key(3)));
+        assert_eq!(tree.sweep_expired(usize::MAX), 1);
+        assert_eq!(tree.len(), 3);
+        assert_eq!(tree.get(&key(1)), Some("again".to_string()));
+    }
+
+    #[test]
+    fn deadlines_follow_their_keys() {
+        let clock = Arc::new(ManualClock::new(Duration::ZERO));
+        let mut tree = tree_at(&clock);
+        for i in 0..100 {
+            let ttl = Duration::from_secs(1 + i as u64 % 2);
+            tree.insert_with_ttl(key(i), String::new(), ttl);
+        }
+        clock.advance(Duration::from_millis(1500));
+
+        let mut upper = tree.split_off(&key(50));
+        assert_eq!(tree.sweep_expired(usize::MAX), 25);
+        assert_eq!(upper.ttl(&key(51)), Some(Duration::from_millis(500)));
+
+        // interleaved keys take the batch path, which writes them one by one
+        let mut other = tree_at(&clock);
+        other.insert_with_ttl(key(3), String::new(), Duration::from_secs(5));
+        tree.append(&mut other);
+        assert_eq!(tree.ttl(&key(3)), Some(Duration::from_secs(5)));
+
+        tree.append(&mut upper);
+        tree.set_fanout(8);
+        assert_eq!(tree.ttl(&key(51)), Some(Duration::from_millis(500)));
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(tree.sweep_expired(usize::MAX), 24 + 50);
+        assert_eq!(tree.scan(..), vec![(key(3), String::new())]);
+        tree.validate().unwrap();
+    }
+
+    #[test]
+    fn snapshots_skip_unswept_expired_entries() {
+        let clock = Arc::new(ManualClock::new(Duration::ZERO));
+        let mut tree = tree_at(&clock);
+        for i in 0..10 {
+            tree.insert(key(i), i.to_string());
+        }
+        tree.insert_with_ttl(key(4), "4".to_string(), Duration::from_secs(1));
+        tree.insert_with_ttl(key(10), "10".to_string(), Duration::from_secs(60));
+        clock.advance(Duration::from_secs(2));
+        assert_eq!(tree.len(), 11);
+        assert_eq!(tree.scan(..).len(), 10);
+
+        let mut bytes = Vec::new();
+        tree.write_snapshot(&mut bytes).unwrap();
+        let mut loaded = BPlusTree::new();
+        loaded.read_snapshot(&bytes[..]).unwrap();
+        assert_eq!(loaded.scan(..), tree.scan(..));
+        assert_eq!(loaded.len(), 10);
+        // deadlines are not saved
+        assert_eq!(loaded.ttl(&key(10)), None);
+
+        let path = std::env::temp_dir().join(format!("ttl_snapshot_{}", std::process::id()));
+        tree.save(&path).unwrap();
+        let mut loaded = BPlusTree::new();
+        loaded.load(&path).unwrap();
+        assert_eq!(loaded.get(&key(4)), None);
+        assert_eq!(loaded.get(&key(10)), Some("10".to_string()));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn order_statistics_count_unswept_expired_entries() {
+        let clock = Arc::new(ManualClock::new(Duration::ZERO));
+        let mut tree = tree_at(&clock);
+        for i in 0..20 {
+            if i % 4 == 0 {
+                tree.insert_with_ttl(key(i), i.to_string(), Duration::from_secs(1));
+            } else {
+                tree.insert(key(i), i.to_string());
+            }
+        }
+        clock.advance(Duration::from_secs(2));
+
+        assert_eq!(tree.count(..), 20);
+        assert_eq!(tree.count(key(0)..key(10)), 10);
+        assert_eq!(tree.rank(&key(5)), 5);
+        // positions include expired entries, so select can return one that get hides
+        assert_eq!(tree.select(4), Some((key(4), "4".to_string())));
+        assert_eq!(tree.get(&key(4)), None);
+        for position in 0..20 {
+            let (key, _) = tree.select(position).unwrap();
+            assert_eq!(tree.rank(&key), position);
+        }
+
+        tree.sweep_expired(usize::MAX);
+        assert_eq!(tree.count(..), 15);
+        assert_eq!(tree.count(key(0)..key(10)), 7);
+        assert_eq!(tree.rank(&key(5)), 3);
+        assert_eq!(tree.select(4), Some((key(6), "6".to_string())));
+        let live: Vec<_> = (0..15)
+            .filter_map(|position| tree.select(position))
+            .collect();
+        assert_eq!(live, tree.scan(..));
+    }
+
+    #[test]
+    fn aggregates_include_unswept_expired_entries() {
+        struct Sum;
+
+        impl Monoid for Sum {
+            type Summary = u64;
+
+            fn identity(&self) -> u64 {
+                0
+            }
+
+            fn measure(&self, _: &str, value: &str) -> u64 {
+                value.parse().unwrap()
+            }
+
+            fn combine(&self, left: &u64, right: &u64) -> u64 {
+                left + right
+            }
+        }
+
+        let clock = Arc::new(ManualClock::new(Duration::ZERO));
+        let mut tree = tree_at(&clock);
+        let sum = tree.register_aggregate(Sum);
+        for i in 1..=10 {
+            tree.insert(key(i), i.to_string());
+        }
+        tree.insert_with_ttl(key(10), "10".to_string(), Duration::from_secs(1));
+        clock.advance(Duration::from_secs(2));
+        assert_eq!(tree.aggregate(sum, ..), 55);
+        tree.sweep_expired(usize::MAX);
+        assert_eq!(tree.aggregate(sum, ..), 45);
+    }
+}
@@ -0,0 +1,229 @@
+// Structural validation, for debugging sessions and tests that want to know whether
+// an operation left the tree intact. `validate` walks the tree from the root and
+// reports the first broken invariant it finds: a parent pointer that does not point
+// back, keys out of order or outside the separators of the parent, a node holding
+// more keys than the fanout, children and keys or values that do not pair up, stale
+// per-child counts, leaves at different depths, or arena nodes no parent points to.
+//
+// Underfull nodes are not an error: merges only happen when two siblings fit in one
+// node, so a tree may legitimately hold nodes with a single key.
+
+use std::cmp::Ordering;
+
+use crate::keys::Key;
+use crate::{BPlusTree, NodeValue};
+
+// the state of a walk through the tree
+struct Walk {
+    leaf_depth: Option<usize>,
+    reached: Vec<bool>,
+}
+
+impl BPlusTree {
+    // Ok if every invariant holds, or the first violation found
+    pub fn validate(&self) -> Result<(), String> {
+        let mut walk = Walk {
+            leaf_depth: None,
+            reached: vec![false; self.nodes.len()],
+        };
+        if self.nodes[self.root_index].parent.is_some() {
+            return Err(format!("root {} has a parent", self.root_index));
+        }
+        self.validate_node(self.root_index, None, None, 0, &mut walk)?;
+        match walk.reached.iter().position(|reached| !reached) {
+            Some(index) => Err(format!("node {} is not reachable from the root", index)),
+            None => Ok(()),
+        }
+    }
+
+    // checks the subtree below `index`, which may hold keys in [lower, upper)
+    fn validate_node(
+        &self,
+        index: usize,
+        lower: Option<&str>,
+        upper: Option<&str>,
+        depth: usize,
+        walk: &mut Walk,
+    ) -> Result<(), String> {
+        if std::mem::replace(&mut walk.reached[index], true) {
+            return Err(format!("node {} is reachable twice", index));
+        }
+        let node = &self.nodes[index];
+        if node.keys.len() > self.fanout {
+            return Err(format!("node {} holds {} keys", index, node.keys.len()));
+        }
+
+        let in_bounds = |key: Key<'_>| {
+            lower.is_none_or(|lower| self.key_order.cmp_key(key, lower) != Ordering::Less)
+                && upper.is_none_or(|upper| self.key_order.cmp_key(key, upper) == Ordering::Less)
+        };
+        let keys: Vec<Key<'_>> = node.keys.iter().collect();
+        for pair in keys.windows(2) {
+            if self.key_order.cmp_key(pair[0], &pair[1].to_cow()) != Ordering::Less {
+                return Err(format!(
+                    "node {} has {:?} before {:?}",
+                    index, pair[0], pair[1]
+                ));
+            }
+        }
+        if let Some(key) = keys.iter().find(|key| !in_bounds(**key)) {
+            return Err(format!(
+                "node {} holds {:?} outside [{:?}, {:?})",
+                index, key, lower, upper
+            ));
+        }
+        for pair in node.buffer.windows(2) {
+            if self.key_order.cmp(&pair[0].0, &pair[1].0) != Ordering::Less {
+                return Err(format!(
+                    "node {} buffers {:?} before {:?}",
+                    index, pair[0].0, pair[1].0
+                ));
+            }
+        }
+        if let Some((key, _)) = node
+            .buffer
+            .iter()
+            .find(|(key, _)| !in_bounds(Key::full(key)))
+        {
+            return Err(format!(
+                "node {} buffers {:?} outside [{:?}, {:?})",
+                index, key, lower, upper
+            ));
+        }
+
+        match node.values {
+            NodeValue::Leaf(ref values) => {
+                if values.len() != keys.len() {
+                    return Err(format!(
+                        "leaf {} has {} keys and {} values",
+                        index,
+                        keys.len(),
+                        values.len()
+                    ));
+                }
+                match walk.leaf_depth {
+                    Some(leaf_depth) if leaf_depth != depth => Err(format!(
+                        "leaf {} is at depth {}, other leaves at {}",
+                        index, depth, leaf_depth
+                    )),
+                    _ => {
+                        walk.leaf_depth = Some(depth);
+                        Ok(())
+                    }
+                }
+            }
+            NodeValue::Internal(ref children) => {
+                if children.len() != keys.len() + 1 {
+                    return Err(format!(
+                        "node {} has {} keys and {} children",
+                        index,
+                        keys.len(),
+                        children.len()
+                    ));
+                }
+                if node.counts.len() != children.len() || node.summaries.len() != children.len() {
+                    return Err(format!("node {} has stale per-child counts", index));
+                }
+                let separators: Vec<String> = keys.iter().map(|key| key.to_string()).collect();
+                for (position, child) in children.iter().enumerate() {
+                    if self.nodes[*child].parent != Some(index) {
+                        return Err(format!(
+                            "node {} has parent {:?}, not {}",
+                            child, self.nodes[*child].parent, index
+                        ));
+                    }
+                    let child_lower = match position {
+                        0 => lower,
+                        _ => Some(separators[position - 1].as_str()),
+                    };
+                    let child_upper = separators.get(position).map(String::as_str).or(upper);
+                    self.validate_node(*child, child_lower, child_upper, depth + 1, walk)?;
+                    if node.counts[position] != self.subtree_count(*child) {
+                        return Err(format!(
+                            "node {} counts {} entries below child {}, which holds {}",
+                            index,
+                            node.counts[position],
+                            child,
+                            self.subtree_count(*child)
+                        ));
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tree() -> BPlusTree {
+        let mut tree = BPlusTree::new();
+        for i in 0..100 {
+            tree.insert(format!("{:03}", i), String::new());
+        }
+        tree.validate().unwrap();
+        tree
+    }
+
+    fn first_leaf(tree: &BPlusTree) -> usize {
+        let mut index = tree.root_index;
+        while let NodeValue::Internal(ref children) = tree.nodes[index].values {
+            index = children[0];
+        }
+        index
+    }
+
+    #[test]
+    fn broken_invariants_are_reported() {
+        let mut unordered = tree();
+        let leaf = first_leaf(&unordered);
+        unordered.nodes[leaf].keys.set(0, "zzz".to_string());
+        assert!(unordered.validate().unwrap_err().contains("before"));
+
+        let mut stale = tree();
+        let root = stale.root_index;
+        stale.nodes[root].counts[0] += 1;
+        assert!(stale.validate().is_err());
+
+        let mut orphan = tree();
+        let leaf = first_leaf(&orphan);
+        let parent = orphan.nodes[leaf].parent.unwrap();
+        orphan.nodes[leaf].parent = Some(parent + 1);
+        assert!(orphan.validate().is_err());
+
+        let mut unreachable = tree();
+        unreachable
+            .nodes
+            .push(crate::ArrayNode::new(unreachable.fanout));
+        assert!(unreachable
+            .validate()
+            .unwrap_err()
+            .contains("not reachable"));
+
+        let mut mismatched = tree();
+        let leaf = first_leaf(&mismatched);
+        if let NodeValue::Leaf(ref mut values) = mismatched.nodes[leaf].values {
+            values.pop();
+        }
+        assert!(mismatched.validate().unwrap_err().contains("values"));
+    }
+
+    #[test]
+    fn nodes_are_sized_by_the_tree_fanout() {
+        // one key more than fits splits the root leaf once, before anything regrows
+        let mut tree = BPlusTree::with_fanout(16);
+        for i in 0..17 {
+            tree.insert(format!("{:03}", i), String::new());
+        }
+        tree.validate().unwrap();
+        assert_eq!(tree.height(tree.root_index), 2);
+        for node in tree.nodes.iter() {
+            match node.values {
+                NodeValue::Internal(ref children) => assert!(children.capacity() > 16),
+                NodeValue::Leaf(ref values) => assert!(values.capacity() >= 16),
+            }
+        }
+    }
+}
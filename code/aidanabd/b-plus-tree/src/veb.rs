@@ -0,0 +1,293 @@
+// A frozen, read-only copy of a BPlusTree in a cache-oblivious van Emde Boas layout.
+//
+// The entries reachable from the root are placed in a perfect binary search tree of
+// height h, padded with empty slots on the right. A tree of height h is stored as its
+// top half (height h / 2 rounded up) followed by each of its bottom trees from left to
+// right, all laid out the same way recursively, in one contiguous slot buffer. Every
+// run of consecutive levels is then close together in memory, whatever the size of a
+// cache line or page, so no FANOUT has to be tuned to a particular memory level.
+//
+// Children are not stored. The position of a node follows from its depth, its
+// breadth-first number and the positions of its ancestors, with three small tables per
+// depth (Brodal, Fagerberg and Jacob). Each slot keeps the first 8 bytes of its key so
+// most comparisons never leave the slot buffer; keys and values live in a separate
+// string heap in sorted order, which also makes range scans a sequential read. Byte
+// prefixes say nothing about other comparators, so their trees leave prefixes zeroed
+// and always compare whole keys.
+
+use std::cmp::Ordering;
+use std::ops::{Bound, RangeBounds};
+
+use crate::compare::KeyOrder;
+use crate::keys::Key;
+use crate::{str_bounds, BPlusTree};
+
+const EMPTY: u32 = u32::MAX;
+
+#[derive(Debug, Clone, Copy)]
+struct Slot {
+    prefix: u64,
+    entry: u32,
+}
+
+// empty slots pad the tree on the right and compare above every key
+const EMPTY_SLOT: Slot = Slot {
+    prefix: u64::MAX,
+    entry: EMPTY,
+};
+
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+    key: (u32, u32),
+    value: (u32, u32),
+}
+
+// for the bottom tree rooted at a depth: its size, the size of the top tree above it
+// and the depth of that top tree's root
+#[derive(Debug, Clone, Copy, Default)]
+struct Level {
+    bottom: usize,
+    top: usize,
+    top_depth: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct VebTree {
+    slots: Vec<Slot>,
+    entries: Vec<Entry>,
+    heap: String,
+    levels: Vec<Level>,
+    key_order: KeyOrder,
+}
+
+fn prefix(key: &str, order: &KeyOrder) -> u64 {
+    if !order.is_bytewise() {
+        return 0;
+    }
+    let mut bytes = [0u8; 8];
+    let length = key.len().min(8);
+    bytes[..length].copy_from_slice(&key.as_bytes()[..length]);
+    u64::from_be_bytes(bytes)
+}
+
+fn fill_levels(levels: &mut [Level], depth: usize, height: usize) {
+    if height <= 1 {
+        return;
+    }
+    let bottom = height / 2;
+    let top = height - bottom;
+    levels[depth + top] = Level {
+        bottom: (1 << bottom) - 1,
+        top: (1 << top) - 1,
+        top_depth: depth,
+    };
+    fill_levels(levels, depth, top);
+    fill_levels(levels, depth + top, bottom);
+}
+
+// writes the subtree of `height` rooted at breadth-first number `node` from `position`
+// on and returns the position after it
+fn lay_out(
+    slots: &mut [Slot],
+    position: usize,
+    node: usize,
+    height: usize,
+    depth: usize,
+    slot: &dyn Fn(usize, usize) -> Slot,
+) -> usize {
+    if height == 1 {
+        slots[position] = slot(node, depth);
+        return position + 1;
+    }
+    let bottom = height / 2;
+    let top = height - bottom;
+    let mut position = lay_out(slots, position, node, top, depth, slot);
+    let first = node << top;
+    for offset in 0..(1usize << top) {
+        position = lay_out(slots, position, first + offset, bottom, depth + top, slot);
+    }
+    position
+}
+
+impl VebTree {
+    fn key(&self, entry: u32) -> &str {
+        let (start, end) = self.entries[entry as usize].key;
+        &self.heap[start as usize..end as usize]
+    }
+
+    fn value(&self, entry: u32) -> &str {
+        let (start, end) = self.entries[entry as usize].value;
+        &self.heap[start as usize..end as usize]
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    // the index of the first entry not below `key` (or above it, if `inclusive` is false)
+    fn lower_bound(&self, key: &str, inclusive: bool) -> usize {
+        let mut positions = [0usize; usize::BITS as usize];
+        let key_prefix = prefix(key, &self.key_order);
+        let mut found = self.entries.len();
+        let mut node = 1;
+        for depth in 0..self.levels.len() {
+            if depth > 0 {
+                let level = self.levels[depth];
+                positions[depth] =
+                    positions[level.top_depth] + level.top + (node & level.top) * level.bottom;
+            }
+            let slot = self.slots[positions[depth]];
+            let go_left = slot.entry == EMPTY
+                || match key_prefix.cmp(&slot.prefix) {
+                    Ordering::Less => true,
+                    Ordering::Greater => false,
+                    Ordering::Equal => match self.key_order.cmp(key, self.key(slot.entry)) {
+                        Ordering::Less => true,
+                        Ordering::Equal => inclusive,
+                        Ordering::Greater => false,
+                    },
+                };
+            if go_left {
+                if slot.entry != EMPTY {
+                    found = slot.entry as usize;
+                }
+                node *= 2;
+            } else {
+                node = node * 2 + 1;
+            }
+        }
+        found
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        let index = self.lower_bound(key, true);
+        if index < self.entries.len()
+            && self.key_order.cmp(self.key(index as u32), key) == Ordering::Equal
+        {
+            Some(self.value(index as u32))
+        } else {
+            None
+        }
+    }
+
+    pub fn scan<R: RangeBounds<String>>(&self, range: R) -> Vec<(String, String)> {
+        let (start, end) = str_bounds(&range);
+        let first = match start {
+            Bound::Included(key) => self.lower_bound(key, true),
+            Bound::Excluded(key) => self.lower_bound(key, false),
+            Bound::Unbounded => 0,
+        };
+        (first..self.entries.len())
+            .map(|index| index as u32)
+            .take_while(|index| self.key_order.before_end(Key::full(self.key(*index)), end))
+            .map(|index| (self.key(index).to_string(), self.value(index).to_string()))
+            .collect()
+    }
+}
+
+impl BPlusTree {
+    pub fn freeze(&self) -> VebTree {
+        let sorted = self.scan(..);
+
+        let mut heap = String::new();
+        assert!(
+            sorted
+                .iter()
+                .map(|(key, value)| key.len() + value.len())
+                .sum::<usize>()
+                < EMPTY as usize,
+            "a frozen tree holds at most 4 GiB of keys and values"
+        );
+        let mut entries = Vec::with_capacity(sorted.len());
+        for (key, value) in &sorted {
+            let key_start = heap.len() as u32;
+            heap.push_str(key);
+            let value_start = heap.len() as u32;
+            heap.push_str(value);
+            entries.push(Entry {
+                key: (key_start, value_start),
+                value: (value_start, heap.len() as u32),
+            });
+        }
+
+        let height = (usize::BITS - sorted.len().leading_zeros()) as usize;
+        let mut levels = vec![Level::default(); height];
+        fill_levels(&mut levels, 0, height);
+
+        // in a perfect tree, the in-order rank of breadth-first node `node` at `depth`
+        let slot = |node: usize, depth: usize| {
+            let below = height - depth - 1;
+            let index = ((node - (1 << depth)) << (below + 1)) + (1 << below) - 1;
+            match sorted.get(index) {
+                Some((key, _)) => Slot {
+                    prefix: prefix(key, &self.key_order),
+                    entry: index as u32,
+                },
+                None => EMPTY_SLOT,
+            }
+        };
+        let mut slots = vec![EMPTY_SLOT; (1 << height) - 1];
+        if height > 0 {
+            lay_out(&mut slots, 0, 1, height, 0, &slot);
+        }
+
+        VebTree {
+            slots,
+            entries,
+            heap,
+            levels,
+            key_order: self.key_order.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compare::Natural;
+
+    fn check(tree: &BPlusTree, probes: &[String]) {
+        let frozen = tree.freeze();
+        assert_eq!(frozen.len(), tree.len());
+        for probe in probes {
+            assert_eq!(frozen.get(probe).map(str::to_string), tree.get(probe));
+        }
+        assert_eq!(frozen.scan(..), tree.scan(..));
+        for window in probes.windows(2) {
+            let range = window[0].clone()..=window[1].clone();
+            assert_eq!(frozen.scan(range.clone()), tree.scan(range));
+        }
+    }
+
+    #[test]
+    fn lookups_match_the_tree() {
+        // sizes around powers of two leave the last level full, empty or partly padded
+        for size in [0, 1, 2, 3, 7, 8, 9, 255, 256, 1000] {
+            let mut tree = BPlusTree::new();
+            for i in 0..size {
+                // long shared prefixes make slots fall back to whole keys
+                tree.insert(format!("shared prefix {:05}", i * 2), i.to_string());
+            }
+            let probes: Vec<String> = (0..size * 2 + 2)
+                .step_by(3)
+                .map(|i| format!("shared prefix {:05}", i))
+                .chain(["".to_string(), "z".to_string()])
+                .collect();
+            check(&tree, &probes);
+        }
+    }
+
+    #[test]
+    fn lookups_follow_the_comparator() {
+        let mut tree = BPlusTree::with_comparator(Natural);
+        for i in 0..300 {
+            tree.insert(format!("file{}", i), i.to_string());
+        }
+        let probes: Vec<String> = (0..320).step_by(7).map(|i| format!("file{}", i)).collect();
+        check(&tree, &probes);
+    }
+}
@@ -0,0 +1,465 @@
+// Key-value separation after WiscKey (Lu et al.). Values are appended to a value log
+// on disk and the BPlusTree only maps every key to a pointer to its value, so the
+// tree stays small and cheap to modify however large the values are.
+//
+// The log is a directory of numbered segment files. Each record holds the key as well
+// as the value, so that the log can be replayed into a tree when it is reopened and
+// so that garbage collection can tell whether a record is still live: it reads the
+// oldest segment, appends every record the tree still points to at the head of the
+// log, repoints the tree and deletes the segment. Deletes append a tombstone, which
+// replay needs to forget older records and which GC drops with its segment.
+//
+// Range scans collect the pointers from the tree first and then read the values with
+// several threads at once, since the reads are random and independent of each other.
+//
+// The name of the tree's comparator is kept in a COMPARATOR file next to the segments;
+// a log can only be reopened with the comparator it was written with.
+
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::ops::RangeBounds;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::thread;
+
+use crate::compare::{Bytewise, Comparator};
+use crate::BPlusTree;
+
+// key length and value length, the latter TOMBSTONE for deletes
+const HEADER: u64 = 8;
+const TOMBSTONE: u32 = u32::MAX;
+// scans reading fewer values than this do not start threads
+const PARALLEL_PREFETCH: usize = 64;
+
+// where a value lives in the log
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct ValuePointer {
+    segment: u32,
+    offset: u64,
+    length: u32,
+}
+
+impl ValuePointer {
+    fn encode(&self) -> String {
+        format!("{}:{}:{}", self.segment, self.offset, self.length)
+    }
+
+    fn decode(encoded: &str) -> ValuePointer {
+        let mut parts = encoded.split(':').map(|part| part.parse::<u64>().unwrap());
+        let (segment, offset, length) = (parts.next(), parts.next(), parts.next());
+        ValuePointer {
+            segment: segment.unwrap() as u32,
+            offset: offset.unwrap(),
+            length: length.unwrap() as u32,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Record {
+    key: String,
+    // None for a tombstone
+    value: Option<ValuePointer>,
+}
+
+const COMPARATOR_FILE: &str = "COMPARATOR";
+
+fn segment_path(dir: &Path, segment: u32) -> PathBuf {
+    dir.join(format!("{:08}.vlog", segment))
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+// reads every complete record of a segment; a record cut off by a crash ends it
+fn read_segment(file: &File, segment: u32) -> io::Result<(Vec<Record>, u64)> {
+    let mut bytes = vec![0; file.metadata()?.len() as usize];
+    file.read_exact_at(&mut bytes, 0)?;
+    let mut records = Vec::new();
+    let mut offset = 0;
+    while bytes.len() - offset >= HEADER as usize {
+        let header = &bytes[offset..offset + HEADER as usize];
+        let key_length = u32::from_le_bytes(header[..4].try_into().unwrap()) as usize;
+        let value_length = u32::from_le_bytes(header[4..].try_into().unwrap());
+        let stored = if value_length == TOMBSTONE {
+            0
+        } else {
+            value_length as usize
+        };
+        let key_start = offset + HEADER as usize;
+        let end = key_start + key_length + stored;
+        if end > bytes.len() {
+            break;
+        }
+        let key = String::from_utf8(bytes[key_start..key_start + key_length].to_vec())
+            .map_err(|_| invalid("value log key is not UTF-8"))?;
+        let value = (value_length != TOMBSTONE).then_some(ValuePointer {
+            segment,
+            offset: (key_start + key_length) as u64,
+            length: value_length,
+        });
+        records.push(Record { key, value });
+        offset = end;
+    }
+    Ok((records, offset as u64))
+}
+
+#[derive(Debug)]
+pub struct WiscKey {
+    tree: BPlusTree,
+    dir: PathBuf,
+    segments: BTreeMap<u32, File>,
+    head: u32,
+    head_length: u64,
+    segment_size: u64,
+}
+
+impl WiscKey {
+    // opens the value log in `dir`, replaying it into a fresh tree; the head segment
+    // is rolled over once it reaches `segment_size` bytes
+    pub fn open<P: AsRef<Path>>(dir: P, segment_size: u64) -> io::Result<Self> {
+        WiscKey::open_with_comparator(dir, segment_size, Bytewise)
+    }
+
+    pub fn open_with_comparator<P: AsRef<Path>, C: Comparator>(
+        dir: P,
+        segment_size: u64,
+        comparator: C,
+    ) -> io::Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        let mut numbers = Vec::new();
+        for entry in fs::read_dir(&dir)? {
+            let name = entry?.file_name();
+            let name = name.to_string_lossy();
+            if let Some(number) = name.strip_suffix(".vlog") {
+                numbers.push(
+                    number
+                        .parse::<u32>()
+                        .map_err(|_| invalid("bad segment name"))?,
+                );
+            }
+        }
+        numbers.sort_unstable();
+
+        let tree = BPlusTree::with_comparator(comparator);
+        let recorded_path = dir.join(COMPARATOR_FILE);
+        let recorded = match fs::read_to_string(&recorded_path) {
+            Ok(name) => name,
+            Err(error) if error.kind() == io::ErrorKind::NotFound && numbers.is_empty() => {
+                fs::write(&recorded_path, tree.comparator_name())?;
+                tree.comparator_name().to_string()
+            }
+            // logs from before comparators were recorded are in byte order
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Bytewise.name().to_string(),
+            Err(error) => return Err(error),
+        };
+        if recorded != tree.comparator_name() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "value log was written with comparator {:?}, not {:?}",
+                    recorded,
+                    tree.comparator_name()
+                ),
+            ));
+        }
+
+        let mut store = WiscKey {
+            tree,
+            dir,
+            segments: BTreeMap::new(),
+            head: 0,
+            head_length: 0,
+            segment_size,
+        };
+        for segment in numbers {
+            let file = store.open_segment(segment)?;
+            let (records, length) = read_segment(&file, segment)?;
+            // drop a torn record at the end of the log
+            file.set_len(length)?;
+            for record in records {
+                match record.value {
+                    Some(pointer) => store.tree.insert(record.key, pointer.encode()),
+                    None => store.tree.delete(record.key),
+                }
+            }
+            store.segments.insert(segment, file);
+            store.head = segment;
+            store.head_length = length;
+        }
+        if store.segments.is_empty() {
+            let file = store.open_segment(0)?;
+            store.segments.insert(0, file);
+        }
+        Ok(store)
+    }
+
+    fn open_segment(&self, segment: u32) -> io::Result<File> {
+        OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(segment_path(&self.dir, segment))
+    }
+
+    pub fn segments(&self) -> usize {
+        self.segments.len()
+    }
+
+    // flushes the log to disk
+    pub fn sync(&self) -> io::Result<()> {
+        self.segments[&self.head].sync_data()
+    }
+
+    fn roll_over(&mut self) -> io::Result<()> {
+        self.sync()?;
+        self.head += 1;
+        self.head_length = 0;
+        let file = self.open_segment(self.head)?;
+        self.segments.insert(self.head, file);
+        Ok(())
+    }
+
+    fn append(&mut self, key: &str, value: Option<&str>) -> io::Result<Option<ValuePointer>> {
+        if self.head_length >= self.segment_size {
+            self.roll_over()?;
+        }
+        let value_length = match value {
+            Some(value) => u32::try_from(value.len())
+                .ok()
+                .filter(|length| *length != TOMBSTONE),
+            None => Some(TOMBSTONE),
+        };
+        let (Ok(key_length), Some(value_length)) = (u32::try_from(key.len()), value_length) else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "record too large",
+            ));
+        };
+
+        let mut record =
+            Vec::with_capacity(HEADER as usize + key.len() + value.map_or(0, str::len));
+        record.extend_from_slice(&key_length.to_le_bytes());
+        record.extend_from_slice(&value_length.to_le_bytes());
+        record.extend_from_slice(key.as_bytes());
+        record.extend_from_slice(value.unwrap_or("").as_bytes());
+        (&self.segments[&self.head]).write_all(&record)?;
+
+        let pointer = value.map(|value| ValuePointer {
+            segment: self.head,
+            offset: self.head_length + HEADER + key.len() as u64,
+            length: value.len() as u32,
+        });
+        self.head_length += record.len() as u64;
+        Ok(pointer)
+    }
+
+    fn read(&self, pointer: ValuePointer) -> io::Result<String> {
+        let mut bytes = vec![0; pointer.length as usize];
+        self.segments[&pointer.segment].read_exact_at(&mut bytes, pointer.offset)?;
+        String::from_utf8(bytes).map_err(|_| invalid("value log value is not UTF-8"))
+    }
+
+    pub fn insert(&mut self, key: String, value: String) -> io::Result<()> {
+        let pointer = self.append(&key, Some(&value))?.unwrap();
+        self.tree.insert(key, pointer.encode());
+        Ok(())
+    }
+
+    pub fn delete(&mut self, key: String) -> io::Result<()> {
+        if self.tree.get(&key).is_some() {
+            self.append(&key, None)?;
+            self.tree.delete(key);
+        }
+        Ok(())
+    }
+
+    pub fn get(&self, key: &str) -> io::Result<Option<String>> {
+        match self.tree.get(key) {
+            Some(encoded) => self.read(ValuePointer::decode(&encoded)).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    pub fn scan<R: RangeBounds<String>>(&self, range: R) -> io::Result<Vec<(String, String)>> {
+        let pointers = self.tree.scan(range);
+        let threads = thread::available_parallelism().map_or(1, |threads| threads.get());
+        if pointers.len() < PARALLEL_PREFETCH || threads == 1 {
+            return pointers
+                .into_iter()
+                .map(|(key, encoded)| Ok((key, self.read(ValuePointer::decode(&encoded))?)))
+                .collect();
+        }
+
+        // every thread reads one contiguous slice of the range
+        let chunk = pointers.len().div_ceil(threads);
+        let values = thread::scope(|scope| {
+            let readers: Vec<_> = pointers
+                .chunks(chunk)
+                .map(|pointers| {
+                    scope.spawn(move || {
+                        pointers
+                            .iter()
+                            .map(|(_, encoded)| self.read(ValuePointer::decode(encoded)))
+                            .collect::<io::Result<Vec<String>>>()
+                    })
+                })
+                .collect();
+            readers
+                .into_iter()
+                .map(|reader| reader.join().expect("prefetch thread panicked"))
+                .collect::<io::Result<Vec<_>>>()
+        })?;
+        Ok(pointers
+            .into_iter()
+            .map(|(key, _)| key)
+            .zip(values.into_iter().flatten())
+            .collect())
+    }
+
+    // rewrites the live records of the oldest segment at the head of the log and
+    // deletes it; returns the number of bytes reclaimed
+    pub fn collect_garbage(&mut self) -> io::Result<u64> {
+        let tail = *self.segments.keys().next().unwrap();
+        if tail == self.head {
+            if self.head_length == 0 {
+                return Ok(0);
+            }
+            self.roll_over()?;
+        }
+
+        let file = &self.segments[&tail];
+        let size = file.metadata()?.len();
+        let (records, _) = read_segment(file, tail)?;
+        let mut rewritten = 0;
+        for record in records {
+            let Some(pointer) = record.value else {
+                continue;
+            };
+            let live = self
+                .tree
+                .get(&record.key)
+                .is_some_and(|encoded| ValuePointer::decode(&encoded) == pointer);
+            if live {
+                let value = self.read(pointer)?;
+                let moved = self.append(&record.key, Some(&value))?.unwrap();
+                rewritten += HEADER + (record.key.len() + value.len()) as u64;
+                self.tree.insert(record.key, moved.encode());
+            }
+        }
+
+        // the moved values have to be durable before their old copies go away
+        self.sync()?;
+        self.segments.remove(&tail);
+        fs::remove_file(segment_path(&self.dir, tail))?;
+        Ok(size.saturating_sub(rewritten))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fresh_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("wisckey_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn key(i: usize) -> String {
+        format!("{:04}", i)
+    }
+
+    #[test]
+    fn reopening_replays_the_log() {
+        let dir = fresh_dir("reopen");
+        let mut store = WiscKey::open(&dir, 1024).unwrap();
+        for i in 0..200 {
+            store.insert(key(i), format!("value {}", i)).unwrap();
+        }
+        for i in (0..200).step_by(4) {
+            store.delete(key(i)).unwrap();
+        }
+        store.insert(key(1), "overwritten".to_string()).unwrap();
+        store.sync().unwrap();
+        assert!(store.segments() > 1);
+        let expected = store.scan(..).unwrap();
+        assert_eq!(expected.len(), 150);
+        drop(store);
+
+        let store = WiscKey::open(&dir, 1024).unwrap();
+        assert_eq!(store.scan(..).unwrap(), expected);
+        assert_eq!(store.get(&key(0)).unwrap(), None);
+        assert_eq!(store.get(&key(1)).unwrap(), Some("overwritten".to_string()));
+        assert_eq!(
+            store.scan(key(10)..key(20)).unwrap(),
+            expected
+                .iter()
+                .filter(|(k, _)| *k >= key(10) && *k < key(20))
+                .cloned()
+                .collect::<Vec<_>>()
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn garbage_collection_keeps_live_values() {
+        let dir = fresh_dir("gc");
+        let mut store = WiscKey::open(&dir, 512).unwrap();
+        for round in 0..5 {
+            for i in 0..50 {
+                store.insert(key(i), format!("round {}", round)).unwrap();
+            }
+        }
+        for i in 40..50 {
+            store.delete(key(i)).unwrap();
+        }
+        let expected = store.scan(..).unwrap();
+        let segments = store.segments();
+
+        // most of the log is overwritten, so collecting every old segment shrinks it
+        let mut reclaimed = 0;
+        for _ in 0..segments {
+            reclaimed += store.collect_garbage().unwrap();
+            assert_eq!(store.scan(..).unwrap(), expected);
+        }
+        assert!(reclaimed > 0);
+        assert!(store.segments() < segments);
+        drop(store);
+
+        let store = WiscKey::open(&dir, 512).unwrap();
+        assert_eq!(store.scan(..).unwrap(), expected);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn torn_records_are_dropped_on_reopen() {
+        let dir = fresh_dir("torn");
+        let mut store = WiscKey::open(&dir, 1 << 20).unwrap();
+        store.insert(key(0), "kept".to_string()).unwrap();
+        store.insert(key(1), "torn".to_string()).unwrap();
+        store.sync().unwrap();
+        drop(store);
+
+        let path = segment_path(&dir, 0);
+        let length = fs::metadata(&path).unwrap().len();
+        OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(length - 2)
+            .unwrap();
+        let mut store = WiscKey::open(&dir, 1 << 20).unwrap();
+        assert_eq!(store.get(&key(0)).unwrap(), Some("kept".to_string()));
+        assert_eq!(store.get(&key(1)).unwrap(), None);
+        store.insert(key(2), "after".to_string()).unwrap();
+        drop(store);
+
+        let store = WiscKey::open(&dir, 1 << 20).unwrap();
+        assert_eq!(store.scan(..).unwrap().len(), 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}